@@ -118,6 +118,8 @@ enum Commands {
 }
 
 fn main() -> Result<(), DSEError> {
+    #[cfg(feature = "logging")]
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
     let cli = Cli::parse();
 
     match &cli.command {
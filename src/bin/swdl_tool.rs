@@ -8,7 +8,7 @@ use std::io::Write;
 use std::path::PathBuf;
 
 use clap::{Parser, command, Subcommand};
-use dse::swdl::sf2::{copy_raw_sample_data, copy_presets, DSPOptions};
+use dse::swdl::sf2::{copy_raw_sample_data, copy_presets, apply_pan_law, DSPOptions, PanLaw};
 use dse::swdl::{SWDL, PRGIChunk, KGRPChunk, Keygroup, create_swdl_shell};
 use dse::dtype::{DSEError, SongBuilderFlags};
 
@@ -117,6 +117,7 @@ enum Commands {
         /// 1 - Ideal sample correction for fixed 32728.5Hz hardware output rate
         /// 2 - Discrete lookup table based on the original EoS main bank (all samples must either match the `sample_rate` parameter *or* be converted to that sample rate in this mode!)
         /// 3 - Fitted curve
+        /// 4 - Lookup table based on the original EoS main bank, interpolated between the closest rates for rates missing from it
         #[arg(short = 'C', long, default_value_t = 1)]
         sample_rate_adjustment_curve: usize,
 
@@ -126,11 +127,42 @@ enum Commands {
 
         /// Adjusts the pitch of all samples (in cents)
         #[arg(short = 'P', long, default_value_t = 0, allow_hyphen_values = true)]
-        pitch_adjust: i64
+        pitch_adjust: i64,
+
+        /// The pan law applied to stereo split pairs, as the attenuation at the center in dB.
+        /// 0 - None
+        /// 3 - Constant power, -3dB at the center
+        /// 6 - Constant amplitude, -6dB at the center
+        #[arg(long, default_value_t = 0)]
+        pan_law: u8,
+
+        /// Amount of stereo spread applied to multisampled programs, from 0.0 (respect the SF2 pan only) to 1.0 (lowest split hard left, highest split hard right)
+        #[arg(long, default_value_t = 0.0)]
+        stereo_spread: f64
+    },
+    SampleCSV {
+        /// Sets the SWD or SWD.XML file to list the samples of
+        #[arg(value_name = "INPUT")]
+        input: PathBuf,
+
+        /// Sets the CSV file to write to. The table is printed if this is not given
+        #[arg(short = 'o', long, value_name = "OUTPUT")]
+        output: Option<PathBuf>
+    },
+    ExtractSamples {
+        /// Sets the SWD or SWD.XML file to extract the samples of
+        #[arg(value_name = "INPUT")]
+        input: PathBuf,
+
+        /// Sets the folder to write the WAV files to
+        #[arg(short = 'o', long, value_name = "OUTPUT")]
+        output_folder: Option<PathBuf>
     }
 }
 
 fn main() -> Result<(), DSEError> {
+    #[cfg(feature = "logging")]
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
     let cli = Cli::parse();
 
     match &cli.command {
@@ -197,8 +229,17 @@ fn main() -> Result<(), DSEError> {
 
             println!("\nAll files successfully processed.");
         }
-        Commands::AddSF2 { input_glob, output_folder, swdl: swdl_path, out_swdl: out_swdl_path, resample_threshold, sample_rate, sample_rate_adjustment_curve, adpcm_encoder_lookahead, pitch_adjust } => {
+        Commands::AddSF2 { input_glob, output_folder, swdl: swdl_path, out_swdl: out_swdl_path, resample_threshold, sample_rate, sample_rate_adjustment_curve, adpcm_encoder_lookahead, pitch_adjust, pan_law, stereo_spread } => {
             let (source_file_format, change_ext) = ("sf2", "swd");
+            let pan_law = match pan_law {
+                0 => PanLaw::None,
+                3 => PanLaw::Minus3Db,
+                6 => PanLaw::Minus6Db,
+                _ => return Err(DSEError::Invalid(format!("Invalid pan law {}! The pan law must be 0, 3, or 6.", pan_law)))
+            };
+            if !(0.0..=1.0).contains(stereo_spread) {
+                return Err(DSEError::Invalid(format!("Invalid stereo spread {}! The spread must be within 0.0-1.0.", stereo_spread)));
+            }
             let output_folder = get_final_output_folder(output_folder)?;
             let input_file_paths: Vec<(PathBuf, PathBuf)> = get_input_output_pairs(input_glob, source_file_format, &output_folder, change_ext)?;
             
@@ -242,7 +283,8 @@ fn main() -> Result<(), DSEError> {
                 
                 let sf2 = SoundFont2::load(&mut File::open(&input_file_path)?).map_err(|x| DSEError::SoundFontParseError(format!("{:?}", x)))?;
                 
-                let (sample_mappings, mut sample_infos) = copy_raw_sample_data(&File::open(&input_file_path)?, &sf2, &mut main_bank_swdl, DSPOptions { resample_threshold: *resample_threshold, sample_rate: *sample_rate as f64, sample_rate_relative: false, adpcm_encoder_lookahead: *adpcm_encoder_lookahead }, *sample_rate_adjustment_curve, *pitch_adjust, |_, _| true)?;
+                let dsp_options = DSPOptions { resample_threshold: *resample_threshold, sample_rate: *sample_rate as f64, sample_rate_relative: false, adpcm_encoder_lookahead: *adpcm_encoder_lookahead, trim_silence_db: None, pan_law, stereo_spread: *stereo_spread, dedup_identical_samples: false };
+                let (sample_mappings, mut sample_infos) = copy_raw_sample_data(&File::open(&input_file_path)?, &sf2, &mut main_bank_swdl, dsp_options, *sample_rate_adjustment_curve, *pitch_adjust, |_, _| true)?;
 
                 let fname = input_file_path.file_name().ok_or(DSEError::_FileNameReadFailed(input_file_path.display().to_string()))?
                     .to_str().ok_or(DSEError::DSEFileNameConversionNonUTF8("SF2".to_string(), input_file_path.display().to_string()))?
@@ -252,7 +294,10 @@ fn main() -> Result<(), DSEError> {
                 let mut track_swdl = create_swdl_shell(get_file_last_modified_date_with_default(&input_file_path)?, fname)?;
 
                 let mut prgi = PRGIChunk::new(0);
-                copy_presets(&sf2, &mut sample_infos, &mut prgi.data, |i| Some(sample_mappings.get(&i).copied().ok_or(DSEError::WrapperString(format!("{}Failed to map sample {}!", "Internal Error: ".red(), i))).unwrap()), *sample_rate_adjustment_curve, *pitch_adjust, |_, _, _, _, _, _, _| true, |_, preset, _| Some(preset.header.bank * 128 + preset.header.preset));
+                copy_presets(&sf2, &mut sample_infos, &mut prgi.data, |i| sample_mappings.get(&i).copied(), *sample_rate_adjustment_curve, *pitch_adjust, dsp_options.stereo_spread, |_, _, _, _, _, _, _| true, |_, preset, _| Some(preset.header.bank * 128 + preset.header.preset))?;
+                for program_info in prgi.data.objects.iter_mut() {
+                    apply_pan_law(program_info, dsp_options.pan_law);
+                }
                 track_swdl.prgi = Some(prgi);
 
                 // Add the sample info objects last
@@ -276,10 +321,8 @@ fn main() -> Result<(), DSEError> {
                 ]; // Just a quick template keygroup list. By default only the first kgrp is used!
                 track_swdl.kgrp = Some(track_swdl_kgrp);
 
-                // Write the track swdl file into the specified output directory
-                track_swdl.regenerate_read_markers::<u16, u16>()?;
-                track_swdl.regenerate_automatic_parameters()?;
-                track_swdl.write_to_file::<u16, u16, _>(&mut open_file_overwrite_rw(output_file_path)?)?;
+                // Write the track swdl file into the specified output directory. Program ids are derived from the bank and preset numbers, so the prgi pointer table alone may need 32-bit pointers.
+                track_swdl.save_auto(&mut open_file_overwrite_rw(output_file_path)?)?;
 
                 println!("done!");
             }
@@ -304,6 +347,48 @@ fn main() -> Result<(), DSEError> {
                 main_bank_swdl.write_to_file::<u16, u16, _>(&mut open_file_overwrite_rw(out_swdl_path)?)?;
             }
         },
+        Commands::SampleCSV { input, output } => {
+            let swdl = SWDL::load_path(input)?;
+
+            let mut csv = String::from("id,rootkey,ftune,ctune,volume,pan,smplfmt,smplrate,smplloop,loopbeg,looplen,envon,envmult,atkvol,attack,decay,sustain,hold,decay2,release\n");
+            for sample_info in &swdl.wavi.data.objects {
+                let env = &sample_info.volume_envelope;
+                csv.push_str(&format!("{},{},{},{},{},{},0x{:04X},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+                    sample_info.id, sample_info.rootkey, sample_info.tuning.ftune(), sample_info.tuning.ctune(), sample_info.volume, sample_info.pan,
+                    sample_info.smplfmt, sample_info.smplrate, sample_info.smplloop, sample_info.loopbeg, sample_info.looplen,
+                    env.envon, env.envmult, env.atkvol, env.attack, env.decay, env.sustain, env.hold, env.decay2, env.release));
+            }
+
+            if let Some(output) = output {
+                open_file_overwrite_rw(output)?.write_all(csv.as_bytes())?;
+            } else {
+                print!("{}", csv);
+            }
+        },
+        Commands::ExtractSamples { input, output_folder } => {
+            let output_folder = get_final_output_folder(output_folder)?;
+            let swdl = SWDL::load_path(input)?;
+
+            if swdl.pcmd.is_none() {
+                println!("{}{} has no sample data of its own, its samples are in the main bank! Skipped.", "Warning: ".yellow(), input.display());
+                return Ok(());
+            }
+
+            let mut nwritten = 0;
+            let mut total_bytes = 0;
+            for sample_info in &swdl.wavi.data.objects {
+                let mut wav: Vec<u8> = Vec::new();
+                if let Err(e) = swdl.export_sample_wav(sample_info.id, &mut wav) {
+                    println!("{}Failed to export sample {}: {} Skipped.", "Warning: ".yellow(), sample_info.id, e);
+                    continue;
+                }
+                open_file_overwrite_rw(output_folder.join(format!("sample_{}.wav", sample_info.id)))?.write_all(&wav)?;
+                nwritten += 1;
+                total_bytes += wav.len();
+            }
+
+            println!("\nWrote {} of {} samples ({} bytes).", nwritten, swdl.wavi.data.objects.len(), total_bytes);
+        },
     }
 
     Ok(())
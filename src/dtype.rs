@@ -1,7 +1,8 @@
 use core::panic;
-use std::{io::{Read, Write, Seek, SeekFrom, Cursor}, fmt::{Display, Debug}, vec, ops::RangeInclusive};
+use std::{io::{Read, Write, Seek, SeekFrom, Cursor}, fmt::{Display, Debug}, vec, ops::RangeInclusive, cell::Cell, hash::Hasher};
 use bevy_reflect::{Reflect, Struct};
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian, ByteOrder};
+use log::trace;
 use num_traits::{Zero, AsPrimitive};
 use serde::{Serialize, Deserialize};
 
@@ -64,6 +65,19 @@ impl SongBuilderFlags {
     pub fn parse_from_smdl(smdl: &SMDL) -> SongBuilderFlags {
         Self::from_bits_retain(smdl.header.unk7)
     }
+    /// Determine the smallest pointer widths able to address all the objects in the SWDL's wavi and prgi pointer tables. Flags other than the pointer extensions are kept as they are.
+    pub fn auto_for(swdl: &SWDL) -> SongBuilderFlags {
+        let mut flags = Self::parse_from_swdl(swdl).difference(Self::FULL_POINTER_EXTENSION);
+        if let Err(DSEError::Placeholder()) = swdl.wavi.data.write_to_file::<u16, _>(&mut Cursor::new(&mut Vec::new())) {
+            flags |= Self::WAVI_POINTER_EXTENSION;
+        }
+        if let Some(prgi) = &swdl.prgi {
+            if let Err(DSEError::Placeholder()) = prgi.data.write_to_file::<u16, _>(&mut Cursor::new(&mut Vec::new())) {
+                flags |= Self::PRGI_POINTER_EXTENSION;
+            }
+        }
+        flags
+    }
 }
 pub trait SetSongBuilderFlags {
     fn get_song_builder_flags(&self) -> SongBuilderFlags;
@@ -86,6 +100,39 @@ impl SetSongBuilderFlags for SMDL {
     }
 }
 
+/// Feed the binary representation of `value` into `hasher`. Objects that fail to serialize only contribute a marker byte.
+pub fn hash_bytes_of<T: ReadWrite, H: Hasher>(value: &T, hasher: &mut H) {
+    let mut cursor = Cursor::new(Vec::new());
+    if value.write_to_file(&mut cursor).is_ok() {
+        hasher.write(&cursor.into_inner());
+    } else {
+        hasher.write_u8(0xFF);
+    }
+}
+
+thread_local! {
+    static STRICT_MODE: Cell<bool> = Cell::new(false);
+}
+/// Strict mode favors fidelity over convenience. Bytes that would normally be assumed to hold their typical values are preserved exactly as read, and extra consistency checks are done while reading. Off by default, and set per-thread.
+pub fn set_strict_mode(strict: bool) {
+    STRICT_MODE.with(|x| x.set(strict));
+}
+pub fn strict_mode() -> bool {
+    STRICT_MODE.with(|x| x.get())
+}
+thread_local! {
+    static STRIP_COMMON_UNKNOWNS: Cell<bool> = Cell::new(true);
+}
+/// Controls whether unknown fields that almost always hold a typical value are left out of XML exports (and filled back in with that typical value on import). On by default, and set per-thread.
+/// 
+/// Turn this off to keep every unknown byte in the XML for byte-exact round-trips. See also `SWDL::nondefault_unknowns`.
+pub fn set_strip_common_unknowns(strip: bool) {
+    STRIP_COMMON_UNKNOWNS.with(|x| x.set(strip));
+}
+pub fn strip_common_unknowns() -> bool {
+    STRIP_COMMON_UNKNOWNS.with(|x| x.get())
+}
+
 #[derive(Debug, Display)]
 pub enum DSEFileType {
     SWDL,
@@ -145,7 +192,22 @@ pub enum DSEError {
     SampleReadError(String, u64, usize),
     #[error("Target sample rate {0} unsupported by the lookup table! Cannot determine its adjustment value!!")]
     SampleRateUnsupported(f64),
+    #[error("Sample {0} could not be found in the wavi chunk!")]
+    SampleNotFound(u16),
+    #[error("Sample {0} has no sample data in this bank! Its data is stored in the main bank.")]
+    SampleInExternalBank(u16),
+    #[error("Sample format {0} is not supported!")]
+    UnsupportedSampleFormat(crate::swdl::SampleFormat),
+    #[error("Unknown sample format 0x{0:04X}!")]
+    UnknownSampleFormat(u16),
+    #[error("This SWDL does not contain a pcmd chunk! Its sample data is stored in the main bank.")]
+    SwdlPcmdMissing(),
 
+    #[error("SWDL link bytes {0:?} do not match SMDL link bytes {1:?}! The game will not find the samples for this song.")]
+    LinkBytesMismatch((u8, u8), (u8, u8)),
+
+    #[error("Field type '{0}' is not supported by AutoReadWrite!")]
+    UnsupportedAutoType(String),
     #[error("{0}")]
     Invalid(String),
     #[error("DSE command '{0}' is invalid! {1}")]
@@ -197,6 +259,12 @@ pub enum DSEError {
     PointerTableTooLong(DSEBlockType),
     #[error("The pointer table for the {0} chunk is too large, resulting in some pointers into the table overflowing!!")]
     PointerTableTooLarge(DSEBlockType),
+    #[error("A chunk declares a length of {0} bytes, which exceeds the maximum of {1} bytes allowed by the load options!")]
+    ChunkTooLarge(u64, u64),
+    #[error("A track chunk declares a length of {0} bytes, but {1} bytes were consumed while reading its events! An event's parameter count is likely wrong.")]
+    TrackLengthMismatch(u64, u64),
+    #[error("{1} bytes at offset {0} of a track come after its end of track event and belong to no event! An earlier event's parameter count is likely wrong. Bytes: {2:02X?}")]
+    DSEUnexpectedTrailingEventBytes(u64, usize, Vec<u8>),
     #[error("MIDI messages too far apart to be converted into the Smf0 format!")]
     DSESmf0MessagesTooFarApart(),
     #[error("Some notes are too long to be converted!")]
@@ -322,10 +390,10 @@ impl<T: Reflect + Struct + Default + AutoReadWrite> ReadWrite for T {
                             writer.write_all(field.as_any().downcast_ref::<[u8; 32]>().ok_or(DSEError::_ValidDynamicFieldDowncastFailed())?)?;
                             bytes_written += 32;
                         } else {
-                            panic!("Unsupported auto type!");
+                            return Err(DSEError::UnsupportedAutoType(type_info.type_name().to_string()));
                         }
                     } else {
-                        panic!("Unsupported auto type!");
+                        return Err(DSEError::UnsupportedAutoType(type_info.type_name().to_string()));
                     }
                 },
                 bevy_reflect::TypeInfo::Value(value_info) => {
@@ -351,7 +419,7 @@ impl<T: Reflect + Struct + Default + AutoReadWrite> ReadWrite for T {
                         writer.write_i32::<LittleEndian>(*field.as_any().downcast_ref::<i32>().ok_or(DSEError::_ValidDynamicFieldDowncastFailed())?)?;
                         bytes_written += 4;
                     } else {
-                        panic!("Unsupported auto type!");
+                        return Err(DSEError::UnsupportedAutoType(type_info.type_name().to_string()));
                     }
                 },
                 bevy_reflect::TypeInfo::Struct(_) => {
@@ -364,10 +432,10 @@ impl<T: Reflect + Struct + Default + AutoReadWrite> ReadWrite for T {
                     } else if let Some(tuning) = field.as_any().downcast_ref::<Tuning>() {
                         bytes_written += tuning.write_to_file(writer)?;
                     } else {
-                        panic!("Unsupported auto type!");
+                        return Err(DSEError::UnsupportedAutoType(type_info.type_name().to_string()));
                     }
                 },
-                _ => panic!("Unsupported auto type!")
+                _ => return Err(DSEError::UnsupportedAutoType(type_info.type_name().to_string()))
             }
         }
         Ok(bytes_written)
@@ -391,10 +459,10 @@ impl<T: Reflect + Struct + Default + AutoReadWrite> ReadWrite for T {
                         } else if capacity == 32 {
                             *field.as_any_mut().downcast_mut::<[u8; 32]>().ok_or(DSEError::_ValidDynamicFieldDowncastFailed())? = read_n_bytes!(file, 32)?;
                         } else {
-                            panic!("Unsupported auto type!");
+                            return Err(DSEError::UnsupportedAutoType(type_info.type_name().to_string()));
                         }
                     } else {
-                        panic!("Unsupported auto type!");
+                        return Err(DSEError::UnsupportedAutoType(type_info.type_name().to_string()));
                     }
                 },
                 bevy_reflect::TypeInfo::Value(value_info) => {
@@ -413,7 +481,7 @@ impl<T: Reflect + Struct + Default + AutoReadWrite> ReadWrite for T {
                     } else if value_info.type_name() == "i32" {
                         *field.as_any_mut().downcast_mut::<i32>().ok_or(DSEError::_ValidDynamicFieldDowncastFailed())? = file.read_i32::<LittleEndian>()?;
                     } else {
-                        panic!("Unsupported auto type!");
+                        return Err(DSEError::UnsupportedAutoType(type_info.type_name().to_string()));
                     }
                 },
                 bevy_reflect::TypeInfo::Struct(_) => {
@@ -426,10 +494,10 @@ impl<T: Reflect + Struct + Default + AutoReadWrite> ReadWrite for T {
                     } else if let Some(tuning) = field.as_any_mut().downcast_mut::<Tuning>() {
                         tuning.read_from_file(file)?;
                     } else {
-                        panic!("Unsupported auto type!");
+                        return Err(DSEError::UnsupportedAutoType(type_info.type_name().to_string()));
                     }
                 },
-                _ => panic!("Unsupported auto type!")
+                _ => return Err(DSEError::UnsupportedAutoType(type_info.type_name().to_string()))
             }
         }
         Ok(())
@@ -517,17 +585,24 @@ pub struct PointerTable<T: ReadWrite + Default + IsSelfIndexed + Serialize> {
     #[serde(default)]
     #[serde(skip_serializing)]
     _chunk_len: u32,
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    _max_chunk_len: Option<u64>,
     #[serde(rename = "o")]
     pub objects: Vec<T>
 }
 impl<T: ReadWrite + Default + IsSelfIndexed + Serialize> PointerTable<T> {
     pub fn new(n: usize, chunk_len: u32) -> PointerTable<T> {
-        PointerTable { _read_n: n, _chunk_len: chunk_len, objects: Vec::with_capacity(n) }
+        PointerTable { _read_n: n, _chunk_len: chunk_len, _max_chunk_len: None, objects: Vec::with_capacity(n) }
     }
     pub fn set_read_params(&mut self, n: usize, chunk_len: u32) {
         self._read_n = n;
         self._chunk_len = chunk_len;
     }
+    /// Cap on the declared chunk length accepted by `read_from_file`. `None` means no cap.
+    pub fn set_max_chunk_len(&mut self, max_chunk_len: Option<u64>) {
+        self._max_chunk_len = max_chunk_len;
+    }
     pub fn slots(&self) -> usize {
         if self.objects.len() == 0 {
             return 0;
@@ -545,6 +620,29 @@ impl<T: ReadWrite + Default + IsSelfIndexed + Serialize> PointerTable<T> {
             self.objects.last()
         }
     }
+    /// The lowest self-index not taken by any object in the table. For tables that aren't self-indexed, this is the number of objects, like `slots`.
+    pub fn next_free_self_index(&self) -> usize {
+        let taken: std::collections::BTreeSet<usize> = self.objects.iter().filter_map(|obj| obj.is_self_indexed()).collect();
+        if taken.is_empty() {
+            return self.objects.len();
+        }
+        (0..).find(|index| !taken.contains(index)).unwrap()
+    }
+    /// Give every object whose self-index is already taken by an earlier object the lowest free index instead (see `next_free_self_index`), so that the table can be written. Returns the `(old index, new index)` of every object moved, in table order.
+    /// 
+    /// Anything referring to the moved objects by index (like the `SmplID` of splits) is not updated, since there is no telling which of the duplicates it meant.
+    pub fn dedup_self_indices(&mut self) -> Result<Vec<(usize, usize)>, DSEError> {
+        let mut remapping = Vec::new();
+        for i in 0..self.objects.len() {
+            let old_index = if let Some(old_index) = self.objects[i].is_self_indexed() { old_index } else { break };
+            if self.objects[..i].iter().any(|obj| obj.is_self_indexed() == Some(old_index)) {
+                let new_index = self.next_free_self_index();
+                self.objects[i].change_self_index(new_index)?;
+                remapping.push((old_index, new_index));
+            }
+        }
+        Ok(remapping)
+    }
 }
 pub trait Pointer<O: ByteOrder>: AsPrimitive<u64> + TryFrom<usize> + Eq + Zero {
     fn pointer_size() -> usize;
@@ -624,7 +722,7 @@ impl<T: ReadWrite + Default + IsSelfIndexed + Serialize> PointerTable<T> {
             if P::read(writer)? == P::zero() {
                 // Pointer has not been written in yet
                 writer.seek(SeekFrom::Current(-(bytes_per_pointer as i64)))?;
-                println!("{} pointer", first_pointer + accumulated_write);
+                trace!("{} pointer", first_pointer + accumulated_write);
                 let p: P = (first_pointer + accumulated_write).try_into().map_err(|_| DSEError::Placeholder())?;
                 p.write(writer)?;
             } else {
@@ -639,11 +737,20 @@ impl<T: ReadWrite + Default + IsSelfIndexed + Serialize> PointerTable<T> {
             writer.write_u8(0xAA)?;
         }
         writer.write_all(&accumulated_object_data)?;
-        println!("==============================");
+        trace!("==============================");
         Ok(pointer_table_byte_len_aligned + accumulated_object_data.len())
     }
     pub fn read_from_file<P: Pointer<LittleEndian>, R: Read + Seek>(&mut self, reader: &mut R) -> Result<(), DSEError> {
         let bytes_per_pointer = P::pointer_size();
+        if let Some(max_chunk_len) = self._max_chunk_len {
+            if self._chunk_len as u64 > max_chunk_len {
+                return Err(DSEError::ChunkTooLarge(self._chunk_len as u64, max_chunk_len));
+            }
+            let pointer_table_byte_len = (self._read_n as u64 + P::use_magic().is_some() as u64) * bytes_per_pointer as u64;
+            if pointer_table_byte_len > max_chunk_len {
+                return Err(DSEError::ChunkTooLarge(pointer_table_byte_len, max_chunk_len));
+            }
+        }
         let start_of_pointer_table = reader.seek(SeekFrom::Current(0))?;
         if P::use_magic().is_some() {
             reader.seek(SeekFrom::Current(bytes_per_pointer as i64))?;
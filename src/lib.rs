@@ -2,7 +2,12 @@ mod deserialize_with;
 pub mod dtype;
 pub mod math;
 pub mod fileutils;
+pub mod riff;
+#[cfg(feature = "nds-rom")]
+pub mod nds;
 pub mod swdl;
 pub mod smdl;
 pub mod opinionated_translators;
+pub mod validate;
+pub mod render;
 pub use dse_dsp_sys as dsp;
\ No newline at end of file
@@ -7,9 +7,13 @@ mod deserialize_with;
 pub mod dtype;
 pub mod math;
 pub mod fileutils;
+pub mod riff;
+#[cfg(feature = "nds-rom")]
+pub mod nds;
 pub mod swdl;
 pub mod smdl;
 pub mod opinionated_translators;
+pub mod validate;
 
 use dtype::{*};
 use swdl::*;
@@ -0,0 +1,91 @@
+//! Minimal read-only NitroFS reader, for pulling DSE files straight out of an NDS ROM without running `NDS_UNPACK` first.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use byteorder::{ReadBytesExt, LittleEndian};
+
+use crate::dtype::DSEError;
+
+/// Offsets and sizes of the File Name Table and File Allocation Table, as stored in the ROM header
+struct NitroFSHeader {
+    fnt_offset: u32,
+    fat_offset: u32,
+    fat_size: u32
+}
+impl NitroFSHeader {
+    fn read<R: Read + Seek>(rom: &mut R) -> Result<NitroFSHeader, DSEError> {
+        rom.seek(SeekFrom::Start(0x40))?;
+        let fnt_offset = rom.read_u32::<LittleEndian>()?;
+        let _fnt_size = rom.read_u32::<LittleEndian>()?;
+        let fat_offset = rom.read_u32::<LittleEndian>()?;
+        let fat_size = rom.read_u32::<LittleEndian>()?;
+        Ok(NitroFSHeader { fnt_offset, fat_offset, fat_size })
+    }
+}
+
+/// Look up the file id of `name` within the directory `dir_id`. Returns whether the entry is a directory, alongside its id.
+fn find_in_directory<R: Read + Seek>(rom: &mut R, header: &NitroFSHeader, dir_id: u16, name: &str) -> Result<Option<(bool, u16)>, DSEError> {
+    rom.seek(SeekFrom::Start(header.fnt_offset as u64 + (dir_id & 0x0FFF) as u64 * 8))?;
+    let subtable_offset = rom.read_u32::<LittleEndian>()?;
+    let mut file_id = rom.read_u16::<LittleEndian>()?;
+    rom.seek(SeekFrom::Start(header.fnt_offset as u64 + subtable_offset as u64))?;
+    loop {
+        let type_len = rom.read_u8()?;
+        if type_len == 0x00 {
+            return Ok(None);
+        } else if type_len == 0x80 {
+            return Err(DSEError::Invalid("NitroFS file name table contains a reserved entry type!".to_string()));
+        }
+        let is_dir = type_len & 0x80 != 0;
+        let mut entry_name = vec![0_u8; (type_len & 0x7F) as usize];
+        rom.read_exact(&mut entry_name)?;
+        let entry_name_matches = entry_name.eq_ignore_ascii_case(name.as_bytes());
+        if is_dir {
+            let sub_dir_id = rom.read_u16::<LittleEndian>()?;
+            if entry_name_matches {
+                return Ok(Some((true, sub_dir_id)));
+            }
+        } else {
+            if entry_name_matches {
+                return Ok(Some((false, file_id)));
+            }
+            file_id += 1;
+        }
+    }
+}
+
+/// Read the contents of the file at `internal_path` (e.g. `/data/SOUND/BGM/bgm0001.swd`) from the NitroFS filesystem of an NDS ROM. Name comparisons are case-insensitive.
+pub fn read_file_from_rom<R: Read + Seek>(rom: &mut R, internal_path: &str) -> Result<Vec<u8>, DSEError> {
+    let header = NitroFSHeader::read(rom)?;
+    let not_found = || DSEError::Invalid(format!("File '{}' not found in the NDS ROM!", internal_path));
+
+    let mut components = internal_path.split('/').filter(|x| !x.is_empty()).peekable();
+    let mut dir_id: u16 = 0xF000; // Root directory
+    let mut file_id = None;
+    while let Some(component) = components.next() {
+        let (is_dir, id) = find_in_directory(rom, &header, dir_id, component)?.ok_or_else(not_found)?;
+        if components.peek().is_some() {
+            if !is_dir {
+                return Err(not_found());
+            }
+            dir_id = id;
+        } else if !is_dir {
+            file_id = Some(id);
+        }
+    }
+    let file_id = file_id.ok_or_else(not_found)?;
+
+    if (file_id as u32 + 1) * 8 > header.fat_size {
+        return Err(DSEError::Invalid(format!("File id {} is out of bounds of the NitroFS file allocation table!", file_id)));
+    }
+    rom.seek(SeekFrom::Start(header.fat_offset as u64 + file_id as u64 * 8))?;
+    let start = rom.read_u32::<LittleEndian>()?;
+    let end = rom.read_u32::<LittleEndian>()?;
+    if end < start {
+        return Err(DSEError::Invalid(format!("File id {} has an invalid entry in the NitroFS file allocation table!", file_id)));
+    }
+    let mut data = vec![0_u8; (end - start) as usize];
+    rom.seek(SeekFrom::Start(start as u64))?;
+    rom.read_exact(&mut data)?;
+    Ok(data)
+}
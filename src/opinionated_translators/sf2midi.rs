@@ -2,6 +2,7 @@ use std::{ops::RangeInclusive, collections::{HashMap, HashSet, BTreeMap}, io::{R
 
 use colored::Colorize;
 use indexmap::IndexMap;
+use log::{trace, warn};
 use midly::Smf;
 use soundfont::SoundFont2;
 
@@ -78,6 +79,7 @@ pub trait TrimmedSampleDataCopy {
     ///     1 - Ideal sample correction for fixed 32728.5Hz hardware output rate
     ///     2 - Discrete lookup table based on the original EoS main bank (all samples must either match the `sample_rate` parameter *or* be converted to that sample rate in this mode!)
     ///     3 - Fitted curve
+    ///     4 - Lookup table based on the original EoS main bank, interpolated between the closest rates for rates missing from it
     /// * `pitch_adjust` - Soft global pitch adjust (adjustments are made through `ftune` and `ctune` parameters within DSE instead of done directly on the samples).
     /// * `samples_used` - Samples to copy. If writing to the main bank, this should contain samples used across all songs. If writing to decoupled song banks, this should only contain samples used in that song. Since each entry contains an identifier to the origin Soundfont, it does not need to be trimmed to only contain samples within the Soundfont currently being processed.
     fn trimmed_raw_sample_copy<R: Read + Seek>(&mut self, sf2name: &str, sf2file: R, sf2: &SoundFont2, dsp_options: DSPOptions, sample_rate_adjustment_curve: usize, pitch_adjust: i64, samples_used: &HashSet<SampleEntry>) -> Result<(HashMap<u16, u16>, BTreeMap<u16, SampleInfo>), DSEError>;
@@ -98,6 +100,7 @@ pub trait FromSF2Once {
     ///     1 - Ideal sample correction for fixed 32728.5Hz hardware output rate
     ///     2 - Discrete lookup table based on the original EoS main bank (all samples must either match the `sample_rate` parameter *or* be converted to that sample rate in this mode!)
     ///     3 - Fitted curve
+    ///     4 - Lookup table based on the original EoS main bank, interpolated between the closest rates for rates missing from it
     /// * `pitch_adjust` - Soft global pitch adjust (adjustments are made through `ftune` and `ctune` parameters within DSE instead of done directly on the samples).
     /// * `song_preset_map` - Bank/Program to DSE program id mappings. If `FromMIDIOnce::from_midi_once` was previously run to convert a MIDI, it would have created mappings based on all the presets used in the MIDI, which you should pass here so that the SWD file will have the corresponding Soundfont presets mapped to DSE in the same way.
     /// * `sample_mapping_information` - Soundfont Sample Indices to DSE sample id mappings for each soundfont. If `TrimmedSampleDataCopy::trimmed_raw_sample_copy` was previously run to copy samples from the same SF2's, it should have created custom mappings so as not to overwrite any existing sample data, which you should pass here so that the SWD file will reference the correct samples.
@@ -134,6 +137,7 @@ impl FromSF2Once for SWDL {
                     |i| sample_mappings.get(&i).copied(),
                     sample_rate_adjustment_curve,
                     pitch_adjust,
+                    0.0,
                     |preset_i, _, _, preset_zone_i, _, _, _| instrument_mappings_used.get(&InstrumentMappingEntry { soundfont_name: soundfont_name.clone(), preset_i, preset_zone_i }).is_some(),
                     |_, preset, program_info| {
                         //TODO: An sf2 exported from VGMTrans had an extra empty preset after all the normal ones visible in Polyphone with a bank/preset number of 000:000, which broke the assertion that each id should correspond to one preset. The likely explanation is that empty presets are meant to be ignored, and so we do that here.
@@ -142,18 +146,18 @@ impl FromSF2Once for SWDL {
                         } else {
                             None
                         }
-                    });
+                    })?;
                 let sample_infos_trimmed: BTreeMap<u16, SampleInfo> = samples_used.iter().filter_map(|x| {
                     if let Some(mapping) = sample_mappings.get(&x.sample_i) {
-                        Some((x.sample_i, sample_infos.get(mapping).ok_or(DSEError::_SampleInPresetMissing(*mapping)).unwrap().clone()))
+                        Some(sample_infos.get(mapping).ok_or(DSEError::_SampleInPresetMissing(*mapping)).map(|sample_info| (x.sample_i, sample_info.clone())))
                     } else {
                         // The ones that are filtered out are not in this specific soundfont
                         None
                     }
-                }).collect::<BTreeMap<u16, SampleInfo>>();
+                }).collect::<Result<BTreeMap<u16, SampleInfo>, DSEError>>()?;
                 sample_infos_merged.extend(sample_infos_trimmed);
             } else {
-                println!("{}Soundfont '{}' is never used! Writing will be skipped.", "Warning: ".yellow(), soundfont_name);
+                warn!("Soundfont '{}' is never used! Writing will be skipped.", soundfont_name);
             }
         }
         self.prgi = Some(prgi);
@@ -191,14 +195,16 @@ impl FromSF2Once for SWDL {
 
 impl TrimmedSampleDataCopy for SWDL {
     fn trimmed_raw_sample_copy<R: Read + Seek>(&mut self, sf2name: &str, sf2file: R, sf2: &SoundFont2, dsp_options: DSPOptions, sample_rate_adjustment_curve: usize, pitch_adjust: i64, samples_used: &HashSet<SampleEntry>) -> Result<(HashMap<u16, u16>, BTreeMap<u16, SampleInfo>), DSEError> {
-        Ok(copy_raw_sample_data(
+        let (sample_mappings, sample_infos) = copy_raw_sample_data(
             sf2file,
             sf2,
             self,
             dsp_options,
             sample_rate_adjustment_curve,
             pitch_adjust,
-            |sample_i, _| samples_used.contains(&SampleEntry { soundfont_name: sf2name.to_string(), sample_i: sample_i as u16 }))?)
+            |sample_i, _| samples_used.contains(&SampleEntry { soundfont_name: sf2name.to_string(), sample_i: sample_i as u16 }))?;
+        self.record_sample_provenance(sf2name, &sample_mappings);
+        Ok((sample_mappings, sample_infos))
     }
 }
 
@@ -263,7 +269,7 @@ impl FromMIDIOnce for SMDL {
         let mut current_id = 0_u8;
         for (trkid, programs_requiring_mapping) in programs_requiring_mapping.into_iter() {
             for (event, (bank, program)) in programs_requiring_mapping {
-                println!("trk{:02} bank{} prgm{}", trkid, bank, program);
+                trace!("trk{:02} bank{} prgm{}", trkid, bank, program);
                 let program_id;
                 if let Some(&existing_program_id) = song_preset_map.get(&(bank, program)) {
                     program_id = existing_program_id;
@@ -290,23 +296,25 @@ impl FromMIDIOnce for SMDL {
         let track_soundfonts = uses.iter().map(|soundfont_name| soundfonts.get(soundfont_name).ok_or(DSEError::Invalid(format!("Soundfont with name '{}' not found!", soundfont_name)))).collect::<Result<Vec<&SoundFont2>, _>>()?;
         self.trks.objects = Vec::with_capacity(trks.len());
         for x in trks.into_iter() {
+            let mut preset_names: Vec<String> = Vec::new();
             for ProgramUsed { bank, program, notes, is_default } in x.programs_used() {
                 let find_preset = find_preset_in_soundfonts(&track_soundfonts, *bank as u16, *program as u16);
                 if find_preset.is_none() && *is_default {
-                    println!("{}None of the following soundfonts {:?} used by a track contain a default 000:000 piano preset! Any MIDI tracks lacking MIDI Bank Select and Program Change messages will cause the tool to fail!", "Warning: ".yellow(), uses);
+                    warn!("None of the following soundfonts {:?} used by a track contain a default 000:000 piano preset! Any MIDI tracks lacking MIDI Bank Select and Program Change messages will cause the tool to fail!", uses);
                     continue;
                 }
                 let (soundfont_i, preset_i) = find_preset.ok_or(DSEError::Invalid(format!("Preset {:03}:{:03} not found in any of the specified soundfonts for song '{}'!", bank, program, name)))?;
                 let sf2 = soundfonts.get(&uses[soundfont_i]).ok_or(DSEError::Invalid(format!("Soundfont with name '{}' not found!", &uses[soundfont_i])))?;
                 presets_used.get_or_insert(HashSet::new())
                     .insert(PresetEntry { soundfont_name: uses[soundfont_i].clone(), preset_i });
+                preset_names.push(format!("{:03}:{:03} {}", bank, program, sf2.presets[preset_i].header.name.trim_end_matches('\0')));
 
                 let mut dummy_prgi = PointerTable::new(0, 0);
                 copy_presets(sf2, &mut (0..sf2.sample_headers.len()).into_iter().map(|i| {
                     let mut dummy_smpl = SampleInfo::default();
                     dummy_smpl.smplrate = 44100;
                     (i as u16, dummy_smpl)
-                }).collect::<BTreeMap<u16, SampleInfo>>(), &mut dummy_prgi, |x| Some(x), 1, 0, |preset_i, preset, global_preset_zone, preset_zone_i, preset_zone, _, _| {
+                }).collect::<BTreeMap<u16, SampleInfo>>(), &mut dummy_prgi, |x| Some(x), 1, 0, 0.0, |preset_i, preset, global_preset_zone, preset_zone_i, preset_zone, _, _| {
                     // When this is called, the instrument is guaranteed to not be a global instrument
                     let mut preset_zones_to_search = vec![preset_zone];
                     if let Some(global_preset_zone) = global_preset_zone {
@@ -354,7 +362,7 @@ impl FromMIDIOnce for SMDL {
                     } else {
                         None
                     }
-                });
+                })?;
                 //TODO: An sf2 exported from VGMTrans had an extra empty preset after all the normal ones visible in Polyphone with a bank/preset number of 000:000, which broke the assertion that each id should correspond to one preset. The likely explanation is that empty presets are meant to be ignored, and so we do that here.
                 dummy_prgi.objects.retain(|x| {
                     x.splits_table.len() > 0
@@ -371,7 +379,11 @@ impl FromMIDIOnce for SMDL {
                     }
                 }
             }
-            self.trks.objects.push(x.close_track());
+            let mut trk = x.close_track();
+            if preset_names.len() > 0 {
+                trk._preset_names = Some(preset_names.join(", "));
+            }
+            self.trks.objects.push(trk);
         }
 
         // Regenerate read markers for the SMDL
@@ -0,0 +1,380 @@
+use std::collections::{BTreeMap, HashMap};
+use std::f64::consts::FRAC_PI_2;
+
+use log::debug;
+
+use crate::dtype::DSEError;
+use crate::smdl::{SMDL, DSEEvent};
+use crate::swdl::{SWDL, ADSRVolumeEnvelope};
+
+/// Tempo used until the first SetTempo event
+const DEFAULT_BPM: f64 = 120.0;
+/// Longest release that gets rendered, since the longest envelope times in the lookup tables run for hours
+const MAX_RELEASE_SECONDS: f64 = 10.0;
+/// Output rate of the NDS sound hardware. Sample tunings already account for the sample rate relative to it.
+const HARDWARE_OUTPUT_RATE: f64 = 32728.5;
+
+/// A note as the sequencer would start it, along with the state of its track at the time
+struct Note {
+    start_tick: u128,
+    end_tick: u128,
+    key: i32,
+    velocity: u8,
+    program: u8,
+    volume: u8,
+    expression: u8,
+    pan: u8
+}
+
+/// Play through every track and collect the notes played, along with the tempo changes (tick to bpm)
+fn collect_notes(smdl: &SMDL) -> (Vec<Note>, BTreeMap<u128, f64>) {
+    let mut notes = Vec::new();
+    let mut tempos = BTreeMap::new();
+    for trk in &smdl.trks.objects {
+        let mut octave: i32 = 4;
+        let mut last_duration: u32 = 0;
+        let (mut program, mut volume, mut expression, mut pan) = (0, 127, 127, 64);
+        for (tick, event) in trk.events_with_ticks() {
+            match event {
+                DSEEvent::PlayNote(note) => {
+                    octave += note.octavemod as i32 - 2;
+                    if note.keydownduration != 0 {
+                        last_duration = note.keydownduration;
+                    }
+                    notes.push(Note { start_tick: tick, end_tick: tick + last_duration as u128, key: octave * 12 + note.note as i32, velocity: note.velocity, program, volume, expression, pan });
+                },
+                DSEEvent::Other(other) => match other.code {
+                    0x98 => break, // EOT
+                    0xA0 => octave = other.parameters[0] as i32, // SetTrackOctave
+                    0xA1 => octave += other.parameters[0] as i8 as i32, // AddToTrackOctave
+                    0xA4 | 0xA5 => { // SetTempo, SetTempo2
+                        if other.parameters[0] != 0 {
+                            tempos.insert(tick, other.parameters[0] as f64);
+                        }
+                    },
+                    0xAC => program = other.parameters[0], // SetProgram
+                    0xE0 => volume = other.parameters[0], // SetTrackVolume
+                    0xE3 => expression = other.parameters[0], // SetTrackExpression
+                    0xE8 => pan = other.parameters[0], // SetTrackPan
+                    _ => {  }
+                },
+                DSEEvent::FixedDurationPause(_) => {  }
+            }
+        }
+    }
+    notes.sort_by_key(|note| note.start_tick);
+    (notes, tempos)
+}
+
+/// Converts ticks to seconds following the tempo changes of a song
+struct TempoMap {
+    /// (tick, seconds at that tick, bpm from that tick on), starting at tick 0
+    changes: Vec<(u128, f64, f64)>,
+    tpqn: f64
+}
+impl TempoMap {
+    fn new(tempos: &BTreeMap<u128, f64>, tpqn: u16) -> TempoMap {
+        let mut tempo_map = TempoMap { changes: vec![(0, 0.0, DEFAULT_BPM)], tpqn: tpqn as f64 };
+        for (&tick, &bpm) in tempos {
+            if tick == 0 {
+                tempo_map.changes[0].2 = bpm;
+            } else {
+                let seconds = tempo_map.seconds_at(tick);
+                tempo_map.changes.push((tick, seconds, bpm));
+            }
+        }
+        tempo_map
+    }
+    fn seconds_at(&self, tick: u128) -> f64 {
+        let i = self.changes.partition_point(|&(change_tick, _, _)| change_tick <= tick) - 1;
+        let (change_tick, seconds, bpm) = self.changes[i];
+        seconds + (tick - change_tick) as f64 * 60.0 / (bpm * self.tpqn)
+    }
+}
+
+/// A volume envelope with its times in seconds and its levels from 0 to 1
+struct Envelope {
+    attack_level: f64,
+    attack: f64,
+    hold: f64,
+    decay: f64,
+    sustain_level: f64,
+    decay2: f64,
+    release: f64
+}
+impl Envelope {
+    fn new(volume_envelope: &ADSRVolumeEnvelope) -> Option<Envelope> {
+        if !volume_envelope.envon {
+            return None;
+        }
+        let times = volume_envelope.display_times();
+        Some(Envelope {
+            attack_level: times.attack_level as f64 / 127.0,
+            attack: times.attack as f64 / 1000.0,
+            hold: times.hold as f64 / 1000.0,
+            decay: times.decay as f64 / 1000.0,
+            sustain_level: times.sustain_level as f64 / 127.0,
+            decay2: times.decay2 as f64 / 1000.0,
+            release: (times.release as f64 / 1000.0).min(MAX_RELEASE_SECONDS)
+        })
+    }
+    /// Level `t` seconds after the note started, while the key is still down
+    fn level_held(&self, mut t: f64) -> f64 {
+        if t < self.attack {
+            return self.attack_level + (1.0 - self.attack_level) * t / self.attack;
+        }
+        t -= self.attack;
+        if t < self.hold {
+            return 1.0;
+        }
+        t -= self.hold;
+        if t < self.decay {
+            return 1.0 + (self.sustain_level - 1.0) * t / self.decay;
+        }
+        t -= self.decay;
+        if t < self.decay2 {
+            return self.sustain_level * (1.0 - t / self.decay2);
+        }
+        0.0
+    }
+    /// Level `t` seconds after the note started, with the key released `released` seconds after the note started
+    fn level(&self, t: f64, released: f64) -> f64 {
+        if t < released {
+            self.level_held(t)
+        } else if t - released < self.release {
+            self.level_held(released) * (1.0 - (t - released) / self.release)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A split being played by a note
+struct Voice {
+    smpl_id: u16,
+    /// Start, release, and end in seconds
+    start: f64,
+    release: f64,
+    end: f64,
+    /// Sample frames to advance per output frame
+    step: f64,
+    gain_l: f64,
+    gain_r: f64,
+    envelope: Option<Envelope>
+}
+
+/// Render a song to interleaved stereo 16-bit PCM at `sample_rate`, playing it through once without looping.
+///
+/// This is a basic renderer meant for checking conversions by ear, not a faithful emulation of the DSE driver:
+/// * Notes are matched to every split of their program covering their key and velocity. Programs missing from the SWDL are silent.
+/// * Keygroups limit how many voices play at once, and the oldest voice of a full keygroup is cut off. Priorities are ignored.
+/// * Pitch comes from the key, the root key and tuning of the split, and the sample rate of the sample. Pitch bend and LFOs are ignored.
+/// * Volume is the product of the velocity, track volume and expression, split volume and program volume. Pans are added up around the center.
+/// * Envelopes are linear, with releases capped to 10 seconds.
+///
+/// The SWDL must carry its own sample data, so songs that use the main bank should be rendered with a SWDL merged with it. Samples in formats that can't be decoded are skipped.
+pub fn render_song(smdl: &SMDL, swdl: &SWDL, sample_rate: u32) -> Result<Vec<i16>, DSEError> {
+    if smdl.song.tpqn == 0 {
+        return Err(DSEError::Invalid("Song tpqn must be greater than zero!".to_string()));
+    }
+    if sample_rate == 0 {
+        return Err(DSEError::Invalid("Sample rate must be greater than zero!".to_string()));
+    }
+    let pcmd = swdl.pcmd.as_ref().ok_or(DSEError::SwdlPcmdMissing())?;
+    let (notes, tempos) = collect_notes(smdl);
+    let tempo_map = TempoMap::new(&tempos, smdl.song.tpqn);
+    let keygroup_usage = swdl.keygroup_usage();
+
+    // ======== VOICE ALLOCATION ========
+    let mut voices: Vec<Voice> = Vec::new();
+    let mut keygroup_voices: HashMap<u8, Vec<usize>> = HashMap::new();
+    for note in &notes {
+        let program = if let Some(program) = swdl.prgi.as_ref().and_then(|prgi| prgi.data.objects.iter().find(|program| program.header.id == note.program as u16)) {
+            program
+        } else {
+            debug!("Program {} is missing! Its notes are skipped.", note.program);
+            continue;
+        };
+        let start = tempo_map.seconds_at(note.start_tick);
+        let release = tempo_map.seconds_at(note.end_tick);
+        for split in &program.splits_table.objects {
+            if !(split.lowkey as i32 <= note.key && note.key <= split.hikey as i32 && split.lovel as i32 <= note.velocity as i32 && note.velocity as i32 <= split.hivel as i32) {
+                continue;
+            }
+            if swdl.sample_info(split.SmplID).is_err() {
+                continue;
+            }
+
+            let max_voices = keygroup_usage.get(&split.kgrpid).and_then(|usage| usage.max_voices());
+            let active = keygroup_voices.entry(split.kgrpid).or_default();
+            active.retain(|&voice_i| voices[voice_i].end > start);
+            if let Some(max_voices) = max_voices {
+                if max_voices == 0 {
+                    continue;
+                }
+                while active.len() >= max_voices {
+                    let oldest = active.remove(0);
+                    voices[oldest].end = voices[oldest].end.min(start);
+                }
+            }
+
+            let envelope = Envelope::new(&split.volume_envelope);
+            let end = release + envelope.as_ref().map(|envelope| envelope.release).unwrap_or(0.0);
+            let cents = (note.key - split.rootkey as i32) as f64 * 100.0 + split.tuning.to_cents() as f64;
+            let step = 2.0_f64.powf(cents / 1200.0) * HARDWARE_OUTPUT_RATE / sample_rate as f64;
+            let gain = [note.velocity, note.volume, note.expression, split.smplvol.max(0) as u8, program.header.prgvol.max(0) as u8].iter().map(|&x| x as f64 / 127.0).product::<f64>();
+            let pan = (note.pan as i32 + split.smplpan as i32 + program.header.prgpan as i32 - 128).clamp(0, 127) as f64 / 127.0;
+            active.push(voices.len());
+            voices.push(Voice { smpl_id: split.SmplID, start, release, end, step, gain_l: gain * (pan * FRAC_PI_2).cos(), gain_r: gain * (pan * FRAC_PI_2).sin(), envelope });
+        }
+    }
+
+    // ======== MIXING ========
+    let total_frames = voices.iter().map(|voice| (voice.end * sample_rate as f64).ceil() as usize).max().unwrap_or(0);
+    let mut mix = vec![0.0_f64; total_frames * 2];
+    let mut decoded: HashMap<u16, Option<(Vec<i16>, Option<usize>)>> = HashMap::new();
+    for voice in &voices {
+        if !decoded.contains_key(&voice.smpl_id) {
+            let sample_info = swdl.sample_info(voice.smpl_id)?;
+            let entry = match sample_info.decode_samples(pcmd) {
+                Ok(samples) => {
+                    let loop_start = if sample_info.smplloop { Some(sample_info.loop_start_frame()).filter(|&loop_start| loop_start < samples.len()) } else { None };
+                    Some((samples, loop_start))
+                },
+                Err(DSEError::UnsupportedSampleFormat(_)) | Err(DSEError::UnknownSampleFormat(_)) => {
+                    debug!("Sample {} can't be decoded! Its voices are skipped.", voice.smpl_id);
+                    None
+                },
+                Err(e) => return Err(e)
+            };
+            decoded.insert(voice.smpl_id, entry);
+        }
+        let (samples, loop_start) = if let Some(Some((samples, loop_start))) = decoded.get(&voice.smpl_id) { (samples, *loop_start) } else { continue };
+        if samples.is_empty() {
+            continue;
+        }
+        let start_frame = (voice.start * sample_rate as f64) as usize;
+        let end_frame = ((voice.end * sample_rate as f64).ceil() as usize).min(total_frames);
+        let mut pos = 0.0_f64;
+        for frame in start_frame..end_frame {
+            while pos >= samples.len() as f64 {
+                match loop_start {
+                    Some(loop_start) => pos -= (samples.len() - loop_start) as f64,
+                    None => break
+                }
+            }
+            if pos >= samples.len() as f64 {
+                break;
+            }
+            let i = pos as usize;
+            let next = if i + 1 < samples.len() { samples[i + 1] } else { loop_start.map(|loop_start| samples[loop_start]).unwrap_or(0) };
+            let frac = pos - i as f64;
+            let value = (samples[i] as f64 * (1.0 - frac) + next as f64 * frac) / 32768.0;
+            let t = (frame - start_frame) as f64 / sample_rate as f64;
+            let level = match &voice.envelope {
+                Some(envelope) => envelope.level(t, voice.release - voice.start),
+                None => 1.0
+            };
+            mix[frame * 2] += value * level * voice.gain_l;
+            mix[frame * 2 + 1] += value * level * voice.gain_r;
+            pos += voice.step;
+        }
+    }
+    Ok(mix.into_iter().map(|x| (x * 32767.0).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smdl::{TrkChunk, create_pause_events, events};
+    use crate::swdl::{SampleInfo, SampleFormat, ProgramInfo, SplitEntry, PRGIChunk, KGRPChunk, Keygroup, PCMDChunk, lookup_env_time_value_i32};
+
+    const SAMPLE_RATE: u32 = 32000;
+
+    fn other(code: u8, parameter: u8) -> DSEEvent {
+        let mut event = events::Other::default();
+        event.code = code;
+        event.parameters[0] = parameter;
+        DSEEvent::Other(event)
+    }
+    fn note(keydownduration: u32) -> DSEEvent {
+        let mut play_note = events::PlayNote::default();
+        play_note.velocity = 100;
+        play_note.octavemod = 2;
+        play_note.keydownduration = keydownduration;
+        DSEEvent::PlayNote(play_note)
+    }
+    /// A song with a tpqn of 48 and a single track playing `events` on program 0
+    fn tiny_smdl(events: Vec<DSEEvent>) -> SMDL {
+        let mut smdl = SMDL::default();
+        smdl.song.tpqn = 48;
+        let mut trk = TrkChunk::default();
+        trk.events.events = [vec![other(0xAC, 0)], events, vec![other(0x98, 0)]].concat(); // SetProgram, ..., EndOfTrack
+        smdl.trks.objects.push(trk);
+        smdl
+    }
+    /// A bank with a looping PCM16 sample of constant level, played by program 0 through a single split in keygroup 1
+    fn tiny_swdl(volume_envelope: ADSRVolumeEnvelope, poly: i8) -> SWDL {
+        let mut swdl = SWDL::default();
+        swdl.wavi.data.objects.push(SampleInfo::builder(0).rootkey(48).format(SampleFormat::Pcm16).smplrate(32728).loop_bounds(0, 16).smplloop(true).build());
+        let mut pcmd = PCMDChunk::default();
+        pcmd.data = 8000_i16.to_le_bytes().repeat(32);
+        swdl.pcmd = Some(pcmd);
+
+        let mut program_info = ProgramInfo::default();
+        program_info.header.prgvol = 127;
+        program_info.header.prgpan = 64;
+        let mut split = SplitEntry::default();
+        (split.lowkey, split.hikey, split.lovel, split.hivel) = (0, 127, 0, 127);
+        (split.rootkey, split.smplvol, split.smplpan, split.kgrpid) = (48, 127, 64, 1);
+        split.volume_envelope = volume_envelope;
+        program_info.splits_table.objects = vec![split];
+        let mut prgi = PRGIChunk::new(0);
+        prgi.data.objects.push(program_info);
+        swdl.prgi = Some(prgi);
+
+        let mut kgrp = KGRPChunk::default();
+        kgrp.data.objects = (0..2).map(|id| Keygroup { id, poly: if id == 1 { poly } else { -1 }, priority: 8, vclow: 0, vchigh: 15, unk50: 0, unk51: 0 }).collect();
+        swdl.kgrp = Some(kgrp);
+        swdl
+    }
+
+    #[test]
+    fn renders_a_note_with_its_release() {
+        let mut volume_envelope = ADSRVolumeEnvelope::default();
+        volume_envelope.envon = true;
+        volume_envelope.atkvol = 127;
+        volume_envelope.sustain = 127;
+        volume_envelope.decay2 = 127;
+        volume_envelope.release = lookup_env_time_value_i32(200);
+        let release = Envelope::new(&volume_envelope).unwrap().release;
+        assert!(release > 0.0);
+
+        // A quarter note at 150 bpm lasts 0.4 seconds
+        let smdl = tiny_smdl(vec![other(0xA4, 150), note(48)]); // SetTempo
+        let output = render_song(&smdl, &tiny_swdl(volume_envelope, -1), SAMPLE_RATE).unwrap();
+        assert_eq!(output.len(), ((0.4 + release) * SAMPLE_RATE as f64).ceil() as usize * 2);
+        let note_frames = (0.4 * SAMPLE_RATE as f64) as usize;
+        assert!(output[..note_frames * 2].iter().all(|&x| x != 0));
+    }
+
+    #[test]
+    fn keygroup_with_a_single_voice_cuts_the_first_note() {
+        // At the default 120 bpm, the first note lasts a second. The second note starts a quarter of a second in and lasts another quarter of a second.
+        let smdl = tiny_smdl([vec![note(96)], create_pause_events(24).unwrap(), vec![note(24)]].concat());
+        let output_frames = |poly: i8| render_song(&smdl, &tiny_swdl(ADSRVolumeEnvelope::default(), poly), SAMPLE_RATE).unwrap().len() / 2;
+        assert_eq!(output_frames(-1), SAMPLE_RATE as usize);
+        assert_eq!(output_frames(2), SAMPLE_RATE as usize);
+        // With a single voice, the first note is cut when the second one starts, so the song ends with the second note
+        assert_eq!(output_frames(1), SAMPLE_RATE as usize / 2);
+    }
+
+    #[test]
+    fn zero_tpqn_or_sample_rate_is_an_error() {
+        let swdl = tiny_swdl(ADSRVolumeEnvelope::default(), -1);
+        let mut smdl = tiny_smdl(vec![note(48)]);
+        assert!(render_song(&smdl, &swdl, 0).is_err());
+        smdl.song.tpqn = 0;
+        assert!(render_song(&smdl, &swdl, SAMPLE_RATE).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use std::io::{Read, Write, Seek, SeekFrom};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+
+use crate::dtype::DSEError;
+
+pub type FourCC = [u8; 4];
+
+/// Walk the RIFF chunks between `start` and `start + len`, returning the id, offset of the chunk data, and length of the chunk data for each one.
+pub fn read_chunks_in<R: Read + Seek>(reader: &mut R, start: u64, len: u64) -> Result<Vec<(FourCC, u64, u64)>, DSEError> {
+    let end = start + len;
+    let mut chunks = Vec::new();
+    let mut pos = start;
+    while pos + 8 <= end {
+        reader.seek(SeekFrom::Start(pos))?;
+        let mut id: FourCC = [0; 4];
+        reader.read_exact(&mut id)?;
+        let chunk_len = reader.read_u32::<LittleEndian>()? as u64;
+        let data_offset = pos + 8;
+        if data_offset + chunk_len > end {
+            return Err(DSEError::Invalid(format!("RIFF chunk '{}' at offset {} declares a length of {} bytes, which runs past the end of its container!", String::from_utf8_lossy(&id), pos, chunk_len)));
+        }
+        chunks.push((id, data_offset, chunk_len));
+        pos = data_offset + chunk_len + (chunk_len & 1); // Chunks are padded to an even length
+    }
+    reader.seek(SeekFrom::Start(end))?;
+    Ok(chunks)
+}
+/// Walk the RIFF chunks from the current position to the end of the stream, returning the id, offset of the chunk data, and length of the chunk data for each one.
+/// 
+/// Container chunks like `RIFF` and `LIST` are not descended into. Their contents start with a 4 byte form type, after which `read_chunks_in` can be used to walk the subchunks.
+pub fn read_chunks<R: Read + Seek>(reader: &mut R) -> Result<Vec<(FourCC, u64, u64)>, DSEError> {
+    let start = reader.seek(SeekFrom::Current(0))?;
+    let end = reader.seek(SeekFrom::End(0))?;
+    read_chunks_in(reader, start, end - start)
+}
+/// Read the top-level `RIFF` chunk at the current position, returning its form type (e.g. `WAVE` or `sfbk`) and its subchunks.
+pub fn read_riff<R: Read + Seek>(reader: &mut R) -> Result<(FourCC, Vec<(FourCC, u64, u64)>), DSEError> {
+    let start = reader.seek(SeekFrom::Current(0))?;
+    let end = reader.seek(SeekFrom::End(0))?;
+    let chunks = read_chunks_in(reader, start, end - start)?;
+    let &(_, data_offset, data_len) = chunks.first().filter(|(id, _, _)| id == b"RIFF").ok_or(DSEError::Invalid("File is not a RIFF file!".to_string()))?;
+    if data_len < 4 {
+        return Err(DSEError::Invalid("RIFF chunk is too short to contain a form type!".to_string()));
+    }
+    reader.seek(SeekFrom::Start(data_offset))?;
+    let mut form_type: FourCC = [0; 4];
+    reader.read_exact(&mut form_type)?;
+    Ok((form_type, read_chunks_in(reader, data_offset + 4, data_len - 4)?))
+}
+/// Write a RIFF chunk with its id, length, data, and a padding byte if the data has an odd length
+pub fn write_chunk<W: Write>(writer: &mut W, id: &FourCC, data: &[u8]) -> Result<usize, DSEError> {
+    writer.write_all(id)?;
+    writer.write_u32::<LittleEndian>(data.len().try_into().map_err(|_| DSEError::Invalid("RIFF chunk is too large!".to_string()))?)?;
+    writer.write_all(data)?;
+    if data.len() % 2 == 1 {
+        writer.write_u8(0)?;
+        return Ok(9 + data.len());
+    }
+    Ok(8 + data.len())
+}
@@ -1,31 +1,38 @@
 use core::panic;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::Debug;
+use std::hash::Hasher;
 use std::fs::File;
 use std::io::{Read, Write, Seek, SeekFrom, Cursor};
 use std::path::Path;
 use bevy_reflect::Reflect;
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use log::{debug, info};
 use serde::{Serialize, Deserialize};
 
 use crate::fileutils::valid_file_of_type;
-use crate::swdl::DSEString;
+use crate::swdl::{DSEString, SWDL};
 use crate::peek_byte;
 use crate::dtype::*;
 use crate::deserialize_with;
 
 pub mod midi;
+pub mod dse_command;
 
 /// By default, all unknown bytes that do not have a consistent pattern of values in the EoS roms are included in the XML.
 /// However, a subset of these not 100% purpose-certain bytes is 80% or something of values that have "typical" values.
 /// Setting this to true will strip all those somewhat certain bytes from the Serde serialization process, and replace them
 /// with their typical values.
-const fn serde_use_common_values_for_unknowns<T>(_: &T) -> bool {
-    true
+/// 
+/// This can be toggled at runtime with `dtype::set_strip_common_unknowns`.
+fn serde_use_common_values_for_unknowns<T>(_: &T) -> bool {
+    strip_common_unknowns()
 }
 
 //// NOTE: Any struct fields starting with an _ indicates that that struct field will be ignored when writing, with its appropriate value generate on-the-fly based on the other fields
 
-#[derive(Debug, Reflect, Serialize, Deserialize)]
+#[derive(Debug, Clone, Reflect, Serialize, Deserialize)]
 pub struct SMDLHeader {
     #[serde(default = "GenericDefaultU32::<0x6C646D73>::value")]
     #[serde(skip_serializing)]
@@ -108,7 +115,7 @@ impl Default for SMDLHeader {
 }
 impl AutoReadWrite for SMDLHeader {  }
 
-#[derive(Debug, Reflect, Serialize, Deserialize)]
+#[derive(Debug, Clone, Reflect, Serialize, Deserialize)]
 pub struct SongChunk {
     #[serde(default = "GenericDefaultU32::<0x676E6F73>::value")]
     #[serde(skip_serializing)]
@@ -165,7 +172,9 @@ pub struct SongChunk {
     pub unk12: u32, // usually 0xffffff00
 
     #[serde(default = "GenericDefaultByteArray::<0xFF, 16>::value")]
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing_if = "SongChunk::skip_serializing_unkpad")]
+    /// Assumed to always be 16 0xFF bytes, which is the case for every SMD checked so far. `survey_unkpad` can be used to check this assumption against a collection of files.
+    /// In strict mode, any value that deviates from this is kept in the XML so that it survives a round-trip.
     pub unkpad: [u8; 16], // unknown sequence of 16 0xFF bytes
 }
 impl Default for SongChunk {
@@ -191,6 +200,14 @@ impl Default for SongChunk {
         }
     }
 }
+impl SongChunk {
+    pub fn unkpad_is_standard(&self) -> bool {
+        self.unkpad == [0xFF; 16]
+    }
+    fn skip_serializing_unkpad(unkpad: &[u8; 16]) -> bool {
+        !strict_mode() || *unkpad == [0xFF; 16]
+    }
+}
 impl AutoReadWrite for SongChunk {  }
 
 #[derive(Debug, Reflect, Serialize, Deserialize)]
@@ -255,7 +272,7 @@ pub mod events {
 
     use crate::dtype::{ReadWrite, DSEError};
 
-    #[derive(Debug, Default, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
     pub struct PlayNote {
         pub velocity: u8,
         #[serde(default)]
@@ -294,8 +311,6 @@ pub mod events {
             self.octavemod = (note_data & 0b00110000) >> 4;
             self.note = note_data & 0b00001111;
 
-            // print!("{}", ['t', 'T', 'y', 'Y', 'u', 'i', 'I', 'o', 'O', 'p', 'P', 'a', ' '][self.note as usize]);
-
             let mut keydownduration = [0_u8; 4];
             for i in (4-self._nbparambytes as usize)..4 {
                 keydownduration[i] = reader.read_u8()?;
@@ -305,10 +320,20 @@ pub mod events {
         }
     }
 
-    #[derive(Debug, Default, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
     pub struct FixedDurationPause {
         duration: u8,
     }
+    /// Durations in ticks of the fixed duration pauses 0x80 to 0x8F (from dse_sequence.hpp, ppmdu_2)
+    const FIXED_DURATION_PAUSE_TICKS: [u8; 16] = [96, 72, 64, 48, 36, 32, 24, 18, 16, 12, 9, 8, 6, 4, 3, 2];
+    impl FixedDurationPause {
+        pub fn code(&self) -> u8 {
+            self.duration
+        }
+        pub fn ticks(&self) -> u32 {
+            FIXED_DURATION_PAUSE_TICKS[(self.duration & 0x0F) as usize] as u32
+        }
+    }
     impl ReadWrite for FixedDurationPause {
         fn write_to_file<W: std::io::Read + std::io::Write + std::io::Seek>(&self, writer: &mut W) -> Result<usize, DSEError> {
             writer.write_u8(self.duration)?;
@@ -448,7 +473,7 @@ pub mod events {
             Other::name_to_code(&String::deserialize(d)?).map_err(serde::de::Error::custom)
         }
     }
-    #[derive(Debug, Default, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
     pub struct Other {
         #[serde(rename = "@code")]
         #[serde(with = "named")]
@@ -476,6 +501,20 @@ pub mod events {
         pub fn is_eot_event(&self) -> bool {
             self.code == 0x98
         }
+        /// If this event is a pause, returns how many ticks it pauses for. `last_pause` is the duration of the previous pause in the track, needed by RepeatLastPause and AddToLastPause.
+        /// 
+        /// PauseUntilRelease depends on the playback state and is counted as zero ticks.
+        pub fn pause_ticks(&self, last_pause: u32) -> Option<u32> {
+            match self.code {
+                0x90 => Some(last_pause),
+                0x91 => Some(last_pause + self.parameters[0] as u32),
+                0x92 => Some(self.parameters[0] as u32),
+                0x93 => Some((&self.parameters[..2]).read_u16::<LittleEndian>().ok()? as u32),
+                0x94 => Some((&self.parameters[..3]).read_u24::<LittleEndian>().ok()?),
+                0x95 => Some(0),
+                _ => None
+            }
+        }
     }
     impl ReadWrite for Other {
         fn write_to_file<W: std::io::Read + std::io::Write + std::io::Seek>(&self, writer: &mut W) -> Result<usize, DSEError> {
@@ -495,7 +534,7 @@ pub mod events {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DSEEvent {
     PlayNote(events::PlayNote),
     FixedDurationPause(events::FixedDurationPause),
@@ -513,6 +552,14 @@ impl DSEEvent {
             _ => false
         }
     }
+    /// If this event is a pause, returns how many ticks it pauses for. See `events::Other::pause_ticks`.
+    pub fn pause_ticks(&self, last_pause: u32) -> Option<u32> {
+        match self {
+            DSEEvent::FixedDurationPause(pause) => Some(pause.ticks()),
+            DSEEvent::Other(other) => other.pause_ticks(last_pause),
+            DSEEvent::PlayNote(_) => None
+        }
+    }
 }
 impl ReadWrite for DSEEvent {
     fn write_to_file<W: Read + Write + Seek>(&self, writer: &mut W) -> Result<usize, DSEError> {
@@ -583,9 +630,17 @@ impl ReadWrite for TrkEvents {
             Ok((event, reader.seek(SeekFrom::Current(0))?))
         };
         (evt, current_cursor_pos) = read_event()?;
+        let mut reached_eot = evt.is_eot_event();
         self.events.push(evt);
         while current_cursor_pos < start_cursor_pos + _trk_events_len {
+            if reached_eot && strict_mode() {
+                // Nothing should come after the end of track, so whatever is left can't be trusted to be events
+                let mut trailing = vec![0; (start_cursor_pos + _trk_events_len - current_cursor_pos) as usize];
+                reader.read_exact(&mut trailing)?;
+                return Err(DSEError::DSEUnexpectedTrailingEventBytes(current_cursor_pos, trailing.len(), trailing));
+            }
             (evt, current_cursor_pos) = read_event()?;
+            reached_eot = evt.is_eot_event();
             self.events.push(evt);
         }
         Ok(())
@@ -600,18 +655,25 @@ pub struct TrkChunk {
     pub header: TrkChunkHeader,
     #[serde(flatten)]
     pub preamble: TrkChunkPreamble,
+    /// Informational only. Names of the soundfont presets used by this track, as recorded during MIDI import. Never read back from XML and not written to the binary.
+    #[serde(default)]
+    #[serde(skip_deserializing)]
+    #[serde(rename = "@presets")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _preset_names: Option<String>,
     pub events: TrkEvents,
     #[serde(default)]
     #[serde(skip_serializing)]
-    pub _padding: Vec<u8>
+    pub _padding: Option<Vec<u8>> // The padding as read, which may be empty. `None` when the track wasn't read from a file
 }
 impl Default for TrkChunk {
     fn default() -> Self {
         TrkChunk {
             header: TrkChunkHeader::default(),
             preamble: TrkChunkPreamble::default(),
+            _preset_names: None,
             events: TrkEvents::new(0),
-            _padding: Vec::new()
+            _padding: None
         }
     }
 }
@@ -620,8 +682,17 @@ impl ReadWrite for TrkChunk {
         let mut bytes_written = self.header.write_to_file(writer)?;
         bytes_written += self.preamble.write_to_file(writer)?;
         bytes_written += self.events.write_to_file(writer)?;
+        // The chunk header is 16 bytes, so aligning the total also aligns the preamble and events the reader consumed before the padding
         let bytes_written_aligned = ((bytes_written - 1) | 3) + 1;
         let pad_len = bytes_written_aligned - bytes_written;
+        if let Some(padding) = self._padding.as_ref().filter(|_| strict_mode()) {
+            // Reproduce the padding exactly as read, even if it's unusual or missing
+            if padding.len() != pad_len {
+                debug!("trk{:02} has {} bytes of padding instead of the usual {}, preserving it.", self.preamble.trkid, padding.len(), pad_len);
+            }
+            writer.write_all(padding)?;
+            return Ok(bytes_written + padding.len());
+        }
         for _ in 0..pad_len {
             writer.write_u8(0x98)?;
         }
@@ -629,15 +700,202 @@ impl ReadWrite for TrkChunk {
     }
     fn read_from_file<R: Read + Seek>(&mut self, reader: &mut R) -> Result<(), DSEError> {
         self.header.read_from_file(reader)?;
+        let start_cursor_pos = reader.seek(SeekFrom::Current(0))?;
         self.preamble.read_from_file(reader)?;
         self.events.set_read_params(self.header.chunklen as u64);
         self.events.read_from_file(reader)?;
+        let bytes_consumed = reader.seek(SeekFrom::Current(0))? - start_cursor_pos;
+        if strict_mode() && bytes_consumed != self.header.chunklen as u64 {
+            return Err(DSEError::TrackLengthMismatch(self.header.chunklen as u64, bytes_consumed));
+        }
+        let mut padding = Vec::new();
         while peek_byte!(reader)? == 0x98 {
-            self._padding.push(reader.read_u8()?);
+            padding.push(reader.read_u8()?);
         }
+        self._padding = Some(padding);
         Ok(())
     }
 }
+impl TrkChunk {
+    /// Pair every event in the track with the absolute tick it occurs at
+    pub fn events_with_ticks(&self) -> Vec<(u128, &DSEEvent)> {
+        let mut events_with_ticks = Vec::with_capacity(self.events.events.len());
+        let mut global_tick: u128 = 0;
+        let mut last_pause: u32 = 0;
+        for event in &self.events.events {
+            events_with_ticks.push((global_tick, event));
+            if let Some(pause) = event.pause_ticks(last_pause) {
+                if pause != 0 {
+                    last_pause = pause;
+                }
+                global_tick += pause as u128;
+            }
+        }
+        events_with_ticks
+    }
+    /// Insert events so that they occur at the absolute tick `tick`, after any other events already at that tick. Pauses are split as needed, and the track is extended if it ends before `tick`.
+    pub fn insert_events_at_tick(&mut self, tick: u128, new_events: Vec<DSEEvent>) -> Result<(), DSEError> {
+        let end_tick = self.total_ticks();
+        if tick >= end_tick {
+            let has_eot = self.events.events.last().map(|evt| evt.is_eot_event()).unwrap_or(false);
+            let eot_event = if has_eot { self.events.events.pop() } else { None };
+            self.events.events.extend(create_pause_events(tick - end_tick)?);
+            self.events.events.extend(new_events);
+            self.events.events.extend(eot_event);
+            return Ok(());
+        }
+        let mut global_tick: u128 = 0;
+        let mut last_pause: u32 = 0;
+        for i in 0..self.events.events.len() {
+            if let Some(pause) = self.events.events[i].pause_ticks(last_pause) {
+                if pause != 0 && global_tick + pause as u128 > tick {
+                    let nbinserted;
+                    if global_tick == tick {
+                        nbinserted = new_events.len();
+                        self.events.events.splice(i..i, new_events);
+                        self.fix_relative_pauses(i + nbinserted, last_pause)?;
+                    } else {
+                        // Split the pause around the new events
+                        let mut replacement = create_pause_events(tick - global_tick)?;
+                        replacement.extend(new_events);
+                        replacement.extend(create_pause_events(global_tick + pause as u128 - tick)?);
+                        nbinserted = replacement.len();
+                        self.events.events.splice(i..(i + 1), replacement);
+                        self.fix_relative_pauses(i + nbinserted, pause)?;
+                    }
+                    return Ok(());
+                }
+                if pause != 0 {
+                    last_pause = pause;
+                }
+                global_tick += pause as u128;
+            }
+        }
+        Ok(())
+    }
+    /// Replace RepeatLastPause and AddToLastPause events starting from index `start` with explicit pauses, until the next pause that doesn't depend on the previous one. `last_pause` is the duration they originally referred to.
+    fn fix_relative_pauses(&mut self, start: usize, mut last_pause: u32) -> Result<(), DSEError> {
+        let mut i = start;
+        while i < self.events.events.len() {
+            let pause = self.events.events[i].pause_ticks(last_pause);
+            let is_relative = match &self.events.events[i] {
+                DSEEvent::Other(other) => other.code == 0x90 || other.code == 0x91,
+                _ => false
+            };
+            if let Some(pause) = pause {
+                if is_relative {
+                    let replacement = create_pause_events(pause as u128)?;
+                    let nbreplacement = replacement.len();
+                    self.events.events.splice(i..(i + 1), replacement);
+                    i += nbreplacement;
+                    if pause != 0 {
+                        last_pause = pause;
+                    }
+                    continue;
+                } else if pause != 0 {
+                    break;
+                }
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+    /// Total length of the track in ticks, counting every kind of pause. RepeatLastPause and AddToLastPause are resolved against the last pause before them.
+    pub fn total_ticks(&self) -> u128 {
+        let mut global_tick: u128 = 0;
+        let mut last_pause: u32 = 0;
+        for event in &self.events.events {
+            if let Some(pause) = event.pause_ticks(last_pause) {
+                if pause != 0 {
+                    last_pause = pause;
+                }
+                global_tick += pause as u128;
+            }
+        }
+        global_tick
+    }
+    /// Pair every event except pauses and EndOfTrack with the absolute tick it occurs at. Octave events are consumed and notes get their absolute key and duration, so that the events still mean the same thing when moved to another track.
+    fn absolute_events(&self) -> Vec<(u128, AbsoluteEvent)> {
+        let mut absolute_events = Vec::new();
+        let mut global_tick: u128 = 0;
+        let mut last_pause: u32 = 0;
+        let mut octave: i32 = 4; // Default track octave
+        let mut last_duration: u32 = 0;
+        for event in &self.events.events {
+            if let Some(pause) = event.pause_ticks(last_pause) {
+                if pause != 0 {
+                    last_pause = pause;
+                }
+                global_tick += pause as u128;
+                continue;
+            }
+            match event {
+                DSEEvent::PlayNote(note) => {
+                    octave += note.octavemod as i32 - 2;
+                    if note.keydownduration != 0 {
+                        last_duration = note.keydownduration;
+                    }
+                    let key = (octave * 12 + note.note as i32).clamp(0, 127) as u8;
+                    absolute_events.push((global_tick, AbsoluteEvent::Note { key, velocity: note.velocity, duration: last_duration }));
+                },
+                DSEEvent::Other(other) if other.is_eot_event() => {  },
+                DSEEvent::Other(other) if other.code == 0xA0 => { // SetTrackOctave
+                    octave = other.parameters[0] as i32;
+                },
+                DSEEvent::Other(other) if other.code == 0xA1 => { // AddToTrackOctave
+                    octave += other.parameters[0] as i8 as i32;
+                },
+                event => absolute_events.push((global_tick, AbsoluteEvent::Other(event.clone())))
+            }
+        }
+        absolute_events
+    }
+}
+/// An event taken out of its track by `TrkChunk::absolute_events`
+enum AbsoluteEvent {
+    Note { key: u8, velocity: u8, duration: u32 },
+    Other(DSEEvent)
+}
+/// Create a SetTrackOctave event followed by a PlayNote event, which together play `key` regardless of the octave the track was in
+fn create_absolute_note_events(key: u8, velocity: u8, duration: u32) -> Result<[DSEEvent; 2], DSEError> {
+    let mut octave_event = events::Other::default();
+    octave_event.code = events::Other::name_to_code("SetTrackOctave")?;
+    octave_event.parameters[0] = key / 12;
+    let mut note = events::PlayNote::default();
+    note.velocity = velocity;
+    note.octavemod = 2;
+    note.note = key % 12;
+    note.keydownduration = duration;
+    Ok([DSEEvent::Other(octave_event), DSEEvent::PlayNote(note)])
+}
+/// Name a MIDI key, with middle C (60) being "C4"
+pub fn midi_note_name(key: u8) -> String {
+    const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+    format!("{}{}", NOTE_NAMES[(key % 12) as usize], (key / 12) as i32 - 1)
+}
+/// Create the pause events needed to wait for the given number of ticks
+pub fn create_pause_events(mut ticks: u128) -> Result<Vec<DSEEvent>, DSEError> {
+    let mut pause_events = Vec::new();
+    while ticks > 0 {
+        let mut pause_event = events::Other::default();
+        if let Ok(delta) = u8::try_from(ticks) {
+            pause_event.code = events::Other::name_to_code("Pause8Bits")?;
+            (&mut pause_event.parameters[..]).write_u8(delta)?;
+            ticks = 0;
+        } else if let Ok(delta) = u16::try_from(ticks) {
+            pause_event.code = events::Other::name_to_code("Pause16Bits")?;
+            (&mut pause_event.parameters[..]).write_u16::<LittleEndian>(delta)?;
+            ticks = 0;
+        } else {
+            let delta = ticks.min(0xFFFFFF) as u32;
+            pause_event.code = events::Other::name_to_code("Pause24Bits")?;
+            (&mut pause_event.parameters[..]).write_u24::<LittleEndian>(delta)?;
+            ticks -= delta as u128;
+        }
+        pause_events.push(DSEEvent::Other(pause_event));
+    }
+    Ok(pause_events)
+}
 /// Note: BGM0016 is a counter example to all the indices having to be in perfect order
 impl IsSelfIndexed for TrkChunk {
     fn is_self_indexed(&self) -> Option<usize> {
@@ -722,22 +980,350 @@ impl SMDL {
 
         Ok(())
     }
+    /// Fade the song's volume to zero over the last `duration_ticks` ticks of the song, using a FadeSongVolume event on the meta track (track 0).
+    /// 
+    /// If an event on the meta track doesn't fall exactly `duration_ticks` before the end, the fade starts at the closest event before that instead, and is lengthened to still end with the song.
+    pub fn add_fadeout(&mut self, duration_ticks: u32) -> Result<(), DSEError> {
+        let song_end = self.trks.objects.iter().map(|trk| trk.total_ticks()).max().unwrap_or(0);
+        let fade_start = song_end.saturating_sub(duration_ticks as u128);
+        let meta_trk_i = self.trks.objects.iter().position(|trk| trk.preamble.trkid == 0).ok_or(DSEError::Invalid("SMDL has no meta track (track 0) to place the fade-out on!".to_string()))?;
+        let meta_trk = &mut self.trks.objects[meta_trk_i];
+
+        // Drop the end of track event, it's added back at the end
+        let has_eot = meta_trk.events.events.last().map(|evt| evt.is_eot_event()).unwrap_or(false);
+        if has_eot {
+            meta_trk.events.events.pop();
+        }
+        let meta_trk_end = meta_trk.total_ticks();
+        let (insert_i, insert_tick) = if meta_trk_end <= fade_start {
+            let pause_events = create_pause_events(fade_start - meta_trk_end)?;
+            meta_trk.events.events.extend(pause_events);
+            (meta_trk.events.events.len(), fade_start)
+        } else {
+            let events_with_ticks = meta_trk.events_with_ticks();
+            let insert_i = events_with_ticks.iter().rposition(|(tick, _)| *tick <= fade_start).unwrap_or(0);
+            (insert_i, events_with_ticks.get(insert_i).map(|(tick, _)| *tick).unwrap_or(0))
+        };
+
+        let fade_duration = u16::try_from(song_end - insert_tick).map_err(|_| DSEError::Invalid(format!("Fade-out duration of {} ticks is too long! FadeSongVolume can only fade over at most {} ticks.", song_end - insert_tick, u16::MAX)))?;
+        let mut fade_event = events::Other::default();
+        fade_event.code = events::Other::name_to_code("FadeSongVolume")?;
+        let mut params = &mut fade_event.parameters[..];
+        params.write_u16::<LittleEndian>(fade_duration)?; // Rate
+        params.write_u8(0)?; // Target volume
+        meta_trk.events.events.insert(insert_i, DSEEvent::Other(fade_event));
+
+        let meta_trk_end = meta_trk.total_ticks();
+        let pause_events = create_pause_events(song_end - meta_trk_end)?;
+        meta_trk.events.events.extend(pause_events);
+        let mut eot_event = events::Other::default();
+        eot_event.code = events::Other::name_to_code("EndOfTrack")?;
+        meta_trk.events.events.push(DSEEvent::Other(eot_event));
+        Ok(())
+    }
+    /// Merge every track except the meta track (track 0) onto a single track playing on channel `chanid`. Events are interleaved by their absolute tick.
+    /// 
+    /// The merged track is monophonic: when a note starts while another is still held, the held note is cut short.
+    pub fn collapse_to_channel(&mut self, chanid: u8) -> Result<(), DSEError> {
+        let song_end = self.trks.objects.iter().map(|trk| trk.total_ticks()).max().unwrap_or(0);
+        let mut meta_trk = None;
+        let mut merged: Vec<(u128, AbsoluteEvent)> = Vec::new();
+        for trk in std::mem::take(&mut self.trks.objects) {
+            if trk.preamble.trkid == 0 {
+                meta_trk = Some(trk);
+                continue;
+            }
+            merged.extend(trk.absolute_events());
+        }
+        merged.sort_by_key(|(tick, _)| *tick); // Stable, so events at the same tick keep their track order
+
+        let mut collapsed_events: Vec<DSEEvent> = Vec::with_capacity(merged.len());
+        let mut current_tick: u128 = 0;
+        let mut last_note: Option<(usize, u128)> = None;
+        for (tick, merged_event) in merged {
+            collapsed_events.extend(create_pause_events(tick - current_tick)?);
+            current_tick = tick;
+            match merged_event {
+                AbsoluteEvent::Note { key, velocity, duration } => {
+                    // Cut off the previous note if it's still being held
+                    if let Some((last_note_i, last_note_tick)) = last_note {
+                        if let DSEEvent::PlayNote(held_note) = &mut collapsed_events[last_note_i] {
+                            if last_note_tick + held_note.keydownduration as u128 > tick {
+                                held_note.keydownduration = (tick - last_note_tick) as u32;
+                            }
+                        }
+                    }
+                    collapsed_events.extend(create_absolute_note_events(key, velocity, duration)?);
+                    last_note = Some((collapsed_events.len() - 1, tick));
+                },
+                AbsoluteEvent::Other(event) => collapsed_events.push(event)
+            }
+        }
+        collapsed_events.extend(create_pause_events(song_end - current_tick)?);
+        let mut eot_event = events::Other::default();
+        eot_event.code = events::Other::name_to_code("EndOfTrack")?;
+        collapsed_events.push(DSEEvent::Other(eot_event));
+
+        let mut collapsed_trk = TrkChunk::default();
+        collapsed_trk.preamble.trkid = 1;
+        collapsed_trk.preamble.chanid = chanid;
+        collapsed_trk.events.events = collapsed_events;
+        if let Some(meta_trk) = meta_trk {
+            self.trks.objects.push(meta_trk);
+        }
+        self.trks.objects.push(collapsed_trk);
+        self.regenerate_read_markers()
+    }
+    /// Parse a script of DSE commands in the same syntax as `dsec` MIDI markers, and insert the resulting events at the absolute tick `at_tick`.
+    /// 
+    /// Commands go on the track at index `track` unless the script switches tracks with `trk n` or `evttrk`.
+    pub fn apply_dsec_script(&mut self, track: usize, at_tick: u128, script: &str) -> Result<(), DSEError> {
+        let script = script.trim();
+        let script = script.strip_prefix("dsec").unwrap_or(script);
+        let mut events_per_track: BTreeMap<usize, Vec<DSEEvent>> = BTreeMap::new();
+        for (track_i, evt) in dse_command::parse_dsec_script(script, track)? {
+            if track_i >= self.trks.objects.len() {
+                return Err(DSEError::Invalid(format!("DSE command script refers to track {}, but the SMDL only has {} tracks!", track_i, self.trks.objects.len())));
+            }
+            events_per_track.entry(track_i).or_default().push(DSEEvent::Other(evt));
+        }
+        for (track_i, new_events) in events_per_track {
+            self.trks.objects[track_i].insert_events_at_tick(at_tick, new_events)?;
+        }
+        self.regenerate_read_markers()
+    }
+    /// Set every SetTempo and SetTempo2 event in the song to `new_bpm`, so that the whole song plays at a single tempo. Like `scale_tempo`, the tempo must be within 1-255 bpm.
+    pub fn set_tempo(&mut self, new_bpm: u8) -> Result<(), DSEError> {
+        if new_bpm == 0 {
+            return Err(DSEError::Invalid("Invalid tempo 0 bpm! The tempo must be within 1-255 bpm.".to_string()));
+        }
+        let tempo_codes = [events::Other::name_to_code("SetTempo")?, events::Other::name_to_code("SetTempo2")?];
+        for trk in self.trks.objects.iter_mut() {
+            for event in trk.events.events.iter_mut() {
+                if let DSEEvent::Other(other) = event {
+                    if tempo_codes.contains(&other.code) {
+                        other.parameters[0] = new_bpm;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Multiply every SetTempo and SetTempo2 event in the song by `factor`, keeping any tempo changes in proportion. Results are clamped to 1-255 bpm.
+    pub fn scale_tempo(&mut self, factor: f64) -> Result<(), DSEError> {
+        if !factor.is_finite() || factor <= 0.0 {
+            return Err(DSEError::Invalid(format!("Invalid tempo scale factor {}! The factor must be greater than zero.", factor)));
+        }
+        let tempo_codes = [events::Other::name_to_code("SetTempo")?, events::Other::name_to_code("SetTempo2")?];
+        for trk in self.trks.objects.iter_mut() {
+            for event in trk.events.events.iter_mut() {
+                if let DSEEvent::Other(other) = event {
+                    if tempo_codes.contains(&other.code) {
+                        other.parameters[0] = (other.parameters[0] as f64 * factor).round().clamp(1.0, 255.0) as u8;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Point every SetProgram event at a new program id, according to `mapping` (old id to new id). Programs not in the mapping are left alone.
+    /// 
+    /// Meant to be used with the mapping returned by `SWDL::dedup_programs`.
+    pub fn remap_programs(&mut self, mapping: &HashMap<u16, u16>) -> Result<(), DSEError> {
+        for trk in self.trks.objects.iter_mut() {
+            for event in trk.events.events.iter_mut() {
+                if let DSEEvent::Other(other) = event {
+                    if other.code == 0xAC { // SetProgram
+                        if let Some(&new_id) = mapping.get(&(other.parameters[0] as u16)) {
+                            other.parameters[0] = new_id.try_into().map_err(|_| DSEError::Invalid(format!("Program id {} does not fit in a SetProgram event!", new_id)))?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Group the events of every track by the channel it plays on (`preamble.chanid`), ordered by channel. The events are copied as they are, track after track in track order, so tracks sharing a channel are concatenated rather than interleaved. See `merge_tracks_by_channel` to interleave them by tick instead.
+    pub fn split_tracks_by_channel(&self) -> Vec<(u8, Vec<DSEEvent>)> {
+        let mut channels: BTreeMap<u8, Vec<DSEEvent>> = BTreeMap::new();
+        for trk in &self.trks.objects {
+            channels.entry(trk.preamble.chanid).or_default().extend(trk.events.events.iter().cloned());
+        }
+        channels.into_iter().collect()
+    }
+    /// Like `split_tracks_by_channel`, but merges the tracks sharing a channel into a single playable event list. This transforms the events:
+    /// 
+    /// * The meta track (track 0) is left out.
+    /// * Tracks sharing a channel are interleaved by absolute tick, with events at the same tick kept in track order.
+    /// * Pauses and EndOfTrack events are regenerated, and every note is preceded by a SetTrackOctave so that it keeps its pitch.
+    /// * Each channel ends with a single EndOfTrack at the end of its longest track.
+    pub fn merge_tracks_by_channel(&self) -> Result<Vec<(u8, Vec<DSEEvent>)>, DSEError> {
+        let mut channels: BTreeMap<u8, (u128, Vec<(u128, AbsoluteEvent)>)> = BTreeMap::new();
+        for trk in self.trks.objects.iter().filter(|trk| trk.preamble.trkid != 0) {
+            let (channel_end, merged) = channels.entry(trk.preamble.chanid).or_default();
+            *channel_end = (*channel_end).max(trk.total_ticks());
+            merged.extend(trk.absolute_events());
+        }
+        channels.into_iter().map(|(chanid, (channel_end, mut merged))| {
+            merged.sort_by_key(|(tick, _)| *tick); // Stable, so events at the same tick keep their track order
+            let mut channel_events: Vec<DSEEvent> = Vec::with_capacity(merged.len());
+            let mut current_tick: u128 = 0;
+            for (tick, merged_event) in merged {
+                channel_events.extend(create_pause_events(tick - current_tick)?);
+                current_tick = tick;
+                match merged_event {
+                    AbsoluteEvent::Note { key, velocity, duration } => channel_events.extend(create_absolute_note_events(key, velocity, duration)?),
+                    AbsoluteEvent::Other(event) => channel_events.push(event)
+                }
+            }
+            channel_events.extend(create_pause_events(channel_end - current_tick)?);
+            let mut eot_event = events::Other::default();
+            eot_event.code = events::Other::name_to_code("EndOfTrack")?;
+            channel_events.push(DSEEvent::Other(eot_event));
+            Ok((chanid, channel_events))
+        }).collect()
+    }
+    /// Extend every track that ends before the longest one with pauses, placed before its EndOfTrack, so that all tracks end on the same tick.
+    pub fn pad_tracks_to_longest(&mut self) -> Result<(), DSEError> {
+        let song_end = self.trks.objects.iter().map(|trk| trk.total_ticks()).max().unwrap_or(0);
+        for trk in self.trks.objects.iter_mut() {
+            if trk.total_ticks() < song_end {
+                trk.insert_events_at_tick(song_end, Vec::new())?;
+            }
+        }
+        self.regenerate_read_markers()
+    }
+    /// Hash the musical content of the song: the header minus its timestamp, the song chunk, and the events of every track. The file lengths and other regenerated read markers are left out, so two songs differing only in when they were saved hash identically.
+    /// 
+    /// The hash is stable for a given build of this crate, but is not guaranteed to be stable across Rust versions.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        let mut header = self.header.clone();
+        (header.year, header.month, header.day, header.hour, header.minute, header.second, header.centisecond) = (0, 0, 0, 0, 0, 0, 0);
+        header.flen = 0;
+        hash_bytes_of(&header, &mut hasher);
+        let mut song = self.song.clone();
+        song.nbtrks = 0;
+        hash_bytes_of(&song, &mut hasher);
+        for trk in &self.trks.objects {
+            hash_bytes_of(&trk.preamble, &mut hasher);
+            hash_bytes_of(&trk.events, &mut hasher);
+        }
+        hasher.finish()
+    }
+    /// Count how many notes start within each window of `window_ticks` ticks, across all tracks. The windows cover the whole song.
+    pub fn note_density(&self, window_ticks: u128) -> Vec<(u128, usize)> {
+        if window_ticks == 0 {
+            return Vec::new();
+        }
+        let mut song_end: u128 = 0;
+        let mut note_ticks: Vec<u128> = Vec::new();
+        for trk in &self.trks.objects {
+            for (tick, event) in trk.events_with_ticks() {
+                if let DSEEvent::PlayNote(_) = event {
+                    note_ticks.push(tick);
+                    song_end = song_end.max(tick + 1);
+                }
+                song_end = song_end.max(tick);
+            }
+        }
+        let nwindows = (song_end + window_ticks - 1) / window_ticks;
+        let mut density: Vec<(u128, usize)> = (0..nwindows).map(|i| (i * window_ticks, 0)).collect();
+        for tick in note_ticks {
+            density[(tick / window_ticks) as usize].1 += 1;
+        }
+        density
+    }
+    /// Play through every track without producing sound, keeping track of the notes held and the program, bank, octave and volume of each track, and report the state each track ends in.
+    /// 
+    /// This catches problems that only show up during playback, like notes that are still sounding when their track ends or keys that the octave events push out of range. Programs can't be checked here since they live in the SWDL, see `PlaybackReport::undefined_programs`.
+    pub fn simulate_playback(&self) -> Result<PlaybackReport, DSEError> {
+        let mut tracks = Vec::with_capacity(self.trks.objects.len());
+        for trk in &self.trks.objects {
+            let mut report = TrackPlaybackReport { trkid: trk.preamble.trkid, final_octave: 4, ..Default::default() };
+            // Release tick of each held note, along with its key and start tick
+            let mut notes_held: Vec<(i32, u128, u128)> = Vec::new();
+            let mut last_duration: u32 = 0;
+            let mut last_pause: u32 = 0;
+            let mut global_tick: u128 = 0;
+            for (event_i, event) in trk.events.events.iter().enumerate() {
+                notes_held.retain(|&(_, _, release_tick)| release_tick > global_tick);
+                match event {
+                    DSEEvent::PlayNote(note) => {
+                        report.final_octave += note.octavemod as i32 - 2;
+                        if note.keydownduration != 0 {
+                            last_duration = note.keydownduration;
+                        }
+                        let key = report.final_octave * 12 + note.note as i32;
+                        if !(0..=127).contains(&key) {
+                            report.keys_out_of_range.push((event_i, key));
+                        }
+                        notes_held.push((key, global_tick, global_tick + last_duration as u128));
+                    },
+                    DSEEvent::Other(other) => match other.code {
+                        0x98 => { // EOT
+                            report.reached_eot = true;
+                            break;
+                        },
+                        0xA0 => report.final_octave = other.parameters[0] as i32, // SetTrackOctave
+                        0xA1 => report.final_octave += other.parameters[0] as i8 as i32, // AddToTrackOctave
+                        0xA9 => report.final_swdl = Some(other.parameters[0]), // SetSwdl
+                        0xAA => report.final_bank = Some(other.parameters[0]), // SetBank
+                        0xAC => { // SetProgram
+                            report.final_program = Some(other.parameters[0]);
+                            report.program_changes.push((global_tick, other.parameters[0]));
+                        },
+                        0xE0 => report.final_volume = Some(other.parameters[0]), // SetTrackVolume
+                        _ => {  }
+                    },
+                    DSEEvent::FixedDurationPause(_) => {  }
+                }
+                if let Some(pause) = event.pause_ticks(last_pause) {
+                    if pause != 0 {
+                        last_pause = pause;
+                    }
+                    global_tick += pause as u128;
+                }
+            }
+            notes_held.retain(|&(_, _, release_tick)| release_tick > global_tick);
+            for &(key, start_tick, release_tick) in &notes_held {
+                let name = u8::try_from(key).ok().filter(|&key| key <= 127).map(midi_note_name).unwrap_or_else(|| format!("key {}", key));
+                debug!("trk{:02}: {} started at tick {} is still held at tick {}, its release would have been at tick {}.", report.trkid, name, start_tick, global_tick, release_tick);
+            }
+            report.end_tick = global_tick;
+            report.notes_held_at_end = notes_held;
+            tracks.push(report);
+        }
+        Ok(PlaybackReport { nbtrks: self.song.nbtrks, nbtrks_matches: self.song.nbtrks as usize == self.trks.objects.len(), tracks })
+    }
     pub fn regenerate_read_markers(&mut self) -> Result<(), DSEError> { //TODO: make more efficient
-        // ======== NUMERICAL VALUES (LENGTHS, SLOTS, etc) ========
-        self.header.flen = self.write_to_file(&mut Cursor::new(&mut Vec::new()))?.try_into().map_err(|_| DSEError::BinaryFileTooLarge(DSEFileType::SMDL))?;
+        self.regenerate_slot_counts()?;
+        self.regenerate_lengths()?;
+        self.regenerate_labels();
+        Ok(())
+    }
+    /// Regenerate only the track and channel counts.
+    pub fn regenerate_slot_counts(&mut self) -> Result<(), DSEError> {
         self.song.nbtrks = self.trks.len() as u8;
         self.song.nbchans = self.trks.objects.iter().map(|x| x.preamble.chanid).max().ok_or(DSEError::Invalid("SMDL file contains zero tracks! Unable to automatically determine number of channels used!!".to_string()))? + 1;
+        Ok(())
+    }
+    /// Regenerate only the file and track chunk lengths.
+    pub fn regenerate_lengths(&mut self) -> Result<(), DSEError> {
+        self.header.flen = self.write_to_file(&mut Cursor::new(&mut Vec::new()))?.try_into().map_err(|_| DSEError::BinaryFileTooLarge(DSEFileType::SMDL))?;
         for trk in self.trks.objects.iter_mut() {
             trk.header.chunklen = trk.preamble.write_to_file(&mut Cursor::new(&mut Vec::new()))? as u32 + trk.events.write_to_file(&mut Cursor::new(&mut Vec::new()))? as u32;
         }
-        // ======== CHUNK LABELS ========
+        Ok(())
+    }
+    /// Regenerate only the magic number and chunk labels.
+    pub fn regenerate_labels(&mut self) {
         self.header.magicn = 0x6C646D73;  //  The 4 characters "smdl" {0x73,0x6D,0x64,0x6C} 
         self.song.label = 0x676E6F73; // Song chunk label "song" {0x73,0x6F,0x6E,0x67}
         for obj in self.trks.objects.iter_mut() {
             obj.header.label = 0x206B7274; // track chunk label "trk\0x20" {0x74,0x72,0x6B,0x20}
         }
         self.eoc.label = 0x20636F65; // the ChunkID -  The chunk ID "eoc\0x20" {0x65, 0x6F, 0x63, 0x20} 
-        Ok(())
     }
 }
 impl ReadWrite for SMDL {
@@ -757,7 +1343,123 @@ impl ReadWrite for SMDL {
         Ok(())
     }
 }
+/// State of a single track at the point where `SMDL::simulate_playback` reached its end
+#[derive(Debug, Clone, Default)]
+pub struct TrackPlaybackReport {
+    pub trkid: u8,
+    /// Tick at which the track ended
+    pub end_tick: u128,
+    /// Whether the track ended on an EndOfTrack event rather than by running out of events
+    pub reached_eot: bool,
+    /// Notes still sounding when the track ended, as (key, tick the note started, tick the note would have been released)
+    pub notes_held_at_end: Vec<(i32, u128, u128)>,
+    /// Every SetProgram event, as (tick, program id)
+    pub program_changes: Vec<(u128, u8)>,
+    /// Notes whose key lies outside of 0-127, as (event index, key)
+    pub keys_out_of_range: Vec<(usize, i32)>,
+    pub final_swdl: Option<u8>,
+    pub final_bank: Option<u8>,
+    pub final_program: Option<u8>,
+    pub final_octave: i32,
+    pub final_volume: Option<u8>
+}
+/// Result of `SMDL::simulate_playback`
+#[derive(Debug, Clone, Default)]
+pub struct PlaybackReport {
+    pub tracks: Vec<TrackPlaybackReport>,
+    /// The track count declared in the song chunk
+    pub nbtrks: u8,
+    /// Whether `nbtrks` matches the number of tracks actually present
+    pub nbtrks_matches: bool
+}
+impl PlaybackReport {
+    /// Whether every track ended on an EndOfTrack with no notes held and no keys out of range, and the track count is correct
+    pub fn is_clean(&self) -> bool {
+        self.nbtrks_matches && self.tracks.iter().all(|trk| trk.reached_eot && trk.notes_held_at_end.is_empty() && trk.keys_out_of_range.is_empty())
+    }
+    /// Program changes to programs that don't exist in `swdl`, as (trkid, tick, program id)
+    pub fn undefined_programs(&self, swdl: &SWDL) -> Vec<(u8, u128, u8)> {
+        let defined: BTreeSet<u16> = swdl.prgi.as_ref().map(|prgi| prgi.data.objects.iter().map(|program| program.header.id).collect()).unwrap_or_default();
+        self.tracks.iter().flat_map(|trk| trk.program_changes.iter()
+            .filter(|(_, program)| !defined.contains(&(*program as u16)))
+            .map(move |&(tick, program)| (trk.trkid, tick, program))).collect()
+    }
+}
+/// Summary of a single track, as produced by `SMDL::quick_scan`
+#[derive(Debug, Clone, Default)]
+pub struct TrackSummary {
+    pub trkid: u8,
+    pub chanid: u8,
+    /// Program ids set by SetProgram events, in the order they appear
+    pub programs: Vec<u8>,
+    /// Tempos set by SetTempo events, in the order they appear
+    pub tempos: Vec<u8>,
+    pub nnotes: usize
+}
+/// Summary of a song, as produced by `SMDL::quick_scan`
+#[derive(Debug, Clone, Default)]
+pub struct SongSummary {
+    pub fname: String,
+    pub link_bytes: (u8, u8),
+    pub tpqn: u16,
+    pub tracks: Vec<TrackSummary>
+}
+impl SongSummary {
+    /// All programs used anywhere in the song
+    pub fn programs_used(&self) -> BTreeSet<u8> {
+        self.tracks.iter().flat_map(|trk| trk.programs.iter().copied()).collect()
+    }
+}
 impl SMDL {
+    /// Read the headers of an SMD file and scan its tracks for the programs and tempos used, without building the full event list of each track.
+    /// 
+    /// Note data is skipped over, so this is much cheaper than `SMDL::load` when cataloging many songs.
+    pub fn quick_scan<R: Read + Seek>(reader: &mut R) -> Result<SongSummary, DSEError> {
+        let mut header = SMDLHeader::default();
+        header.read_from_file(reader)?;
+        let mut song = SongChunk::default();
+        song.read_from_file(reader)?;
+        let mut summary = SongSummary {
+            fname: header.fname.to_string(),
+            link_bytes: (header.unk1, header.unk2),
+            tpqn: song.tpqn,
+            tracks: Vec::with_capacity(song.nbtrks as usize)
+        };
+        for _ in 0..song.nbtrks {
+            let mut trk_header = TrkChunkHeader::default();
+            trk_header.read_from_file(reader)?;
+            let events_end = reader.seek(SeekFrom::Current(0))? + trk_header.chunklen as u64;
+            let mut preamble = TrkChunkPreamble::default();
+            preamble.read_from_file(reader)?;
+            let mut trk_summary = TrackSummary { trkid: preamble.trkid, chanid: preamble.chanid, ..Default::default() };
+            while reader.seek(SeekFrom::Current(0))? < events_end {
+                match reader.read_u8()? {
+                    0x0..=0x7F => { // PlayNote
+                        let note_data = reader.read_u8()?;
+                        reader.seek(SeekFrom::Current(((note_data & 0b11000000) >> 6) as i64))?;
+                        trk_summary.nnotes += 1;
+                    },
+                    0x80..=0x8F => {  }, // FixedDurationPause
+                    code => {
+                        let (_, &(_, _, nbparams)) = events::Other::lookup(code)?;
+                        let mut parameters = [0_u8; 5];
+                        reader.read_exact(&mut parameters[..nbparams as usize])?;
+                        match code {
+                            0xA4 | 0xA5 => trk_summary.tempos.push(parameters[0]), // SetTempo
+                            0xAC => trk_summary.programs.push(parameters[0]), // SetProgram
+                            _ => {  }
+                        }
+                    }
+                }
+            }
+            reader.seek(SeekFrom::Start(events_end))?;
+            while peek_byte!(reader)? == 0x98 {
+                reader.read_u8()?;
+            }
+            summary.tracks.push(trk_summary);
+        }
+        Ok(summary)
+    }
     pub fn load<R: Read + Seek>(file: &mut R) -> Result<SMDL, DSEError> {
         let mut smdl = SMDL::default();
         smdl.read_from_file(file)?;
@@ -773,10 +1475,10 @@ impl SMDL {
     pub fn load_path<P: AsRef<Path> + Debug>(path: P) -> Result<SMDL, DSEError> {
         let smdl;
         if valid_file_of_type(&path, "smd") {
-            println!("[*] Opening smd {:?}", &path);
+            info!("[*] Opening smd {:?}", &path);
             smdl = SMDL::load(&mut File::open(path)?)?;
         } else if valid_file_of_type(&path, "xml") {
-            println!("[*] Opening smd {:?} (xml)", &path);
+            info!("[*] Opening smd {:?} (xml)", &path);
             smdl = SMDL::load_xml(&mut File::open(path)?)?;
         } else {
             return Err(DSEError::Invalid(format!("File '{:?}' is not an SMD file!", path)));
@@ -801,10 +1503,306 @@ impl SMDL {
     }
 }
 
+/// Count the distinct `unkpad` values found in the song chunks of the given SMDL files
+pub fn survey_unkpad<'a, I: IntoIterator<Item = &'a SMDL>>(smdls: I) -> BTreeMap<[u8; 16], usize> {
+    let mut counts = BTreeMap::new();
+    for smdl in smdls {
+        *counts.entry(smdl.song.unkpad).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Parameter values carried by an opcode across a collection of SMDL files
+#[derive(Debug, Default)]
+pub struct OpcodeSurvey {
+    pub occurrences: usize,
+    /// Distinct parameter bytes and how many times each was seen
+    pub parameters: BTreeMap<Vec<u8>, usize>,
+    /// First byte of the event directly following the opcode and how many times each was seen. Note velocities (0x00-0x7F) or opcodes that rarely appear otherwise are a hint that the parameter count in the opcode table is wrong and the parser has desynced.
+    pub followed_by: BTreeMap<u8, usize>
+}
+/// Survey the parameters of an opcode across SMDL files. Intended for the opcodes whose meaning is still unknown, like 0xD8, or uncertain, like 0xF6.
+pub fn survey_opcode_parameters<'a, I: IntoIterator<Item = &'a SMDL>>(smdls: I, code: u8) -> Result<OpcodeSurvey, DSEError> {
+    let (_, &(_, _, nbparams)) = events::Other::lookup(code)?;
+    let mut survey = OpcodeSurvey::default();
+    for smdl in smdls {
+        for trk in &smdl.trks.objects {
+            let mut events = trk.events.events.iter().peekable();
+            while let Some(event) = events.next() {
+                if let DSEEvent::Other(other) = event {
+                    if other.code == code {
+                        survey.occurrences += 1;
+                        *survey.parameters.entry(other.parameters[..nbparams as usize].to_vec()).or_insert(0) += 1;
+                        if let Some(next) = events.peek() {
+                            let next_byte = match next {
+                                DSEEvent::PlayNote(play_note) => play_note.velocity,
+                                DSEEvent::FixedDurationPause(pause) => pause.code(),
+                                DSEEvent::Other(other) => other.code
+                            };
+                            *survey.followed_by.entry(next_byte).or_insert(0) += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(survey)
+}
+
+/// A single note for `SMDL::from_note_list`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimpleNote {
+    /// Track the note goes on. Track 0 is the meta track and can't hold notes.
+    pub track: u8,
+    /// Channel of the track. Every note on the same track must use the same channel.
+    pub channel: u8,
+    /// Program the note is played with
+    pub program: u8,
+    pub start_tick: u128,
+    pub duration: u32,
+    /// MIDI key of the note
+    pub key: u8,
+    pub velocity: u8
+}
+impl SMDL {
+    /// Build a song straight from a list of notes, in any order. The meta track (trk 0) only holds the tempo, and every track the notes refer to is created with the program changes and octave changes its notes need. All tracks are padded to end together.
+    pub fn from_note_list(notes: &[SimpleNote], tpqn: u16, tempo: u8) -> Result<SMDL, DSEError> {
+        if tpqn == 0 {
+            return Err(DSEError::Invalid("tpqn must be greater than zero!".to_string()));
+        }
+        let mut tracks: BTreeMap<u8, Vec<&SimpleNote>> = BTreeMap::new();
+        for note in notes {
+            if note.track == 0 {
+                return Err(DSEError::Invalid(format!("Note {:?} is on the meta track! Notes must go on tracks 1 and above.", note)));
+            }
+            if note.channel > 0x0F || note.key > 127 || note.velocity > 127 || note.duration == 0 {
+                return Err(DSEError::Invalid(format!("Note {:?} is out of range! The channel must be within 0-15, the key and velocity within 0-127, and the duration must be above zero.", note)));
+            }
+            tracks.entry(note.track).or_default().push(note);
+        }
+
+        let mut smdl = create_smdl_shell((2008, 11, 16, 13, 40, 57, 3), "notes.smd".to_string())?;
+        smdl.song.tpqn = tpqn;
+        let song_end = notes.iter().map(|note| note.start_tick + note.duration as u128).max().unwrap_or(0);
+
+        let mut meta_trk = midi::TrkChunkWriter::create(0, 0, smdl.get_link_bytes())?;
+        meta_trk.add_other_with_params_u8("SetTempo", tempo)?;
+        meta_trk.fix_current_global_tick(song_end)?;
+        let mut trks = vec![meta_trk.close_track()];
+        for (trkid, mut trk_notes) in tracks {
+            let chanid = trk_notes[0].channel;
+            if let Some(note) = trk_notes.iter().find(|note| note.channel != chanid) {
+                return Err(DSEError::Invalid(format!("Note {:?} is on channel {}, but other notes on trk{:02} are on channel {}!", note, note.channel, trkid, chanid)));
+            }
+            trk_notes.sort_by_key(|note| note.start_tick);
+            // (tick, is_note_on, note), with note offs sorting before note ons on the same tick
+            let mut messages: Vec<(u128, bool, &SimpleNote)> = Vec::with_capacity(trk_notes.len() * 2);
+            for note in trk_notes {
+                messages.push((note.start_tick, true, note));
+                messages.push((note.start_tick + note.duration as u128, false, note));
+            }
+            messages.sort_by_key(|&(tick, is_note_on, _)| (tick, is_note_on));
+
+            let mut trk = midi::TrkChunkWriter::create(trkid, chanid, smdl.get_link_bytes())?;
+            let mut program = None;
+            for (tick, is_note_on, note) in messages {
+                trk.fix_current_global_tick(tick)?;
+                if is_note_on {
+                    if program != Some(note.program) {
+                        trk.add_other_with_params_u8("SetProgram", note.program)?;
+                        program = Some(note.program);
+                    }
+                    trk.note_on(note.key, note.velocity)?;
+                } else {
+                    trk.note_off(note.key)?;
+                }
+            }
+            trk.fix_current_global_tick(song_end)?;
+            trks.push(trk.close_track());
+        }
+
+        smdl.trks.objects = trks;
+        smdl.regenerate_read_markers()?;
+        Ok(smdl)
+    }
+}
 // Setup empty smdl object
 pub fn create_smdl_shell(last_modified: (u16, u8, u8, u8, u8, u8, u8), mut fname: String) -> Result<SMDL, DSEError> {
     let mut smdl = SMDL::default();
     smdl.set_metadata(last_modified, fname)?;
     Ok(smdl)
 }
+/// Create a metronome song that plays a short middle C on every beat for `beats` beats, using program `click_program`. Useful as a test fixture for tempo handling, or for checking a bank's percussion sample.
+/// 
+/// The song has the usual meta track (track 0) holding the tempo, plus a single track with the clicks.
+pub fn create_metronome_smdl(bpm: u8, beats: u32, tpqn: u16, click_program: u8) -> Result<SMDL, DSEError> {
+    if tpqn == 0 {
+        return Err(DSEError::Invalid("Metronome tpqn must be greater than zero!".to_string()));
+    }
+    let mut smdl = create_smdl_shell((2008, 11, 16, 13, 40, 57, 3), "metronome.smd".to_string())?;
+    smdl.song.tpqn = tpqn;
+
+    let mut meta_trk = midi::TrkChunkWriter::create(0, 0, smdl.get_link_bytes())?;
+    meta_trk.add_other_with_params_u8("SetTempo", bpm)?;
+    let mut click_trk = midi::TrkChunkWriter::create(1, 0, smdl.get_link_bytes())?;
+    click_trk.add_other_with_params_u8("SetProgram", click_program)?;
+    for beat in 0..beats as u128 {
+        click_trk.fix_current_global_tick(beat * tpqn as u128)?;
+        click_trk.note_on(60, 127)?;
+        click_trk.fix_current_global_tick(beat * tpqn as u128 + (tpqn as u128 / 2).max(1))?;
+        click_trk.note_off(60)?;
+    }
+    let song_end = beats as u128 * tpqn as u128;
+    meta_trk.fix_current_global_tick(song_end)?;
+    click_trk.fix_current_global_tick(song_end)?;
+
+    smdl.trks.objects = vec![meta_trk.close_track(), click_trk.close_track()];
+    smdl.regenerate_read_markers()?;
+    Ok(smdl)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A track with a SetTempo(120) and an end of track, 7 bytes long counting the preamble, so the chunk ends 3 bytes past a 4 byte boundary
+    fn unaligned_track_bytes(padding: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![
+            0x74, 0x72, 0x6B, 0x20, // "trk "
+            0x00, 0x00, 0x00, 0x01,
+            0x04, 0xFF, 0x00, 0x00,
+            0x07, 0x00, 0x00, 0x00, // chunklen
+            0x00, 0x00, 0x00, 0x00, // Preamble
+            0xA4, 0x78, // SetTempo(120)
+            0x98 // EndOfTrack
+        ];
+        bytes.extend_from_slice(padding);
+        bytes
+    }
+
+    fn round_trip_track(bytes: &[u8]) -> Vec<u8> {
+        let mut file = bytes.to_vec();
+        file.extend_from_slice(b"eoc "); // Something that isn't padding after the track
+        let mut trk = TrkChunk::default();
+        trk.read_from_file(&mut Cursor::new(file)).unwrap();
+        let mut written = Cursor::new(Vec::new());
+        trk.write_to_file(&mut written).unwrap();
+        written.into_inner()
+    }
+
+    #[test]
+    fn strict_mode_preserves_unaligned_track_padding() {
+        set_strict_mode(true);
+        for padding in [&[][..], &[0x98][..], &[0x98; 5][..]] {
+            let bytes = unaligned_track_bytes(padding);
+            assert_eq!(round_trip_track(&bytes), bytes);
+        }
+        set_strict_mode(false);
+        assert_eq!(round_trip_track(&unaligned_track_bytes(&[])), unaligned_track_bytes(&[0x98]));
+    }
+
+    #[test]
+    fn strict_mode_rejects_events_after_end_of_track() {
+        let mut bytes = unaligned_track_bytes(&[0xA4, 0x78, 0x98]); // Another SetTempo(120) and EndOfTrack
+        bytes[12] = 0x0A; // Count them in the chunklen
+        bytes.extend_from_slice(&[0x98, 0x98]); // Padding
+        bytes.extend_from_slice(b"eoc ");
+        let read_track = || TrkChunk::default().read_from_file(&mut Cursor::new(&bytes));
+
+        set_strict_mode(true);
+        let result = read_track();
+        set_strict_mode(false);
+        assert!(matches!(result, Err(DSEError::DSEUnexpectedTrailingEventBytes(_, 3, _))), "{:?}", result);
+        assert!(read_track().is_ok());
+    }
+
+    fn test_track(trkid: u8, chanid: u8, events: Vec<DSEEvent>) -> TrkChunk {
+        let mut trk = TrkChunk::default();
+        trk.preamble.trkid = trkid;
+        trk.preamble.chanid = chanid;
+        trk.events.events = events;
+        trk
+    }
+    fn test_note(note: u8, keydownduration: u32) -> DSEEvent {
+        let mut play_note = events::PlayNote::default();
+        play_note.velocity = 100;
+        play_note.octavemod = 2;
+        play_note.note = note;
+        play_note.keydownduration = keydownduration;
+        DSEEvent::PlayNote(play_note)
+    }
+
+    #[test]
+    fn split_tracks_by_channel_keeps_event_order() {
+        let pause = |ticks: u128| create_pause_events(ticks).unwrap();
+        let mut smdl = SMDL::default();
+        smdl.trks.objects.push(test_track(0, 0, pause(96)));
+        smdl.trks.objects.push(test_track(1, 3, [vec![test_note(0, 24)], pause(48)].concat()));
+        smdl.trks.objects.push(test_track(2, 5, vec![test_note(2, 12)]));
+        smdl.trks.objects.push(test_track(3, 3, vec![test_note(7, 24)]));
+
+        let event_bytes = |events: &[DSEEvent]| {
+            let mut cursor = Cursor::new(Vec::new());
+            for event in events {
+                event.write_to_file(&mut cursor).unwrap();
+            }
+            cursor.into_inner()
+        };
+        let channels = smdl.split_tracks_by_channel();
+        assert_eq!(channels.iter().map(|(chanid, _)| *chanid).collect::<Vec<_>>(), vec![0, 3, 5]);
+        let expected = [
+            pause(96),
+            [vec![test_note(0, 24)], pause(48), vec![test_note(7, 24)]].concat(),
+            vec![test_note(2, 12)]
+        ];
+        for ((_, events), expected) in channels.iter().zip(expected.iter()) {
+            assert_eq!(events.len(), expected.len());
+            assert_eq!(event_bytes(events), event_bytes(expected));
+        }
+    }
+
+    #[test]
+    fn merge_tracks_by_channel_interleaves_by_tick() {
+        let pause = |ticks: u128| create_pause_events(ticks).unwrap();
+        let mut smdl = SMDL::default();
+        smdl.trks.objects.push(test_track(0, 0, pause(96)));
+        smdl.trks.objects.push(test_track(1, 3, [vec![test_note(0, 24)], pause(48), vec![test_note(4, 24)]].concat()));
+        smdl.trks.objects.push(test_track(2, 3, [pause(24), vec![test_note(7, 24)], pause(72)].concat()));
+        smdl.trks.objects.push(test_track(3, 5, vec![test_note(2, 12)]));
+
+        let channels = smdl.merge_tracks_by_channel().unwrap();
+        assert_eq!(channels.iter().map(|(chanid, _)| *chanid).collect::<Vec<_>>(), vec![3, 5]);
+
+        let merged = test_track(1, 3, channels[0].1.clone());
+        let notes: Vec<(u128, u8)> = merged.absolute_events().into_iter().filter_map(|(tick, event)| match event {
+            AbsoluteEvent::Note { key, .. } => Some((tick, key)),
+            AbsoluteEvent::Other(_) => None
+        }).collect();
+        assert_eq!(notes, vec![(0, 48), (24, 55), (48, 52)]);
+        assert_eq!(merged.total_ticks(), 96);
+        assert!(merged.events.events.last().unwrap().is_eot_event());
+    }
 
+    #[test]
+    fn pad_tracks_to_longest_pads_before_end_of_track() {
+        let pause = |ticks: u128| create_pause_events(ticks).unwrap();
+        let mut eot_event = events::Other::default();
+        eot_event.code = events::Other::name_to_code("EndOfTrack").unwrap();
+        let eot = DSEEvent::Other(eot_event);
+        let mut smdl = SMDL::default();
+        smdl.trks.objects.push(test_track(0, 0, vec![eot.clone()]));
+        smdl.trks.objects.push(test_track(1, 0, [vec![test_note(0, 24)], pause(300), vec![eot.clone()]].concat()));
+        smdl.trks.objects.push(test_track(2, 1, vec![test_note(4, 24)]));
+
+        smdl.pad_tracks_to_longest().unwrap();
+
+        for trk in &smdl.trks.objects {
+            assert_eq!(trk.total_ticks(), 300);
+        }
+        assert!(smdl.trks.objects[0].events.events.last().unwrap().is_eot_event());
+        assert!(smdl.trks.objects[1].events.events.last().unwrap().is_eot_event());
+        assert_eq!(smdl.trks.objects[1].events.events.len(), 3); // Already the longest, so left alone
+        assert!(matches!(smdl.trks.objects[2].events.events[0], DSEEvent::PlayNote(_)));
+    }
+}
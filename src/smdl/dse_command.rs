@@ -0,0 +1,245 @@
+use std::io::Write;
+
+use byteorder::{WriteBytesExt, LittleEndian, BigEndian};
+use log::debug;
+
+use crate::dtype::DSEError;
+
+use super::events::Other;
+
+/// Parse a single DSE command such as `SetTrackVolume(100)` or `PitchBend(50_i16le)` into an event.
+/// 
+/// Untyped arguments are parsed as `i8` or `u8`, in decimal or hex. Typed arguments take the form `value_type`, where the type is one of `i8`, `u8`, or a wider integer type with an endianness suffix, like `u16le` or `i32be`. The total size of the arguments must match the number of parameter bytes the event takes.
+pub fn parse_dsec_command(cmd: &str) -> Result<Other, DSEError> {
+    let cmd = cmd.trim();
+    let name;
+    let mut arguments_bytes: Vec<u8> = Vec::new();
+
+    if let Some(left_paren_index) = cmd.chars().position(|c| c == '(') {
+        name = cmd[..left_paren_index].trim_end();
+
+        // Parse arguments
+        let mut arguments_str = cmd[(left_paren_index+1)..].trim_start();
+        if arguments_str.len() == 0 {
+            return Err(DSEError::InvalidDSECommand(cmd.to_string(), "Opening parentheses must be closed!!".to_string()));
+        } else {
+            if arguments_str.chars().last().unwrap() == ')' {
+                arguments_str = arguments_str[..(arguments_str.len()-1)].trim_end();
+            } else {
+                return Err(DSEError::InvalidDSECommand(cmd.to_string(), "Opening parentheses must be closed!!".to_string()));
+            }
+        }
+        for arg in arguments_str.split(",").map(|x| x.trim().to_lowercase()) {
+            let mut added_argument_bytes: Vec<u8> = Vec::new();
+
+            let typed: Vec<&str> = arg.split("_").map(|x| x.trim()).collect();
+
+            if arg == "" {
+                // Skip
+            }
+
+            else if typed.len() == 2 {
+                // Typed
+                match typed[1] {
+                    "i8" => added_argument_bytes.write_i8(
+                        typed[0].parse::<i8>()
+                            .map_or_else(|_| i8::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "u8" => added_argument_bytes.write_u8(
+                        typed[0].parse::<u8>()
+                            .map_or_else(|_| u8::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+
+                    "i16le" => added_argument_bytes.write_i16::<LittleEndian>(
+                        typed[0].parse::<i16>()
+                            .map_or_else(|_| i16::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "u16le" => added_argument_bytes.write_u16::<LittleEndian>(
+                        typed[0].parse::<u16>()
+                            .map_or_else(|_| u16::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "i32le" => added_argument_bytes.write_i32::<LittleEndian>(
+                        typed[0].parse::<i32>()
+                            .map_or_else(|_| i32::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "u32le" => added_argument_bytes.write_u32::<LittleEndian>(
+                        typed[0].parse::<u32>()
+                            .map_or_else(|_| u32::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "i64le" => added_argument_bytes.write_i64::<LittleEndian>(
+                        typed[0].parse::<i64>()
+                            .map_or_else(|_| i64::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "u64le" => added_argument_bytes.write_u64::<LittleEndian>(
+                        typed[0].parse::<u64>()
+                            .map_or_else(|_| u64::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "i128le" => added_argument_bytes.write_i128::<LittleEndian>(
+                        typed[0].parse::<i128>()
+                            .map_or_else(|_| i128::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "u128le" => added_argument_bytes.write_u128::<LittleEndian>(
+                        typed[0].parse::<u128>()
+                            .map_or_else(|_| u128::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+
+                    "i16be" => added_argument_bytes.write_i16::<BigEndian>(
+                        typed[0].parse::<i16>()
+                            .map_or_else(|_| i16::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "u16be" => added_argument_bytes.write_u16::<BigEndian>(
+                        typed[0].parse::<u16>()
+                            .map_or_else(|_| u16::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "i32be" => added_argument_bytes.write_i32::<BigEndian>(
+                        typed[0].parse::<i32>()
+                            .map_or_else(|_| i32::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "u32be" => added_argument_bytes.write_u32::<BigEndian>(
+                        typed[0].parse::<u32>()
+                            .map_or_else(|_| u32::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "i64be" => added_argument_bytes.write_i64::<BigEndian>(
+                        typed[0].parse::<i64>()
+                            .map_or_else(|_| i64::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "u64be" => added_argument_bytes.write_u64::<BigEndian>(
+                        typed[0].parse::<u64>()
+                            .map_or_else(|_| u64::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "i128be" => added_argument_bytes.write_i128::<BigEndian>(
+                        typed[0].parse::<i128>()
+                            .map_or_else(|_| i128::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+                    "u128be" => added_argument_bytes.write_u128::<BigEndian>(
+                        typed[0].parse::<u128>()
+                            .map_or_else(|_| u128::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
+                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
+                    ),
+
+                    _ => {
+                        return Err(DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))
+                    }
+                }?;
+            }
+            
+            else if let Ok(val) = arg.parse::<i8>() {
+                added_argument_bytes.write_i8(val)?;
+            } else if let Ok(val) = i8::from_str_radix(&arg.trim_start_matches("0x"), 16) {
+                added_argument_bytes.write_i8(val)?;
+            }
+            
+            else if let Ok(val) = arg.parse::<u8>() {
+                added_argument_bytes.write_u8(val)?;
+            } else if let Ok(val) = u8::from_str_radix(&arg.trim_start_matches("0x"), 16) {
+                added_argument_bytes.write_u8(val)?;
+            }
+
+            else {
+                return Err(DSEError::InvalidDSECommand(cmd.to_string(), format!("Value '{}' could not be parsed!", arg)));
+            }
+
+            arguments_bytes.extend(added_argument_bytes);
+        }
+    } else {
+        name = cmd;
+    }
+
+    let mut evt = Other::default();
+    evt.code = Other::name_to_code(name)?;
+
+    // Check if the appropriate number of arguments were passed
+    let (canonical_name, (_, _, num_bytes_taken)) = Other::lookup(evt.code)?;
+    if arguments_bytes.len() != *num_bytes_taken as usize {
+        return Err(DSEError::InvalidDSECommandArguments(cmd.to_string(), arguments_bytes.len(), canonical_name.to_string(), *num_bytes_taken as usize))
+    }
+
+    (&mut evt.parameters[..]).write_all(&arguments_bytes)?;
+    Ok(evt)
+}
+/// Parse a semicolon-separated list of DSE commands, the same syntax used by `dsec` MIDI markers (without the `dsec` prefix).
+/// 
+/// Returns each parsed event along with the index of the track it should go on. Commands go on `track_i` until a `trk n` or `evttrk` command switches tracks.
+pub fn parse_dsec_script(script: &str, mut track_i: usize) -> Result<Vec<(usize, Other)>, DSEError> {
+    let mut parsed = Vec::new();
+    for cmd in script.trim_start().split(";") {
+        let cmd = cmd.trim();
+
+        debug!("{}", cmd);
+
+        if cmd.starts_with("trk") {
+            let new_track_n = cmd[3..].trim_start().parse::<usize>()
+                .map_err(|_| DSEError::InvalidDSECommandFailedToParseTrkChange(cmd.to_string()))?;
+            track_i = new_track_n;
+            continue;
+        } else if cmd.starts_with("evttrk") {
+            track_i = 0;
+            continue;
+        }
+
+        parsed.push((track_i, parse_dsec_command(cmd)?));
+    }
+    Ok(parsed)
+}
+/// Parse a `dsec` MIDI marker, a `dsec` prefix followed by a script of DSE commands (see `parse_dsec_script`). Commands start out on the meta track (index 0).
+pub fn parse_dse_command(marker: &str) -> Result<Vec<(usize, Other)>, DSEError> {
+    let marker = marker.trim();
+    let script = marker.strip_prefix("dsec").ok_or(DSEError::InvalidDSECommand(marker.to_string(), "DSE command markers must start with 'dsec'!".to_string()))?;
+    parse_dsec_script(script, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_arguments() {
+        let evt = parse_dsec_command("PitchBend(50_i16le)").unwrap();
+        assert_eq!(evt.code, Other::name_to_code("PitchBend").unwrap());
+        assert_eq!(evt.parameters, [50, 0, 0, 0, 0]);
+        let evt = parse_dsec_command("PitchBend(-2_i16be)").unwrap();
+        assert_eq!(evt.parameters, [0xFF, 0xFE, 0, 0, 0]);
+    }
+    #[test]
+    fn hex_arguments() {
+        let evt = parse_dsec_command("SetTrackVolume(0xFF_u8)").unwrap();
+        assert_eq!(evt.parameters, [0xFF, 0, 0, 0, 0]);
+        let evt = parse_dsec_command("SetTrackVolume(0x7F)").unwrap();
+        assert_eq!(evt.parameters, [0x7F, 0, 0, 0, 0]);
+    }
+    #[test]
+    fn arity_is_validated() {
+        assert!(matches!(parse_dsec_command("SetTrackVolume(1, 2)"), Err(DSEError::InvalidDSECommandArguments(..))));
+        assert!(matches!(parse_dsec_command("PitchBend(1)"), Err(DSEError::InvalidDSECommandArguments(..))));
+        assert!(matches!(parse_dsec_command("SetTrackVolume"), Err(DSEError::InvalidDSECommandArguments(..))));
+        assert!(parse_dsec_command("SetTrackVolume(100").is_err());
+    }
+    #[test]
+    fn script_track_changes() {
+        let parsed = parse_dsec_script("SetTrackVolume(100); trk 2; SetTrackPan(64); evttrk; SetTempo(120)", 1).unwrap();
+        assert_eq!(parsed.iter().map(|(track_i, _)| *track_i).collect::<Vec<_>>(), vec![1, 2, 0]);
+    }
+    #[test]
+    fn marker_starts_on_the_meta_track() {
+        let parsed = parse_dse_command("dsec SetTrackVolume(100); trk 3; SetTrackPan(64)").unwrap();
+        assert_eq!(parsed.iter().map(|(track_i, evt)| (*track_i, evt.parameters[0])).collect::<Vec<_>>(), vec![(0, 100), (3, 64)]);
+        assert!(parse_dse_command("SetTrackVolume(100)").is_err());
+    }
+}
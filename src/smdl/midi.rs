@@ -1,12 +1,14 @@
-use std::{borrow::Cow, collections::{HashMap, BTreeSet, BTreeMap}, u8, hash::Hash, io::Write, rc::Rc, cell::RefCell};
+use std::{borrow::Cow, collections::{HashMap, BTreeSet, BTreeMap}, u8, hash::Hash, rc::Rc, cell::RefCell};
 
 use byteorder::{WriteBytesExt, LittleEndian, BigEndian, ByteOrder};
-use colored::Colorize;
-use midly::{Smf, TrackEvent, num::{u4, u28, u24}};
+use log::{debug, warn};
+use midly::{Smf, TrackEvent, num::{u4, u7, u15, u24, u28}};
 
 use crate::dtype::DSEError;
+use crate::swdl::ADSRVolumeEnvelope;
 
-use super::{TrkChunk, events::{PlayNote, Other, FixedDurationPause}, DSEEvent};
+use super::{SMDL, TrkChunk, events::{PlayNote, Other, FixedDurationPause}, DSEEvent};
+pub use super::dse_command::{parse_dsec_command, parse_dsec_script, parse_dse_command};
 
 // Open input MIDI file
 pub fn open_midi<'a>(smf_source: &'a Vec<u8>) -> Result<Smf<'a>, DSEError> {
@@ -24,14 +26,14 @@ pub fn get_midi_messages_flattened<'a>(smf: &'a Smf) -> Result<Cow<'a, [TrackEve
     match smf.header.format {
         midly::Format::SingleTrack => { Ok(Cow::from(&smf.tracks[0])) },
         midly::Format::Parallel => {
-            println!("{}SMF1-type MIDI file detected! All MIDI tracks contained within will be mapped to MIDI channels and converted to SMF0!", "Warning: ".yellow());
-            println!("{}This converter assumes that the first MIDI track encountered is dedicated solely for Meta events to follow convention.", "Warning: ".yellow());
+            warn!("SMF1-type MIDI file detected! All MIDI tracks contained within will be mapped to MIDI channels and converted to SMF0!");
+            warn!("This converter assumes that the first MIDI track encountered is dedicated solely for Meta events to follow convention.");
             let mut first_track_is_meta: bool = true;
             for midi_msg in &smf.tracks[0] {
                 match midi_msg.kind {
                     midly::TrackEventKind::Midi { channel: _, message: _ } => {
                         // Track does not follow convention!
-                        println!("{}SMF1 multi-track MIDI file contains note events in the first track! The first track is usually reserved only for meta events. It will be assumed that this MIDI file does not follow that convention.", "Warning: ".yellow());
+                        warn!("SMF1 multi-track MIDI file contains note events in the first track! The first track is usually reserved only for meta events. It will be assumed that this MIDI file does not follow that convention.");
                         first_track_is_meta = false;
                         break;
                     },
@@ -74,13 +76,262 @@ pub fn get_midi_messages_flattened<'a>(smf: &'a Smf) -> Result<Cow<'a, [TrackEve
         },
     }
 }
+/// Flatten a MIDI file into a single-track SMF0 `Smf` using the same channel mapping as the importer, so that it can be saved and inspected.
+pub fn flatten_to_smf0(smf: &Smf) -> Result<Smf<'static>, DSEError> {
+    let midi_messages = get_midi_messages_flattened(smf)?;
+    let mut track: Vec<TrackEvent<'static>> = Vec::with_capacity(midi_messages.len() + 1);
+    let mut carried_delta: u32 = 0;
+    for midi_msg in midi_messages.iter() {
+        // Every SMF1 track brings its own EndOfTrack, only the final one should remain
+        if let midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack) = midi_msg.kind {
+            carried_delta += midi_msg.delta.as_int();
+            continue;
+        }
+        let mut midi_msg = midi_msg.to_static();
+        midi_msg.delta = u28::try_from(midi_msg.delta.as_int() + carried_delta).ok_or(DSEError::DSESmf0MessagesTooFarApart())?;
+        carried_delta = 0;
+        track.push(midi_msg);
+    }
+    track.push(TrackEvent {
+        delta: u28::try_from(carried_delta).ok_or(DSEError::DSESmf0MessagesTooFarApart())?,
+        kind: midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack)
+    });
+    let mut smf0 = Smf::new(midly::Header::new(midly::Format::SingleTrack, smf.header.timing));
+    smf0.tracks.push(track);
+    Ok(smf0)
+}
+
+impl SMDL {
+    /// Convert the song back into a multitrack SMF1 `Smf`, the reverse of `copy_midi_messages`.
+    /// 
+    /// Each DSE track becomes one MIDI track on the channel given by its `chanid`. Notes, tempo, program changes, volume, pan, expression and pitch bend are converted, and `LoopPoint` becomes a `loopStart` marker. All other events are dropped.
+    pub fn export_midi(&self) -> Result<Smf<'static>, DSEError> {
+        let mut smf = Smf::new(midly::Header::new(midly::Format::Parallel, midly::Timing::Metrical(u15::new(self.song.tpqn & 0x7FFF))));
+        for trk in &self.trks.objects {
+            let channel = u4::new(trk.preamble.chanid & 0x0F);
+            let midi = |message: midly::MidiMessage| midly::TrackEventKind::Midi { channel, message };
+            let mut midi_messages: Vec<(u128, midly::TrackEventKind<'static>)> = Vec::new();
+            let mut octave: i32 = 4;
+            let mut last_duration: u32 = 0;
+            let mut end_tick: u128 = 0;
+            for (tick, event) in trk.events_with_ticks() {
+                end_tick = end_tick.max(tick);
+                match event {
+                    DSEEvent::PlayNote(note) => {
+                        octave += note.octavemod as i32 - 2;
+                        if note.keydownduration != 0 {
+                            last_duration = note.keydownduration;
+                        }
+                        let key = u7::new((octave * 12 + note.note as i32).clamp(0, 127) as u8);
+                        let note_off_tick = tick + last_duration as u128;
+                        midi_messages.push((tick, midi(midly::MidiMessage::NoteOn { key, vel: u7::new(note.velocity.clamp(1, 127)) })));
+                        midi_messages.push((note_off_tick, midi(midly::MidiMessage::NoteOff { key, vel: u7::new(0) })));
+                        end_tick = end_tick.max(note_off_tick);
+                    },
+                    DSEEvent::Other(other) => match other.code {
+                        0x98 => { // EOT
+                            break;
+                        },
+                        0x99 => { // LoopPoint
+                            midi_messages.push((tick, midly::TrackEventKind::Meta(midly::MetaMessage::Marker(b"loopStart"))));
+                        },
+                        0xA0 => { // SetTrackOctave
+                            octave = other.parameters[0] as i32;
+                        },
+                        0xA1 => { // AddToTrackOctave
+                            octave += other.parameters[0] as i8 as i32;
+                        },
+                        0xA4 | 0xA5 => { // SetTempo, SetTempo2
+                            if other.parameters[0] != 0 {
+                                midi_messages.push((tick, midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(u24::new(60_000_000 / other.parameters[0] as u32)))));
+                            }
+                        },
+                        0xAC => { // SetProgram
+                            midi_messages.push((tick, midi(midly::MidiMessage::ProgramChange { program: u7::new(other.parameters[0] & 0x7F) })));
+                        },
+                        0xD7 => { // PitchBend
+                            let bend = BigEndian::read_i16(&other.parameters[..2]).clamp(-8192, 8191);
+                            midi_messages.push((tick, midi(midly::MidiMessage::PitchBend { bend: midly::PitchBend::from_int(bend) })));
+                        },
+                        0xE0 | 0xE3 | 0xE8 => { // SetTrackVolume, SetTrackExpression, SetTrackPan
+                            let controller = match other.code {
+                                0xE0 => 7,
+                                0xE3 => 11,
+                                _ => 10
+                            };
+                            midi_messages.push((tick, midi(midly::MidiMessage::Controller { controller: u7::new(controller), value: u7::new(other.parameters[0] & 0x7F) })));
+                        },
+                        _ => {  }
+                    },
+                    DSEEvent::FixedDurationPause(_) => {  }
+                }
+            }
+            // Stable sort, so that events on the same tick keep their order in the track
+            midi_messages.sort_by_key(|(tick, _)| *tick);
 
-pub fn copy_midi_messages<'a, MapProgram>(midi_messages: Cow<'a, [TrackEvent<'a>]>, trks: &mut [TrkChunkWriter], mut map_program: MapProgram) -> Result<u128, DSEError>
+            let mut track: Vec<TrackEvent<'static>> = Vec::with_capacity(midi_messages.len() + 1);
+            let mut previous_tick: u128 = 0;
+            for (tick, kind) in midi_messages.into_iter().chain(std::iter::once((end_tick, midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack)))) {
+                let delta = u32::try_from(tick - previous_tick).ok().and_then(u28::try_from).ok_or(DSEError::DSESmf0MessagesTooFarApart())?;
+                track.push(TrackEvent { delta, kind });
+                previous_tick = tick;
+            }
+            smf.tracks.push(track);
+        }
+        Ok(smf)
+    }
+}
+
+/// Names for the values of the meta track's `Signal` event.
+/// 
+/// When playback reaches a `Signal`, its value is handed to the game, whose scripts can wait for a particular value to sync gameplay (cutscene cues, effects) to the music. The sound engine itself attaches no meaning to any value, so which values are used and what they do is entirely up to the game's scripts. Registering the values a project uses lets `Signal(n)` markers refer to them by name, and catches markers with values that were never registered.
+#[derive(Debug, Clone, Default)]
+pub struct SignalRegistry {
+    names: BTreeMap<u8, String>
+}
+impl SignalRegistry {
+    pub fn new() -> SignalRegistry {
+        SignalRegistry::default()
+    }
+    /// Register `value` under `name`. Names are case-insensitive, and neither the value nor the name may already be registered.
+    pub fn register(&mut self, value: u8, name: &str) -> Result<(), DSEError> {
+        let name = name.trim().to_lowercase();
+        if name.is_empty() || name.parse::<u8>().is_ok() {
+            return Err(DSEError::Invalid(format!("Signal name '{}' must not be empty or a number!", name)));
+        }
+        if let Some(existing) = self.names.get(&value) {
+            return Err(DSEError::Invalid(format!("Signal value {} is already registered as '{}'!", value, existing)));
+        }
+        if let Some(existing) = self.value_of(&name) {
+            return Err(DSEError::Invalid(format!("Signal name '{}' is already registered for value {}!", name, existing)));
+        }
+        self.names.insert(value, name);
+        Ok(())
+    }
+    pub fn name_of(&self, value: u8) -> Option<&str> {
+        self.names.get(&value).map(|name| name.as_str())
+    }
+    pub fn value_of(&self, name: &str) -> Option<u8> {
+        let name = name.trim().to_lowercase();
+        self.names.iter().find(|(_, registered)| **registered == name).map(|(&value, _)| value)
+    }
+    /// Resolve the argument of a `Signal(n)` marker, which is either a registered name or a registered value
+    pub fn resolve(&self, arg: &str) -> Result<u8, DSEError> {
+        let arg = arg.trim();
+        let value = match arg.parse::<u8>() {
+            Ok(value) => value,
+            Err(_) => self.value_of(arg).ok_or(DSEError::Invalid(format!("Signal '{}' is not registered!", arg)))?
+        };
+        if !self.names.contains_key(&value) {
+            return Err(DSEError::Invalid(format!("Signal value {} is not registered!", value)));
+        }
+        Ok(value)
+    }
+}
+/// The volume levels of DSE. They stack: a note is scaled by the volume of its track and by the volume of the channel the track plays on, and `SetNoteVolume` scales the notes played after it on its track. How exactly the game combines them is not fully known (see dse_sequence.hpp in ppmdu_2), so the track volume is the safe choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeLevel {
+    /// `SetNoteVolume` (0xBC)
+    Note,
+    /// `SetChanVolume` (0xC3)
+    Channel,
+    /// `SetTrackVolume` (0xE0)
+    Track
+}
+impl VolumeLevel {
+    pub fn event_name(&self) -> &'static str {
+        match self {
+            VolumeLevel::Note => "SetNoteVolume",
+            VolumeLevel::Channel => "SetChanVolume",
+            VolumeLevel::Track => "SetTrackVolume"
+        }
+    }
+}
+/// How the mod wheel (CC01) drives LFO1 when routed to pitch. The parameters of `SetLFO1` are assumed to be the rate (u16), the depth (u16), and the waveform (u8), all little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModulationLfo {
+    pub rate: u16,
+    /// Depth at a CC01 value of 127. Lower values scale it down linearly.
+    pub max_depth: u16,
+    pub waveform: u8
+}
+/// Options for `copy_midi_messages_with_options`. The defaults match `copy_midi_messages`.
+#[derive(Debug, Clone, Default)]
+pub struct MidiCopyOptions {
+    /// Controller number that marks the loop start, in addition to the `loopStart` marker
+    pub loop_start_cc: Option<u8>,
+    /// Controller number that marks the loop end, in addition to the `loopEnd` marker
+    pub loop_end_cc: Option<u8>,
+    /// If set, `Signal(n)` markers may use registered names, and markers with unregistered values are rejected
+    pub signals: Option<SignalRegistry>,
+    /// Controllers to turn into volume events of the given level. CC07 goes to the track volume unless it is routed elsewhere here.
+    pub volume_routes: HashMap<u8, VolumeLevel>,
+    /// Turn polyphonic aftertouch on a held key into `SetNoteVolume`, and channel aftertouch into `SetChanVolume`. This is only an approximation, since DSE note volume applies to the whole track rather than to a single key.
+    pub aftertouch_to_volume: bool,
+    /// If set, CC01 sets up LFO1 with a depth following the mod wheel and routes it to pitch, or unroutes it when the mod wheel is at 0
+    pub modulation_lfo: Option<ModulationLfo>
+}
+/// Reorder the messages falling on the same tick so that the note offs of notes held from an earlier tick come before everything else. Otherwise, a note retriggered on the tick its previous instance ends would be cut off by the note off of the previous instance right after starting. Note offs for notes that start on the same tick keep their place, so zero-length notes still end after they start.
+fn note_offs_first<'a>(midi_messages: &[TrackEvent<'a>]) -> Vec<TrackEvent<'a>> {
+    fn note_off(midi_msg: &TrackEvent) -> Option<(u8, u8)> {
+        match midi_msg.kind {
+            midly::TrackEventKind::Midi { channel, message: midly::MidiMessage::NoteOff { key, .. } } => Some((channel.as_int(), key.as_int())),
+            midly::TrackEventKind::Midi { channel, message: midly::MidiMessage::NoteOn { key, vel } } if vel == 0 => Some((channel.as_int(), key.as_int())),
+            _ => None
+        }
+    }
+    fn note_on(midi_msg: &TrackEvent) -> Option<(u8, u8)> {
+        match midi_msg.kind {
+            midly::TrackEventKind::Midi { channel, message: midly::MidiMessage::NoteOn { key, vel } } if vel > 0 => Some((channel.as_int(), key.as_int())),
+            _ => None
+        }
+    }
+    let mut reordered = Vec::with_capacity(midi_messages.len());
+    // How many instances of each (channel, key) are held going into the current tick
+    let mut held: HashMap<(u8, u8), usize> = HashMap::new();
+    let mut group_start = 0;
+    while group_start < midi_messages.len() {
+        // Every message after the first in a group has a delta of zero
+        let group_end = midi_messages[group_start + 1..].iter().position(|midi_msg| midi_msg.delta.as_int() != 0).map(|x| group_start + 1 + x).unwrap_or(midi_messages.len());
+        let group = &midi_messages[group_start..group_end];
+        let group_delta = group[0].delta;
+        let mut held_before = held.clone();
+        let (offs, rest): (Vec<&TrackEvent>, Vec<&TrackEvent>) = group.iter().partition(|midi_msg| {
+            if let Some(note) = note_off(midi_msg) {
+                if let Some(count) = held_before.get_mut(&note).filter(|count| **count > 0) {
+                    *count -= 1;
+                    return true;
+                }
+            }
+            false
+        });
+        for (i, midi_msg) in offs.into_iter().chain(rest).enumerate() {
+            if let Some(note) = note_on(midi_msg) {
+                *held.entry(note).or_default() += 1;
+            } else if let Some(count) = note_off(midi_msg).and_then(|note| held.get_mut(&note)) {
+                *count = count.saturating_sub(1);
+            }
+            let mut midi_msg = *midi_msg;
+            midi_msg.delta = if i == 0 { group_delta } else { u28::new(0) };
+            reordered.push(midi_msg);
+        }
+        group_start = group_end;
+    }
+    reordered
+}
+pub fn copy_midi_messages<'a, MapProgram>(midi_messages: Cow<'a, [TrackEvent<'a>]>, trks: &mut [TrkChunkWriter], map_program: MapProgram) -> Result<u128, DSEError>
 where
     MapProgram: FnMut(u8, u8, u8, bool, &mut TrkChunkWriter, Rc<RefCell<DSEEvent>>) -> Option<u8> {
+    copy_midi_messages_with_options(midi_messages, trks, map_program, &MidiCopyOptions::default())
+}
+pub fn copy_midi_messages_with_options<'a, MapProgram>(midi_messages: Cow<'a, [TrackEvent<'a>]>, trks: &mut [TrkChunkWriter], mut map_program: MapProgram, options: &MidiCopyOptions) -> Result<u128, DSEError>
+where
+    MapProgram: FnMut(u8, u8, u8, bool, &mut TrkChunkWriter, Rc<RefCell<DSEEvent>>) -> Option<u8> {
+    let midi_messages = note_offs_first(midi_messages.as_ref());
+
     // Loop through all the events
     let mut global_tick = 0;
-    for midi_msg in midi_messages.as_ref() {
+    for midi_msg in midi_messages.iter() {
         let delta = midi_msg.delta.as_int() as u128;
         global_tick += delta;
 
@@ -101,12 +352,49 @@ where
                         trks[channel_i].fix_current_global_tick(global_tick)?;
                         trks[channel_i].note_off(key.as_int())?
                     },
-                    midly::MidiMessage::Aftertouch { key, vel } => { /* Ignore aftertouch events */ },
+                    midly::MidiMessage::Aftertouch { key, vel } => {
+                        if options.aftertouch_to_volume && trks[channel_i].is_note_held(key.as_int()) {
+                            trks[channel_i].fix_current_global_tick(global_tick)?;
+                            trks[channel_i].add_other_with_params_u8("SetNoteVolume", vel.as_int())?;
+                        }
+                    },
+                    midly::MidiMessage::Controller { controller, value: _ } if options.loop_start_cc == Some(controller.as_int()) => {
+                        for trk in trks.iter_mut() {
+                            trk.fix_current_global_tick(global_tick)?;
+                            trk.add_other_no_params("LoopPoint")?;
+                        }
+                    },
+                    midly::MidiMessage::Controller { controller, value: _ } if options.loop_end_cc == Some(controller.as_int()) => {
+                        for trk in trks.iter_mut() {
+                            trk.fix_current_global_tick(global_tick)?;
+                            trk.release_all_held()?; // Reset synth
+                        }
+                        break;
+                    },
+                    midly::MidiMessage::Controller { controller, value } if controller.as_int() == 1 && options.modulation_lfo.is_some() => { // CC01 Modulation Wheel MSB
+                        let modulation_lfo = options.modulation_lfo.unwrap();
+                        trks[channel_i].fix_current_global_tick(global_tick)?;
+                        if value.as_int() == 0 {
+                            trks[channel_i].add_other_with_params_u8("RouteLFO1ToPitch", 0)?;
+                        } else {
+                            let depth = (modulation_lfo.max_depth as u32 * value.as_int() as u32 / 127) as u16;
+                            let mut params = [0_u8; 5];
+                            params[0..2].copy_from_slice(&modulation_lfo.rate.to_le_bytes());
+                            params[2..4].copy_from_slice(&depth.to_le_bytes());
+                            params[4] = modulation_lfo.waveform;
+                            trks[channel_i].add_other_with_params_bytes("SetLFO1", &params)?;
+                            trks[channel_i].add_other_with_params_u8("RouteLFO1ToPitch", 1)?;
+                        }
+                    },
+                    midly::MidiMessage::Controller { controller, value } if options.volume_routes.contains_key(&controller.as_int()) => {
+                        trks[channel_i].fix_current_global_tick(global_tick)?;
+                        trks[channel_i].add_other_with_params_u8(options.volume_routes[&controller.as_int()].event_name(), value.as_int())?;
+                    },
                     midly::MidiMessage::Controller { controller, value } => {
                         trks[channel_i].fix_current_global_tick(global_tick)?;
                         match controller.as_int() {
                             00 => { // CC00 Bank Select MSB
-                                println!("{}", "Processing bank select message.".green());
+                                debug!("Processing bank select message.");
                                 trks[channel_i].bank_select(value.as_int(), false, &mut map_program)?;
                             },
                             07 => { // CC07 Volume MSB
@@ -118,15 +406,29 @@ where
                             11 => { // CC11 Expression MSB
                                 trks[channel_i].add_other_with_params_u8("SetTrackExpression", value.as_int())?;
                             },
+                            120 | 123 => { // CC120 All Sound Off, CC123 All Notes Off
+                                trks[channel_i].release_all_held()?;
+                            },
+                            121 => { // CC121 Reset All Controllers
+                                trks[channel_i].add_other_with_params_u8("SetTrackVolume", 100)?;
+                                trks[channel_i].add_other_with_params_u8("SetTrackPan", 64)?;
+                                trks[channel_i].add_other_with_params_u8("SetTrackExpression", 127)?;
+                                trks[channel_i].add_other_with_params_i16::<BigEndian>("PitchBend", 0)?;
+                            },
                             _ => { /* Ignore the other controllers for now */ }
                         }
                     },
                     midly::MidiMessage::ProgramChange { program } => {
                         trks[channel_i].fix_current_global_tick(global_tick)?;
-                        println!("{}", "Processing program change message.".green());
+                        debug!("Processing program change message.");
                         trks[channel_i].program_change(program.as_int(), false, &mut map_program)?;
                     },
-                    midly::MidiMessage::ChannelAftertouch { vel } => { /* Ignore channel aftertouch events */ },
+                    midly::MidiMessage::ChannelAftertouch { vel } => {
+                        if options.aftertouch_to_volume {
+                            trks[channel_i].fix_current_global_tick(global_tick)?;
+                            trks[channel_i].add_other_with_params_u8("SetChanVolume", vel.as_int())?;
+                        }
+                    },
                     midly::MidiMessage::PitchBend { bend } => {
                         trks[channel_i].fix_current_global_tick(global_tick)?;
                         trks[channel_i].add_other_with_params_i16::<BigEndian>("PitchBend", bend.as_int())?;
@@ -153,195 +455,23 @@ where
                             } else if marker.trim().to_lowercase() == "loopend" {
                                 for trk in trks.iter_mut() {
                                     trk.fix_current_global_tick(global_tick)?;
-                                    for val in 0..u8::MAX { // Reset synth
-                                        trk.note_off(val)?;
-                                    }
+                                    trk.release_all_held()?; // Reset synth
                                 }
                                 break;
                             } else if marker.trim().to_lowercase() == "loopendnoreset" {
                                 break;
                             } else if marker.trim().to_lowercase().starts_with("signal") {
                                 let cmd = marker.trim().to_lowercase();
-                                let signal_val: u8 = cmd[6..].replace("(", "").replace(")", "").trim().parse::<u8>().map_err(|_| DSEError::Invalid("MIDI Marker 'Signal(n)' must have a uint8 as its parameter!".to_string()))?;
+                                let arg = cmd[6..].replace("(", "").replace(")", "");
+                                let signal_val: u8 = if let Some(signals) = &options.signals {
+                                    signals.resolve(&arg)?
+                                } else {
+                                    arg.trim().parse::<u8>().map_err(|_| DSEError::Invalid("MIDI Marker 'Signal(n)' must have a uint8 as its parameter!".to_string()))?
+                                };
                                 trks[0].fix_current_global_tick(global_tick)?;
-                                trks[0].add_other_with_params_u8("Signal", signal_val)?;
+                                trks[0].add_signal(signal_val)?;
                             } else if marker.trim().starts_with("dsec") {
-                                let mut track_i = 0;
-                                for cmd in marker.trim()[4..].trim_start().split(";") {
-                                    let cmd = cmd.trim();
-
-                                    println!("{}", cmd.green());
-
-                                    if cmd.starts_with("trk") {
-                                        let new_track_n = cmd[3..].trim_start().parse::<usize>()
-                                            .map_err(|_| DSEError::InvalidDSECommandFailedToParseTrkChange(cmd.to_string()))?;
-                                        track_i = new_track_n;
-                                        continue;
-                                    } else if cmd.starts_with("evttrk") {
-                                        track_i = 0;
-                                        continue;
-                                    }
-
-                                    let name;
-                                    let mut arguments_bytes: Vec<u8> = Vec::new();
-
-                                    if let Some(left_paren_index) = cmd.chars().position(|c| c == '(') {
-                                        name = cmd[..left_paren_index].trim_end();
-
-                                        // Parse arguments
-                                        let mut arguments_str = cmd[(left_paren_index+1)..].trim_start();
-                                        if arguments_str.len() == 0 {
-                                            return Err(DSEError::InvalidDSECommand(cmd.to_string(), "Opening parentheses must be closed!!".to_string()));
-                                        } else {
-                                            if arguments_str.chars().last().unwrap() == ')' {
-                                                arguments_str = arguments_str[..(arguments_str.len()-1)].trim_end();
-                                            } else {
-                                                return Err(DSEError::InvalidDSECommand(cmd.to_string(), "Opening parentheses must be closed!!".to_string()));
-                                            }
-                                        }
-                                        for arg in arguments_str.split(",").map(|x| x.trim().to_lowercase()) {
-                                            let mut added_argument_bytes: Vec<u8> = Vec::new();
-
-                                            let typed: Vec<&str> = arg.split("_").map(|x| x.trim()).collect();
-
-                                            if arg == "" {
-                                                // Skip
-                                            }
-
-                                            else if typed.len() == 2 {
-                                                // Typed
-                                                match typed[1] {
-                                                    "i8" => added_argument_bytes.write_i8(
-                                                        typed[0].parse::<i8>()
-                                                            .map_or_else(|_| i8::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "u8" => added_argument_bytes.write_u8(
-                                                        typed[0].parse::<u8>()
-                                                            .map_or_else(|_| u8::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-
-                                                    "i16le" => added_argument_bytes.write_i16::<LittleEndian>(
-                                                        typed[0].parse::<i16>()
-                                                            .map_or_else(|_| i16::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "u16le" => added_argument_bytes.write_u16::<LittleEndian>(
-                                                        typed[0].parse::<u16>()
-                                                            .map_or_else(|_| u16::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "i32le" => added_argument_bytes.write_i32::<LittleEndian>(
-                                                        typed[0].parse::<i32>()
-                                                            .map_or_else(|_| i32::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "u32le" => added_argument_bytes.write_u32::<LittleEndian>(
-                                                        typed[0].parse::<u32>()
-                                                            .map_or_else(|_| u32::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "i64le" => added_argument_bytes.write_i64::<LittleEndian>(
-                                                        typed[0].parse::<i64>()
-                                                            .map_or_else(|_| i64::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "u64le" => added_argument_bytes.write_u64::<LittleEndian>(
-                                                        typed[0].parse::<u64>()
-                                                            .map_or_else(|_| u64::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "i128le" => added_argument_bytes.write_i128::<LittleEndian>(
-                                                        typed[0].parse::<i128>()
-                                                            .map_or_else(|_| i128::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "u128le" => added_argument_bytes.write_u128::<LittleEndian>(
-                                                        typed[0].parse::<u128>()
-                                                            .map_or_else(|_| u128::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-
-                                                    "i16be" => added_argument_bytes.write_i16::<BigEndian>(
-                                                        typed[0].parse::<i16>()
-                                                            .map_or_else(|_| i16::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "u16be" => added_argument_bytes.write_u16::<BigEndian>(
-                                                        typed[0].parse::<u16>()
-                                                            .map_or_else(|_| u16::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "i32be" => added_argument_bytes.write_i32::<BigEndian>(
-                                                        typed[0].parse::<i32>()
-                                                            .map_or_else(|_| i32::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "u32be" => added_argument_bytes.write_u32::<BigEndian>(
-                                                        typed[0].parse::<u32>()
-                                                            .map_or_else(|_| u32::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "i64be" => added_argument_bytes.write_i64::<BigEndian>(
-                                                        typed[0].parse::<i64>()
-                                                            .map_or_else(|_| i64::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "u64be" => added_argument_bytes.write_u64::<BigEndian>(
-                                                        typed[0].parse::<u64>()
-                                                            .map_or_else(|_| u64::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "i128be" => added_argument_bytes.write_i128::<BigEndian>(
-                                                        typed[0].parse::<i128>()
-                                                            .map_or_else(|_| i128::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-                                                    "u128be" => added_argument_bytes.write_u128::<BigEndian>(
-                                                        typed[0].parse::<u128>()
-                                                            .map_or_else(|_| u128::from_str_radix(&typed[0].trim_start_matches("0x"), 16), |x| Ok(x))
-                                                            .map_err(|_| DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))?
-                                                    ),
-
-                                                    _ => {
-                                                        return Err(DSEError::InvalidDSECommandTypedArgument(cmd.to_string(), arg.to_string(), typed[1].to_string()))
-                                                    }
-                                                }?;
-                                            }
-                                            
-                                            else if let Ok(val) = arg.parse::<i8>() {
-                                                added_argument_bytes.write_i8(val)?;
-                                            } else if let Ok(val) = i8::from_str_radix(&arg.trim_start_matches("0x"), 16) {
-                                                added_argument_bytes.write_i8(val)?;
-                                            }
-                                            
-                                            else if let Ok(val) = arg.parse::<u8>() {
-                                                added_argument_bytes.write_u8(val)?;
-                                            } else if let Ok(val) = u8::from_str_radix(&arg.trim_start_matches("0x"), 16) {
-                                                added_argument_bytes.write_u8(val)?;
-                                            }
-
-                                            else {
-                                                return Err(DSEError::InvalidDSECommand(cmd.to_string(), format!("Value '{}' could not be parsed!", arg)));
-                                            }
-
-                                            arguments_bytes.extend(added_argument_bytes);
-                                        }
-                                    } else {
-                                        name = cmd;
-                                    }
-
-                                    let mut evt = Other::default();
-                                    evt.code = Other::name_to_code(name)?;
-
-                                    // Check if the appropriate number of arguments were passed
-                                    let (canonical_name, (_, _, num_bytes_taken)) = Other::lookup(evt.code)?;
-                                    if arguments_bytes.len() != *num_bytes_taken as usize {
-                                        return Err(DSEError::InvalidDSECommandArguments(cmd.to_string(), arguments_bytes.len(), canonical_name.to_string(), *num_bytes_taken as usize))
-                                    }
-
-                                    (&mut evt.parameters[..]).write_all(&arguments_bytes)?;
+                                for (track_i, evt) in parse_dse_command(&marker)? {
                                     trks[track_i].fix_current_global_tick(global_tick)?;
                                     trks[track_i].add_other_event(evt);
                                 }
@@ -406,6 +536,17 @@ impl Hash for ProgramUsed {
         self.program.hash(state);
     }
 }
+/// What `TrkChunkWriter::note_on` does when the key is already held
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// End the held note and start a new one
+    #[default]
+    CutPrevious,
+    /// Drop the new note. The held note ends at the first note off for its key.
+    Ignore,
+    /// Drop the new note, and keep the held note going until every note on for its key has had its note off
+    ExtendPrevious
+}
 pub struct TrkChunkWriter {
     trkid: u8,
     chanid: u8,
@@ -416,11 +557,13 @@ pub struct TrkChunkWriter {
     program: u8,
     programs_used: Vec<ProgramUsed>,
     last_program_change_global_tick: Option<u128>,
-    last_program_change_event_index: Option<usize>
+    last_program_change_event_index: Option<usize>,
+    overlap_policy: OverlapPolicy,
+    stacked_note_ons: HashMap<u8, usize>
 }
 impl TrkChunkWriter {
     pub fn create(trkid: u8, chanid: u8, link_bytes: (u8, u8)) -> Result<TrkChunkWriter, DSEError> {
-        let mut trk_chunk_writer = TrkChunkWriter { trkid, chanid, current_global_tick: 0, trk_events: Vec::new(), notes_held: HashMap::new(), bank: 0, program: 0, programs_used: Vec::new(), last_program_change_global_tick: None, last_program_change_event_index: None };
+        let mut trk_chunk_writer = TrkChunkWriter { trkid, chanid, current_global_tick: 0, trk_events: Vec::new(), notes_held: HashMap::new(), bank: 0, program: 0, programs_used: Vec::new(), last_program_change_global_tick: None, last_program_change_event_index: None, overlap_policy: OverlapPolicy::default(), stacked_note_ons: HashMap::new() };
 
         // Fill in some standard events
         trk_chunk_writer.add_other_with_params_u8("SetTrackExpression", 100)?; // Random value for now
@@ -434,6 +577,9 @@ impl TrkChunkWriter {
     pub fn programs_used(&self) -> &Vec<ProgramUsed> {
         &self.programs_used
     }
+    pub fn set_overlap_policy(&mut self, overlap_policy: OverlapPolicy) {
+        self.overlap_policy = overlap_policy;
+    }
     pub fn bank_select<MapProgram>(&mut self, bank: u8, is_default: bool, mut map_program: MapProgram) -> Result<Option<(Rc<RefCell<DSEEvent>>, usize)>, DSEError>
     where
         MapProgram: FnMut(u8, u8, u8, bool, &mut TrkChunkWriter, Rc<RefCell<DSEEvent>>) -> Option<u8> {
@@ -488,8 +634,19 @@ impl TrkChunkWriter {
     }
     pub fn note_on(&mut self, key: u8, vel: u8) -> Result<(), DSEError> {
         if self.notes_held.contains_key(&key) {
-            println!("{}Overlapping notes detected! By default when there's note overlap a noteoff is sent immediately to avoid them.", "Warning: ".yellow());
-            self.note_off(key)?;
+            match self.overlap_policy {
+                OverlapPolicy::CutPrevious => {
+                    warn!("Overlapping notes detected! By default when there's note overlap a noteoff is sent immediately to avoid them.");
+                    self.note_off(key)?;
+                },
+                OverlapPolicy::Ignore => {
+                    return Ok(());
+                },
+                OverlapPolicy::ExtendPrevious => {
+                    *self.stacked_note_ons.entry(key).or_insert(0) += 1;
+                    return Ok(());
+                }
+            }
         }
         self.add_other_with_params_u8("SetTrackOctave", key / 12)?; // AN EXTRA OCTAVE IS NOT LONGER ADDED BY DEFAULT SO THAT CUSTOM SOUND BANKS WORK CORRECTLY
         let mut evt = PlayNote::default();
@@ -503,10 +660,19 @@ impl TrkChunkWriter {
         }
         Ok(())
     }
+    pub fn is_note_held(&self, key: u8) -> bool {
+        self.notes_held.contains_key(&key)
+    }
     pub fn note_off(&mut self, key: u8) -> Result<(), DSEError> {
         if !self.notes_held.contains_key(&key) {
             return Ok(());
         }
+        if let Some(stacked) = self.stacked_note_ons.get_mut(&key) {
+            if *stacked > 0 {
+                *stacked -= 1;
+                return Ok(());
+            }
+        }
         let (note_on_event, past_global_tick) = self.notes_held.remove(&key).ok_or(DSEError::_ValidHashMapKeyRemovalFailed())?;
         if let Ok(delta) = u32::try_from(self.current_global_tick - past_global_tick) {
             if let Some(delta) = u24::try_from(delta) {
@@ -521,6 +687,15 @@ impl TrkChunkWriter {
         }
         Ok(())
     }
+    /// Send a note_off for every note that is currently held
+    pub fn release_all_held(&mut self) -> Result<(), DSEError> {
+        self.stacked_note_ons.clear();
+        let held_keys: Vec<u8> = self.notes_held.keys().copied().collect();
+        for key in held_keys {
+            self.note_off(key)?;
+        }
+        Ok(())
+    }
     pub fn add_other_no_params(&mut self, name: &str) -> Result<(Rc<RefCell<DSEEvent>>, usize), DSEError> {
         let mut evt = Other::default();
         evt.code = Other::name_to_code(name)?;
@@ -534,23 +709,86 @@ impl TrkChunkWriter {
             Err(DSEError::_InvalidEventTypePassedToSetOtherWithParamsU8())
         }
     }
-    pub fn add_other_with_params_u8(&mut self, name: &str, val: u8) -> Result<(Rc<RefCell<DSEEvent>>, usize), DSEError> {
+    /// Add an event with its parameter bytes given directly. At most 5 bytes fit, and any bytes past the opcode's parameter count would never be written, so they must be zero.
+    pub fn add_other_with_params_bytes(&mut self, name: &str, params: &[u8]) -> Result<(Rc<RefCell<DSEEvent>>, usize), DSEError> {
         let mut evt = Other::default();
         evt.code = Other::name_to_code(name)?;
-        (&mut evt.parameters[..]).write_u8(val)?;
+        let (_, &(_, _, nbparams)) = Other::lookup(evt.code)?;
+        if params.len() > evt.parameters.len() {
+            return Err(DSEError::Invalid(format!("{} parameter bytes were given for '{}', but events can only hold {}!", params.len(), name, evt.parameters.len())));
+        }
+        if params.iter().skip(nbparams as usize).any(|&byte| byte != 0) {
+            return Err(DSEError::Invalid(format!("Parameters {:?} do not fit in the {} parameter bytes of '{}'!", params, nbparams, name)));
+        }
+        evt.parameters[..params.len()].copy_from_slice(params);
         Ok(self.add_other_event(evt))
     }
+    pub fn add_other_with_params_u8(&mut self, name: &str, val: u8) -> Result<(Rc<RefCell<DSEEvent>>, usize), DSEError> {
+        self.add_other_with_params_bytes(name, &[val])
+    }
     pub fn add_other_with_params_i16<E: ByteOrder>(&mut self, name: &str, val: i16) -> Result<(Rc<RefCell<DSEEvent>>, usize), DSEError> {
-        let mut evt = Other::default();
-        evt.code = Other::name_to_code(name)?;
-        (&mut evt.parameters[..]).write_i16::<E>(val)?;
-        Ok(self.add_other_event(evt))
+        let mut params = [0_u8; 2];
+        E::write_i16(&mut params, val);
+        self.add_other_with_params_bytes(name, &params)
     }
     pub fn add_other_with_params_u16<E: ByteOrder>(&mut self, name: &str, val: u16) -> Result<(Rc<RefCell<DSEEvent>>, usize), DSEError> {
-        let mut evt = Other::default();
-        evt.code = Other::name_to_code(name)?;
-        (&mut evt.parameters[..]).write_u16::<E>(val)?;
-        Ok(self.add_other_event(evt))
+        let mut params = [0_u8; 2];
+        E::write_u16(&mut params, val);
+        self.add_other_with_params_bytes(name, &params)
+    }
+    /// Add an event with a 32-bit parameter. For opcodes with fewer than 4 parameter bytes, like `Pause24Bits`, the value must fit in the bytes that are written.
+    pub fn add_other_with_params_u32<E: ByteOrder>(&mut self, name: &str, val: u32) -> Result<(Rc<RefCell<DSEEvent>>, usize), DSEError> {
+        let mut params = [0_u8; 4];
+        E::write_u32(&mut params, val);
+        self.add_other_with_params_bytes(name, &params)
+    }
+    /// Add an event with a 32-bit parameter. For opcodes with fewer than 4 parameter bytes, the value must fit in the bytes that are written.
+    pub fn add_other_with_params_i32<E: ByteOrder>(&mut self, name: &str, val: i32) -> Result<(Rc<RefCell<DSEEvent>>, usize), DSEError> {
+        let mut params = [0_u8; 4];
+        E::write_i32(&mut params, val);
+        self.add_other_with_params_bytes(name, &params)
+    }
+    /// Emit the events needed to override the volume envelope of the current program from the sequence.
+    /// 
+    /// If `env.envon` is false, a single DisableEnvelope is emitted. Otherwise the fields map to events as follows:
+    /// * `atkvol` -> SetEnvAtkLvl
+    /// * `attack` -> SetEnvAtkTime
+    /// * `hold` -> SetEnvHold
+    /// * `decay`, `sustain` -> SetEnvDecSus
+    /// * `decay2` -> SetEnvFade
+    /// * `release` -> SetEnvRelease
+    /// 
+    /// `envmult` has no corresponding event, so the durations are still interpreted using the multiplier of the split being played.
+    pub fn apply_envelope(&mut self, env: &ADSRVolumeEnvelope) -> Result<(), DSEError> {
+        if !env.envon {
+            self.add_other_no_params("DisableEnvelope")?;
+            return Ok(());
+        }
+        let check = |name: &str, val: i8| -> Result<u8, DSEError> {
+            if val < 0 {
+                Err(DSEError::Invalid(format!("Envelope parameter '{}' is {}, but it must be within 0-127!", name, val)))
+            } else {
+                Ok(val as u8)
+            }
+        };
+        let atkvol = check("atkvol", env.atkvol)?;
+        let attack = check("attack", env.attack)?;
+        let hold = check("hold", env.hold)?;
+        let decay = check("decay", env.decay)?;
+        let sustain = check("sustain", env.sustain)?;
+        let decay2 = check("decay2", env.decay2)?;
+        let release = check("release", env.release)?;
+        self.add_other_with_params_u8("SetEnvAtkLvl", atkvol)?;
+        self.add_other_with_params_u8("SetEnvAtkTime", attack)?;
+        self.add_other_with_params_u8("SetEnvHold", hold)?;
+        let mut decsus = Other::default();
+        decsus.code = Other::name_to_code("SetEnvDecSus")?;
+        decsus.parameters[0] = decay;
+        decsus.parameters[1] = sustain;
+        self.add_other_event(decsus);
+        self.add_other_with_params_u8("SetEnvFade", decay2)?;
+        self.add_other_with_params_u8("SetEnvRelease", release)?;
+        Ok(())
     }
     pub fn add_swdl(&mut self, unk2: u8) -> Result<(Rc<RefCell<DSEEvent>>, usize), DSEError> {
         self.add_other_with_params_u8("SetSwdl", unk2)
@@ -558,6 +796,13 @@ impl TrkChunkWriter {
     pub fn add_bank(&mut self, unk1: u8) -> Result<(Rc<RefCell<DSEEvent>>, usize), DSEError> {
         self.add_other_with_params_u8("SetBank", unk1)
     }
+    /// Add a `Signal` event, which hands `value` to the game when it's reached. Signals are only expected on the meta track (trk 0). See `SignalRegistry` for what the values mean.
+    pub fn add_signal(&mut self, value: u8) -> Result<(Rc<RefCell<DSEEvent>>, usize), DSEError> {
+        if self.trkid != 0 {
+            warn!("Signal({}) added to trk{:02} instead of the meta track, the game may never see it!", value, self.trkid);
+        }
+        self.add_other_with_params_u8("Signal", value)
+    }
     // pub fn next_event_index(&self) -> usize {
     //     self.trk_events.len()
     // }
@@ -605,19 +850,13 @@ impl TrkChunkWriter {
         } else if let Ok(delta) = u32::try_from(delta) {
             if let Some(delta) = u24::try_from(delta) {
                 self.current_global_tick += delta.as_int() as u128;
-                let mut pause_event = Other::default();
-                pause_event.code = Other::name_to_code("Pause24Bits")?;
-                (&mut pause_event.parameters[..]).write_u32::<LittleEndian>(delta.as_int())?;
-                self.add_other_event(pause_event);
+                self.add_other_with_params_u32::<LittleEndian>("Pause24Bits", delta.as_int())?;
                 return Ok(());
             }
         }
         let delta = u24::max_value().as_int();
         self.current_global_tick += delta as u128;
-        let mut pause_event = Other::default();
-        pause_event.code = Other::name_to_code("Pause24Bits")?;
-        (&mut pause_event.parameters[..]).write_u32::<LittleEndian>(delta)?;
-        self.add_other_event(pause_event);
+        self.add_other_with_params_u32::<LittleEndian>("Pause24Bits", delta)?;
 
         self.fix_current_global_tick(new_global_tick)
     }
@@ -637,3 +876,111 @@ impl TrkChunkWriter {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(delta: u32, key: u8, vel: u8) -> TrackEvent<'static> {
+        TrackEvent { delta: u28::new(delta), kind: midly::TrackEventKind::Midi { channel: u4::new(0), message: midly::MidiMessage::NoteOn { key: u7::new(key), vel: u7::new(vel) } } }
+    }
+
+    #[test]
+    fn retriggered_note_survives_the_previous_note_off() {
+        let reordered = note_offs_first(&[note(0, 60, 100), note(48, 60, 100), note(0, 60, 0), note(48, 60, 0)]);
+        assert_eq!(reordered, vec![note(0, 60, 100), note(48, 60, 0), note(0, 60, 100), note(48, 60, 0)]);
+    }
+
+    #[test]
+    fn zero_length_note_keeps_its_order() {
+        let messages = [note(0, 60, 100), note(0, 60, 0), note(48, 62, 100), note(0, 62, 0)];
+        assert_eq!(note_offs_first(&messages), messages.to_vec());
+    }
+
+    fn midi_event(delta: u32, message: midly::MidiMessage) -> TrackEvent<'static> {
+        TrackEvent { delta: u28::new(delta), kind: midly::TrackEventKind::Midi { channel: u4::new(0), message } }
+    }
+    /// Copy the messages, all on MIDI channel 0, and return the events written to that channel's track
+    fn copy_channel_0_events(midi_messages: Vec<TrackEvent<'static>>, options: &MidiCopyOptions) -> Vec<DSEEvent> {
+        let mut trks: Vec<TrkChunkWriter> = (0..2).map(|chanid| TrkChunkWriter::create(chanid, chanid, (0, 0)).unwrap()).collect();
+        copy_midi_messages_with_options(Cow::Owned(midi_messages), &mut trks, |_, _, _, _, _, _| None, options).unwrap();
+        trks.remove(1).close_track().events.events
+    }
+    /// Like `copy_channel_0_events`, but only returns the `Other` events, as (code, parameters) pairs
+    fn copy_channel_0(midi_messages: Vec<TrackEvent<'static>>, options: &MidiCopyOptions) -> Vec<(u8, [u8; 5])> {
+        copy_channel_0_events(midi_messages, options).iter().filter_map(|event| match event {
+            DSEEvent::Other(other) => Some((other.code, other.parameters)),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn retriggered_note_keeps_its_duration() {
+        // The note on of the retriggered note comes before the note off of the previous one on the same tick
+        let durations: Vec<u32> = copy_channel_0_events(vec![note(0, 60, 100), note(48, 60, 100), note(0, 60, 0), note(48, 60, 0)], &MidiCopyOptions::default()).iter().filter_map(|event| match event {
+            DSEEvent::PlayNote(play_note) => Some(play_note.keydownduration),
+            _ => None
+        }).collect();
+        assert_eq!(durations, vec![48, 48]);
+    }
+
+    #[test]
+    fn aftertouch_to_volume() {
+        let midi_messages = vec![
+            note(0, 60, 100),
+            midi_event(0, midly::MidiMessage::Aftertouch { key: u7::new(60), vel: u7::new(80) }),
+            midi_event(0, midly::MidiMessage::Aftertouch { key: u7::new(62), vel: u7::new(50) }), // Not held, so dropped
+            midi_event(0, midly::MidiMessage::ChannelAftertouch { vel: u7::new(90) })
+        ];
+        let volume_codes = [Other::name_to_code("SetNoteVolume").unwrap(), Other::name_to_code("SetChanVolume").unwrap()];
+        let volume_events = |options: &MidiCopyOptions| copy_channel_0(midi_messages.clone(), options).into_iter().filter(|(code, _)| volume_codes.contains(code)).map(|(code, parameters)| (code, parameters[0])).collect::<Vec<_>>();
+
+        assert_eq!(volume_events(&MidiCopyOptions { aftertouch_to_volume: true, ..Default::default() }), vec![(volume_codes[0], 80), (volume_codes[1], 90)]);
+        assert!(volume_events(&MidiCopyOptions::default()).is_empty());
+    }
+
+    /// Play a note on key 60 at tick 0, again at tick 24, then release it at ticks 48 and 96, and return the durations of the notes written
+    fn overlapping_note_durations(overlap_policy: OverlapPolicy) -> Vec<u32> {
+        let mut writer = TrkChunkWriter::create(1, 0, (0, 0)).unwrap();
+        writer.set_overlap_policy(overlap_policy);
+        writer.note_on(60, 100).unwrap();
+        writer.fix_current_global_tick(24).unwrap();
+        writer.note_on(60, 100).unwrap();
+        writer.fix_current_global_tick(48).unwrap();
+        writer.note_off(60).unwrap();
+        writer.fix_current_global_tick(96).unwrap();
+        writer.note_off(60).unwrap();
+        writer.close_track().events.events.iter().filter_map(|event| match event {
+            DSEEvent::PlayNote(note) => Some(note.keydownduration),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn overlap_policies() {
+        assert_eq!(OverlapPolicy::default(), OverlapPolicy::CutPrevious);
+        assert_eq!(overlapping_note_durations(OverlapPolicy::CutPrevious), vec![24, 24]);
+        assert_eq!(overlapping_note_durations(OverlapPolicy::Ignore), vec![48]);
+        assert_eq!(overlapping_note_durations(OverlapPolicy::ExtendPrevious), vec![96]);
+    }
+
+    #[test]
+    fn mod_wheel_sets_up_lfo1() {
+        let options = MidiCopyOptions { modulation_lfo: Some(ModulationLfo { rate: 0x0102, max_depth: 1000, waveform: 3 }), ..Default::default() };
+        let midi_messages = vec![
+            midi_event(0, midly::MidiMessage::Controller { controller: u7::new(1), value: u7::new(127) }),
+            midi_event(0, midly::MidiMessage::Controller { controller: u7::new(1), value: u7::new(0) })
+        ];
+        let lfo_events: Vec<(u8, [u8; 5])> = copy_channel_0(midi_messages.clone(), &options).into_iter().filter(|(code, _)| (0xDC..=0xDF).contains(code)).collect();
+        assert_eq!(Other::name_to_code("SetLFO1").unwrap(), 0xDC);
+        assert_eq!(Other::name_to_code("RouteLFO1ToPitch").unwrap(), 0xDF);
+        assert_eq!(Other::lookup(0xDC).unwrap().1.2, 5);
+        assert_eq!(lfo_events, vec![
+            (0xDC, [0x02, 0x01, 0xE8, 0x03, 3]), // Rate and depth as little endian u16, then the waveform
+            (0xDF, [1, 0, 0, 0, 0]),
+            (0xDF, [0, 0, 0, 0, 0])
+        ]);
+
+        assert!(copy_channel_0(midi_messages, &MidiCopyOptions::default()).iter().all(|(code, _)| !(0xDC..=0xDF).contains(code)));
+    }
+}
@@ -1,10 +1,14 @@
 use core::panic;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
 use std::fmt::{Display, Debug};
 use std::io::{Read, Write, Seek, SeekFrom, Cursor};
 use std::fs::File;
 use std::path::Path;
-use bevy_reflect::Reflect;
+use bevy_reflect::{Reflect, Struct};
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use log::{info, trace};
 use phf::phf_map;
 use serde::{Serialize, Deserialize};
 
@@ -14,13 +18,17 @@ use crate::deserialize_with;
 use crate::fileutils::valid_file_of_type;
 
 pub mod sf2;
+pub mod decode;
+pub mod wav;
 
 /// By default, all unknown bytes that do not have a consistent pattern of values in the EoS roms are included in the XML.
 /// However, a subset of these not 100% purpose-certain bytes is 80% or something of values that have "typical" values.
 /// Setting this to true will strip all those somewhat certain bytes from the Serde serialization process, and replace them
 /// with their typical values.
-const fn serde_use_common_values_for_unknowns<T>(_: &T) -> bool {
-    true
+/// 
+/// This can be toggled at runtime with `dtype::set_strip_common_unknowns`.
+fn serde_use_common_values_for_unknowns<T>(_: &T) -> bool {
+    strip_common_unknowns()
 }
 
 //// NOTE: Any struct fields starting with an _ indicates that that struct field will be ignored when writing, with its appropriate value generate on-the-fly based on the other fields
@@ -298,9 +306,33 @@ impl ADSRVolumeEnvelope {
         
         default
     }
+    /// Get the duration of each stage in milliseconds and the sustain level, for display in an envelope editor. The durations are read from the 16-bit table scaled by `envmult` when it is nonzero, or from the 32-bit table otherwise (see `env_index_to_milliseconds`).
+    pub fn display_times(&self) -> EnvelopeTimes {
+        EnvelopeTimes {
+            attack_level: self.atkvol.max(0) as u8,
+            attack: env_index_to_milliseconds(self.envmult, self.attack),
+            hold: env_index_to_milliseconds(self.envmult, self.hold),
+            decay: env_index_to_milliseconds(self.envmult, self.decay),
+            sustain_level: self.sustain.max(0) as u8,
+            decay2: env_index_to_milliseconds(self.envmult, self.decay2),
+            release: env_index_to_milliseconds(self.envmult, self.release)
+        }
+    }
 }
 impl AutoReadWrite for ADSRVolumeEnvelope {  }
 
+/// The stages of an `ADSRVolumeEnvelope` with durations in milliseconds and levels from 0 to 127
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvelopeTimes {
+    pub attack_level: u8,
+    pub attack: i32,
+    pub hold: i32,
+    pub decay: i32,
+    pub sustain_level: u8,
+    pub decay2: i32,
+    pub release: i32
+}
+
 #[derive(Debug, Default, Copy, Clone, Reflect, Serialize, Deserialize)]
 pub struct Tuning {
     #[serde(deserialize_with = "deserialize_with::flattened_xml_attr")]
@@ -343,6 +375,11 @@ impl Tuning {
 
         Tuning::new(ftune, ctune as i8)
     }
+    /// Tuning that raises the pitch by the frequency ratio `ratio`, rounded to the nearest cent. The ratio must be finite and greater than zero.
+    pub fn from_ratio(ratio: f64) -> Tuning {
+        debug_assert!(ratio.is_finite() && ratio > 0.0, "Invalid frequency ratio {}!", ratio);
+        Tuning::from_cents((1200.0 * ratio.log2()).round() as i64)
+    }
     pub fn ftune(&self) -> u8 {
         self.ftune
     }
@@ -360,6 +397,61 @@ impl Tuning {
     }
 }
 impl AutoReadWrite for Tuning {  }
+/// The encoding of a sample's data, as given by `SampleInfo::smplfmt`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    /// 0x0000, signed 8-bit PCM
+    Pcm8,
+    /// 0x0100, signed little-endian 16-bit PCM
+    Pcm16,
+    /// 0x0200, NDS-flavored 4-bit IMA ADPCM
+    Adpcm4,
+    /// 0x0300, possibly the NDS's PSG (square wave/noise) channels. Nothing is known about how its data is laid out, so it can't be decoded.
+    Psg
+}
+impl SampleFormat {
+    /// Look up the format for a `SampleInfo::smplfmt` value, or `None` if the value isn't a known format
+    pub fn from_smplfmt(smplfmt: u16) -> Option<SampleFormat> {
+        match smplfmt {
+            0x0000 => Some(SampleFormat::Pcm8),
+            0x0100 => Some(SampleFormat::Pcm16),
+            0x0200 => Some(SampleFormat::Adpcm4),
+            0x0300 => Some(SampleFormat::Psg),
+            _ => None
+        }
+    }
+    /// The `SampleInfo::smplfmt` value for this format
+    pub fn smplfmt(&self) -> u16 {
+        match self {
+            SampleFormat::Pcm8 => 0x0000,
+            SampleFormat::Pcm16 => 0x0100,
+            SampleFormat::Adpcm4 => 0x0200,
+            SampleFormat::Psg => 0x0300
+        }
+    }
+}
+impl TryFrom<u16> for SampleFormat {
+    type Error = DSEError;
+
+    fn try_from(smplfmt: u16) -> Result<Self, Self::Error> {
+        SampleFormat::from_smplfmt(smplfmt).ok_or(DSEError::UnknownSampleFormat(smplfmt))
+    }
+}
+impl From<SampleFormat> for u16 {
+    fn from(format: SampleFormat) -> Self {
+        format.smplfmt()
+    }
+}
+impl Display for SampleFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SampleFormat::Pcm8 => write!(f, "8-bit PCM"),
+            SampleFormat::Pcm16 => write!(f, "16-bit PCM"),
+            SampleFormat::Adpcm4 => write!(f, "4-bit ADPCM"),
+            SampleFormat::Psg => write!(f, "PSG")
+        }
+    }
+}
 #[derive(Debug, Clone, Reflect, Serialize, Deserialize)]
 pub struct SampleInfo {
     #[serde(default = "GenericDefaultU16::<0xAA01>::value")]
@@ -465,6 +557,21 @@ impl Default for SampleInfo {
         }
     }
 }
+impl SampleInfo {
+    /// Returns an alternative "default value" of `SampleInfo` based on the values most samples in the EoS main bank share, with the envelope from `ADSRVolumeEnvelope::default2`.
+    pub fn eos_defaults() -> Self {
+        let mut default = Self::default();
+
+        default.tuning = Tuning::new(0, -7);
+        default.rootkey = 60;
+        default.ktps = 0;
+        default.volume = 127;
+        default.pan = 64;
+        default.volume_envelope = ADSRVolumeEnvelope::default2();
+
+        default
+    }
+}
 impl IsSelfIndexed for SampleInfo {
     fn is_self_indexed(&self) -> Option<usize> {
         Some(self.id as usize)
@@ -475,6 +582,92 @@ impl IsSelfIndexed for SampleInfo {
     }
 }
 impl AutoReadWrite for SampleInfo {  }
+/// Builds a `SampleInfo` from just its meaningful fields, with every unknown field left at its default
+#[derive(Debug, Clone)]
+pub struct SampleInfoBuilder {
+    sample_info: SampleInfo
+}
+impl SampleInfoBuilder {
+    pub fn new(id: u16) -> SampleInfoBuilder {
+        let mut sample_info = SampleInfo::default();
+        sample_info.id = id;
+        SampleInfoBuilder { sample_info }
+    }
+    pub fn tuning(mut self, tuning: Tuning) -> SampleInfoBuilder {
+        self.sample_info.tuning = tuning;
+        self
+    }
+    /// Set the root key. `ktps` is derived from it when building.
+    pub fn rootkey(mut self, rootkey: i8) -> SampleInfoBuilder {
+        self.sample_info.rootkey = rootkey;
+        self
+    }
+    pub fn volume(mut self, volume: i8) -> SampleInfoBuilder {
+        self.sample_info.volume = volume;
+        self
+    }
+    pub fn pan(mut self, pan: i8) -> SampleInfoBuilder {
+        self.sample_info.pan = pan;
+        self
+    }
+    pub fn format(mut self, format: SampleFormat) -> SampleInfoBuilder {
+        self.sample_info.smplfmt = format.into();
+        self
+    }
+    pub fn smplrate(mut self, smplrate: u32) -> SampleInfoBuilder {
+        self.sample_info.smplrate = smplrate;
+        self
+    }
+    pub fn smplpos(mut self, smplpos: u32) -> SampleInfoBuilder {
+        self.sample_info.smplpos = smplpos;
+        self
+    }
+    /// Set the loop bounds in 4-byte words, see `SampleInfo::loopbeg`. The sample's length is always `loopbeg + looplen`, even when it isn't looped.
+    pub fn loop_bounds(mut self, loopbeg: u32, looplen: u32) -> SampleInfoBuilder {
+        self.sample_info.loopbeg = loopbeg;
+        self.sample_info.looplen = looplen;
+        self
+    }
+    pub fn smplloop(mut self, smplloop: bool) -> SampleInfoBuilder {
+        self.sample_info.smplloop = smplloop;
+        self
+    }
+    pub fn volume_envelope(mut self, volume_envelope: ADSRVolumeEnvelope) -> SampleInfoBuilder {
+        self.sample_info.volume_envelope = volume_envelope;
+        self
+    }
+    pub fn build(mut self) -> SampleInfo {
+        self.sample_info.ktps = 60 - self.sample_info.rootkey;
+        self.sample_info
+    }
+}
+impl SampleInfo {
+    pub fn builder(id: u16) -> SampleInfoBuilder {
+        SampleInfoBuilder::new(id)
+    }
+    /// The format of this sample's data, or `DSEError::UnknownSampleFormat` if `smplfmt` isn't a known format
+    pub fn format(&self) -> Result<SampleFormat, DSEError> {
+        SampleFormat::try_from(self.smplfmt)
+    }
+    /// Decode this sample's data in `pcmd` to 16-bit PCM. The region decoded is `loopbeg + looplen` 4-byte words starting at `smplpos`, which for ADPCM samples includes the 4 byte preamble at the start.
+    pub fn decode_samples(&self, pcmd: &PCMDChunk) -> Result<Vec<i16>, DSEError> {
+        let start = self.smplpos as usize;
+        let len = (self.loopbeg as usize + self.looplen as usize) * 4;
+        let data = pcmd.data.get(start..(start + len)).ok_or(DSEError::SampleReadError(self.id.to_string(), start as u64, len))?;
+        decode::decode_sample(data, self.smplfmt)
+    }
+    /// The frame the loop starts at within the samples returned by `decode_samples`
+    pub fn loop_start_frame(&self) -> usize {
+        // loopbeg is counted in 4-byte words from the start of the sample data, including the ADPCM preamble
+        let loopbeg_bytes = self.loopbeg as usize * 4;
+        match self.format().ok() {
+            Some(SampleFormat::Pcm8) => loopbeg_bytes,
+            Some(SampleFormat::Pcm16) => loopbeg_bytes / 2,
+            Some(SampleFormat::Adpcm4) => loopbeg_bytes.saturating_sub(4) * 2,
+            _ => 0
+        }
+    }
+}
 
 #[derive(Debug, Clone, Reflect, Serialize, Deserialize)]
 pub struct ProgramInfoHeader {
@@ -563,6 +756,47 @@ impl IsSelfIndexed for ProgramInfoHeader {
 }
 impl AutoReadWrite for ProgramInfoHeader {  }
 
+/// What an LFO modulates, as given by `LFOEntry::dest`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LfoDest {
+    /// 0x0
+    Disabled,
+    /// 0x1
+    Pitch,
+    /// 0x2
+    Volume,
+    /// 0x3
+    Pan,
+    /// 0x4, probably a lowpass filter's cutoff
+    Cutoff,
+    /// Any other value, kept as is so that it round-trips
+    Unknown(u8)
+}
+impl From<u8> for LfoDest {
+    fn from(dest: u8) -> Self {
+        match dest {
+            0x0 => LfoDest::Disabled,
+            0x1 => LfoDest::Pitch,
+            0x2 => LfoDest::Volume,
+            0x3 => LfoDest::Pan,
+            0x4 => LfoDest::Cutoff,
+            _ => LfoDest::Unknown(dest)
+        }
+    }
+}
+impl From<LfoDest> for u8 {
+    fn from(dest: LfoDest) -> Self {
+        match dest {
+            LfoDest::Disabled => 0x0,
+            LfoDest::Pitch => 0x1,
+            LfoDest::Volume => 0x2,
+            LfoDest::Pan => 0x3,
+            LfoDest::Cutoff => 0x4,
+            LfoDest::Unknown(dest) => dest
+        }
+    }
+}
+
 #[derive(Debug, Clone, Reflect, Serialize, Deserialize)]
 pub struct LFOEntry {
     #[serde(default)]
@@ -611,6 +845,16 @@ impl Default for LFOEntry {
         }
     }
 }
+impl LFOEntry {
+    /// The destination of this LFO, as given by `dest`
+    pub fn destination(&self) -> LfoDest {
+        LfoDest::from(self.dest)
+    }
+    /// Set `dest` to the given destination
+    pub fn set_destination(&mut self, dest: LfoDest) {
+        self.dest = dest.into();
+    }
+}
 impl IsSelfIndexed for LFOEntry {
     fn is_self_indexed(&self) -> Option<usize> {
         None
@@ -737,6 +981,16 @@ impl Default for SplitEntry {
         }
     }
 }
+impl SplitEntry {
+    /// Limit the key range of this split to `semitones` above and below its root key, so that the sample isn't pitched too far from where it was recorded.
+    pub fn set_range_around_root(&mut self, semitones: i8) {
+        let semitones = semitones.unsigned_abs() as i16;
+        self.lowkey = (self.rootkey as i16 - semitones).clamp(0, 127) as i8;
+        self.hikey = (self.rootkey as i16 + semitones).clamp(0, 127) as i8;
+        self.lowkey2 = self.lowkey;
+        self.hikey2 = self.hikey;
+    }
+}
 impl IsSelfIndexed for SplitEntry {
     fn is_self_indexed(&self) -> Option<usize> {
         Some(self.id as usize)
@@ -785,6 +1039,49 @@ impl Default for ProgramInfo {
         }
     }
 }
+impl ProgramInfo {
+    /// Returns pairs of split indices whose key and velocity ranges both intersect, meaning both splits will sound for the same notes
+    pub fn overlapping_splits(&self) -> Vec<(usize, usize)> {
+        let splits = &self.splits_table.objects;
+        let mut overlapping = Vec::new();
+        for i in 0..splits.len() {
+            for j in (i + 1)..splits.len() {
+                let (a, b) = (&splits[i], &splits[j]);
+                let keys_overlap = a.lowkey <= b.hikey && b.lowkey <= a.hikey;
+                let vels_overlap = a.lovel <= b.hivel && b.lovel <= a.hivel;
+                if keys_overlap && vels_overlap {
+                    overlapping.push((i, j));
+                }
+            }
+        }
+        overlapping
+    }
+    /// Scale down the `smplvol` of layered splits so that their summed output can't go over the volume of a single split.
+    /// Each split is divided by the largest number of splits sounding together anywhere within its key/velocity region. This assumes the worst case of all layers peaking at once, so it is on the conservative side.
+    pub fn balance_layer_volumes(&mut self) {
+        if self.overlapping_splits().is_empty() {
+            return;
+        }
+        let clamp = |x: i8| x.clamp(0, 127) as usize;
+        let mut layers = vec![[0_usize; 128]; 128];
+        for split in &self.splits_table.objects {
+            for key in clamp(split.lowkey)..=clamp(split.hikey) {
+                for vel in clamp(split.lovel)..=clamp(split.hivel) {
+                    layers[key][vel] += 1;
+                }
+            }
+        }
+        for split in self.splits_table.objects.iter_mut() {
+            let mut max_layers = 1;
+            for key in clamp(split.lowkey)..=clamp(split.hikey) {
+                for vel in clamp(split.lovel)..=clamp(split.hivel) {
+                    max_layers = max_layers.max(layers[key][vel]);
+                }
+            }
+            split.smplvol = (split.smplvol as f64 / max_layers as f64).round() as i8;
+        }
+    }
+}
 impl ReadWrite for ProgramInfo {
     fn write_to_file<W: Read + Write + Seek>(&self, writer: &mut W) -> Result<usize, DSEError> {
         let mut bytes_written = self.header.write_to_file(writer)?;
@@ -838,6 +1135,29 @@ impl IsSelfIndexed for Keygroup {
 }
 impl AutoReadWrite for Keygroup {  }
 
+/// How a keygroup is used by the splits of a SWDL, as reported by `SWDL::keygroup_usage`
+#[derive(Debug, Clone, Default)]
+pub struct KeygroupUsage {
+    /// Number of splits assigned to this keygroup
+    pub nsplits: usize,
+    /// Programs with at least one split assigned to this keygroup
+    pub programs: BTreeSet<u16>,
+    /// The keygroup's definition in the kgrp chunk, or `None` if splits refer to a keygroup that doesn't exist
+    pub keygroup: Option<Keygroup>
+}
+impl KeygroupUsage {
+    /// Maximum number of notes this keygroup lets play at once, or `None` if it's unlimited (poly of -1) or undefined. This is further capped by the width of its voice channel range.
+    pub fn max_voices(&self) -> Option<usize> {
+        let keygroup = self.keygroup.as_ref()?;
+        if keygroup.poly < 0 {
+            return None;
+        }
+        let vchigh = if keygroup.vchigh < 0 { 15 } else { keygroup.vchigh };
+        let channels = (vchigh as isize - keygroup.vclow as isize + 1).max(0) as usize;
+        Some((keygroup.poly as usize).min(channels))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WAVIChunk {
     #[serde(default)]
@@ -859,6 +1179,9 @@ impl WAVIChunk {
     pub fn set_read_params(&mut self, nbwavislots: usize) {
         self._read_n = nbwavislots;
     }
+    pub fn set_max_chunk_len(&mut self, max_chunk_len: Option<u64>) {
+        self.data.set_max_chunk_len(max_chunk_len);
+    }
 }
 impl WAVIChunk {
     pub fn write_to_file<P: Pointer<LittleEndian>, W: Read + Write + Seek>(&self, writer: &mut W) -> Result<usize, DSEError> {
@@ -896,6 +1219,9 @@ impl PRGIChunk {
     pub fn set_read_params(&mut self, nbprgislots: usize) {
         self._read_n = nbprgislots;
     }
+    pub fn set_max_chunk_len(&mut self, max_chunk_len: Option<u64>) {
+        self.data.set_max_chunk_len(max_chunk_len);
+    }
 }
 impl PRGIChunk {
     pub fn write_to_file<P: Pointer<LittleEndian>, W: Read + Write + Seek>(&self, writer: &mut W) -> Result<usize, DSEError> {
@@ -938,8 +1264,10 @@ impl Default for KGRPChunk {
 }
 impl ReadWrite for KGRPChunk {
     fn write_to_file<W: Read + Write + Seek>(&self, writer: &mut W) -> Result<usize, DSEError> {
-        Ok(self.header.write_to_file(writer)? + self.data.write_to_file(writer)? + if self.data.objects.len() % 2 == 1 { vec![0x67, 0xC0, 0x40, 0x00, 0x88, 0x00, 0xFF, 0x04].write_to_file(writer)? } else { 0 })
-        // Ok(self.header.write_to_file(writer)? + self.data.write_to_file(writer)? + if let Some(pad) = &self._padding { pad.write_to_file(writer)? } else { 0 })
+        // An odd number of keygroups needs 8 bytes of padding. Write back the padding that was read if there is one, since it isn't always the same.
+        Ok(self.header.write_to_file(writer)? + self.data.write_to_file(writer)? + if self.data.objects.len() % 2 == 1 {
+            if let Some(pad) = &self._padding { pad.write_to_file(writer)? } else { vec![0x67, 0xC0, 0x40, 0x00, 0x88, 0x00, 0xFF, 0x04].write_to_file(writer)? }
+        } else { 0 })
     }
     fn read_from_file<R: Read + Seek>(&mut self, reader: &mut R) -> Result<(), DSEError> {
         self.header.read_from_file(reader)?;
@@ -949,8 +1277,12 @@ impl ReadWrite for KGRPChunk {
         self._padding.as_mut().unwrap().read_from_file(reader)?;
         // "pcmd" {0x70, 0x63, 0x6D, 0x64}
         // "eod\20" {0x65, 0x6F, 0x64, 0x20}
-        if &self._padding.as_ref().unwrap().delimiter[..4] == &[0x70, 0x63, 0x6D, 0x64] ||
-            &self._padding.as_ref().unwrap().delimiter[..4] == &[0x65, 0x6F, 0x64, 0x20] {
+        // "wavi" {0x77, 0x61, 0x76, 0x69} and "prgi" {0x70, 0x72, 0x67, 0x69} can also follow when the chunks are in a custom order
+        let next_magic = &self._padding.as_ref().unwrap().delimiter[..4];
+        if next_magic == &[0x70, 0x63, 0x6D, 0x64] ||
+            next_magic == &[0x65, 0x6F, 0x64, 0x20] ||
+            next_magic == &[0x77, 0x61, 0x76, 0x69] ||
+            next_magic == &[0x70, 0x72, 0x67, 0x69] {
             self._padding = None;
             reader.seek(SeekFrom::Current(-8))?;
         }
@@ -983,17 +1315,26 @@ pub struct PCMDChunk {
     pub data: Vec<u8>,
     #[serde(default)]
     #[serde(skip_serializing)]
-    pub _padding: Vec<u8>
+    pub _padding: Vec<u8>,
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    _max_chunk_len: Option<u64>
 }
 impl Default for PCMDChunk {
     fn default() -> Self {
         PCMDChunk {
             header: ChunkHeader::default(),
             data: Vec::new(),
-            _padding: Vec::new()
+            _padding: Vec::new(),
+            _max_chunk_len: None
         }
     }
 }
+impl PCMDChunk {
+    pub fn set_max_chunk_len(&mut self, max_chunk_len: Option<u64>) {
+        self._max_chunk_len = max_chunk_len;
+    }
+}
 impl ReadWrite for PCMDChunk {
     fn write_to_file<W: Read + Write + Seek>(&self, writer: &mut W) -> Result<usize, DSEError> {
         let len = self.header.write_to_file(writer)? + self.data.write_to_file(writer)?;
@@ -1006,6 +1347,11 @@ impl ReadWrite for PCMDChunk {
     }
     fn read_from_file<R: Read + Seek>(&mut self, reader: &mut R) -> Result<(), DSEError> {
         self.header.read_from_file(reader)?;
+        if let Some(max_chunk_len) = self._max_chunk_len {
+            if self.header.chunklen as u64 > max_chunk_len {
+                return Err(DSEError::ChunkTooLarge(self.header.chunklen as u64, max_chunk_len));
+            }
+        }
         self.data = vec![0; self.header.chunklen as usize];
         self.data.read_from_file(reader)?;
         // EOD\20 {0x65, 0x6F, 0x64, 0x20}
@@ -1028,7 +1374,10 @@ pub struct SWDL {
     pub pcmd: Option<PCMDChunk>,
     #[serde(default = "SWDL::generate_eod_chunk_header")]
     #[serde(skip_serializing)]
-    pub _eod: ChunkHeader
+    pub _eod: ChunkHeader,
+    /// Out-of-band record of where each sample came from, as DSE sample id to soundfont name and original SF2 sample index. Filled in by the import pipeline and never written out.
+    #[serde(skip)]
+    pub _sample_provenance: HashMap<u16, (String, u16)>
 }
 impl DSELinkBytes for SWDL {
     fn get_link_bytes(&self) -> (u8, u8) {
@@ -1072,9 +1421,27 @@ impl SWDL {
     }
     /// Regenerate length, slots, and nb parameters. To keep this working, `write_to_file` should never attempt to read or seek beyond alotted frame, which is initial cursor position and beyond.
     pub fn regenerate_read_markers<PWavi: Pointer<LittleEndian>, PPrgi: Pointer<LittleEndian>>(&mut self) -> Result<(), DSEError> { //TODO: make more efficient
-        // ======== NUMERICAL VALUES (LENGTHS, SLOTS, etc) ========
+        self.regenerate_slot_counts()?;
+        self.regenerate_lengths::<PWavi, PPrgi>()?;
+        self.regenerate_labels();
+        Ok(())
+    }
+    /// Regenerate only the slot and entry counts (`nbwavislots`, `nbprgislots`, and the split and LFO counts of every program).
+    pub fn regenerate_slot_counts(&mut self) -> Result<(), DSEError> {
+        self.header.nbwavislots = self.wavi.data.slots().try_into().map_err(|_| DSEError::PointerTableTooLong(DSEBlockType::SwdlWavi))?;
+        self.header.nbprgislots = self.prgi.as_ref().map(|prgi| prgi.data.slots().try_into().map_err(|_| DSEError::PointerTableTooLong(DSEBlockType::SwdlPrgi))).unwrap_or(Ok(128))?; // In the main bank, this is set to 128 even though there is no prgi chunk
+        if let Some(prgi) = &mut self.prgi {
+            for (i, obj) in prgi.data.objects.iter_mut().enumerate() {
+                obj.header.nbsplits = obj.splits_table.len().try_into().map_err(|_| DSEError::TableTooLong(DSEBlockType::SwdlPrgiProgramInfoSplits(i)))?;
+                obj.header.nblfos = obj.lfo_table.len().try_into().map_err(|_| DSEError::TableTooLong(DSEBlockType::SwdlPrgiProgramInfoLfos(i)))?;
+            }
+        }
+        Ok(())
+    }
+    /// Regenerate only the file and chunk lengths. This serializes the whole file to a scratch buffer, so skip it if an edit doesn't change any lengths.
+    pub fn regenerate_lengths<PWavi: Pointer<LittleEndian>, PPrgi: Pointer<LittleEndian>>(&mut self) -> Result<(), DSEError> {
         self.header.flen = self.write_to_file::<PWavi, PPrgi, _>(&mut Cursor::new(&mut Vec::new()))?.try_into().map_err(|_| DSEError::BinaryFileTooLarge(DSEFileType::SWDL))?;
-        println!("flen {}", self.header.flen);
+        trace!("flen {}", self.header.flen);
         if self.header.pcmdlen & 0xFFFF0000 == 0xAAAA0000 && self.pcmd.is_none() {
             // Expected case of separation with main bank. Noop
         } else if let Some(pcmd) = &mut self.pcmd {
@@ -1084,8 +1451,6 @@ impl SWDL {
             // By default, assume that if the file does not contain a bank of its own, that the samples it refers to are in the main bank
             self.header.pcmdlen = 0xAAAA0000;
         }
-        self.header.nbwavislots = self.wavi.data.slots().try_into().map_err(|_| DSEError::PointerTableTooLong(DSEBlockType::SwdlWavi))?;
-        self.header.nbprgislots = self.prgi.as_ref().map(|prgi| prgi.data.slots().try_into().map_err(|_| DSEError::PointerTableTooLong(DSEBlockType::SwdlPrgi))).unwrap_or(Ok(128))?; // In the main bank, this is set to 128 even though there is no prgi chunk
         self.header.wavilen = self.wavi.data.write_to_file::<PWavi, _>(&mut Cursor::new(&mut Vec::new())).map_err(|e| match e {
             DSEError::Placeholder() => DSEError::PointerTableTooLarge(DSEBlockType::SwdlWavi),
             _ => e
@@ -1096,15 +1461,14 @@ impl SWDL {
                 DSEError::Placeholder() => DSEError::PointerTableTooLarge(DSEBlockType::SwdlPrgi),
                 _ => e
             })?.try_into().map_err(|_| DSEError::BinaryBlockTooLarge(DSEFileType::SWDL, DSEBlockType::SwdlPrgi))?;
-            for (i, obj) in prgi.data.objects.iter_mut().enumerate() {
-                obj.header.nbsplits = obj.splits_table.len().try_into().map_err(|_| DSEError::TableTooLong(DSEBlockType::SwdlPrgiProgramInfoSplits(i)))?;
-                obj.header.nblfos = obj.lfo_table.len().try_into().map_err(|_| DSEError::TableTooLong(DSEBlockType::SwdlPrgiProgramInfoLfos(i)))?;
-            }
         }
         if let Some(kgrp) = &mut self.kgrp {
             kgrp.header.chunklen = kgrp.data.write_to_file(&mut Cursor::new(&mut Vec::new()))?.try_into().map_err(|_| DSEError::BinaryBlockTooLarge(DSEFileType::SWDL, DSEBlockType::SwdlKgrp))?;
         }
-        // ======== CHUNK LABELS ========
+        Ok(())
+    }
+    /// Regenerate only the magic number and chunk labels.
+    pub fn regenerate_labels(&mut self) {
         self.header.magicn = 0x6C647773;
         self.wavi.header.label = 0x69766177; // "wavi"  {0x77, 0x61, 0x76, 0x69}
         if let Some(prgi) = &mut self.prgi {
@@ -1117,7 +1481,6 @@ impl SWDL {
             pcmd.header.label = 0x646D6370; //  "pcmd" {0x70, 0x63, 0x6D, 0x64} 
         }
         // self._eod.label = 0x20646F65; //  "eod\20" {0x65, 0x6F, 0x64, 0x20} 
-        Ok(())
     }
     /// Regenerate automatic parameters.
     pub fn regenerate_automatic_parameters(&mut self) -> Result<(), DSEError> {
@@ -1153,65 +1516,157 @@ impl Default for SWDL {
             prgi: None,
             kgrp: None,
             pcmd: None,
-            _eod: ChunkHeader::default()
+            _eod: ChunkHeader::default(),
+            _sample_provenance: HashMap::new()
         }
     }
 }
 impl SWDL {
     pub fn write_to_file<PWavi: Pointer<LittleEndian>, PPrgi: Pointer<LittleEndian>, W: Read + Write + Seek>(&self, writer: &mut W) -> Result<usize, DSEError> {
+        self.write_to_file_with_chunk_order::<PWavi, PPrgi, _>(writer, DEFAULT_CHUNK_ORDER)
+    }
+    /// Write the chunks in the order given by `chunk_order` instead of the usual wavi, prgi, kgrp, pcmd order. The eod chunk always comes last.
+    /// 
+    /// Every chunk present in this SWDL must appear exactly once in `chunk_order`. Chunks that are listed but not present are skipped.
+    pub fn write_to_file_with_chunk_order<PWavi: Pointer<LittleEndian>, PPrgi: Pointer<LittleEndian>, W: Read + Write + Seek>(&self, writer: &mut W, chunk_order: &[ChunkKind]) -> Result<usize, DSEError> {
+        for kind in [ChunkKind::Wavi, ChunkKind::Prgi, ChunkKind::Kgrp, ChunkKind::Pcmd] {
+            let present = match kind {
+                ChunkKind::Wavi => true,
+                ChunkKind::Prgi => self.prgi.is_some(),
+                ChunkKind::Kgrp => self.kgrp.is_some(),
+                ChunkKind::Pcmd => self.pcmd.is_some()
+            };
+            let count = chunk_order.iter().filter(|&&x| x == kind).count();
+            if count > 1 || (present && count == 0) {
+                return Err(DSEError::Invalid(format!("The {:?} chunk must appear exactly once in the chunk order, but it appears {} times!", kind, count)));
+            }
+        }
         let mut bytes_written = self.header.write_to_file(writer)?;
-        bytes_written += self.wavi.write_to_file::<PWavi, _>(writer)?;
-        bytes_written += if let Some(prgi) = &self.prgi { prgi.write_to_file::<PPrgi, _>(writer)? } else { 0 };
-        bytes_written += if let Some(kgrp) = &self.kgrp { kgrp.write_to_file(writer)? } else { 0 };
-        bytes_written += if let Some(pcmd) = &self.pcmd { pcmd.write_to_file(writer)? } else { 0 };
+        for kind in chunk_order {
+            bytes_written += match kind {
+                ChunkKind::Wavi => self.wavi.write_to_file::<PWavi, _>(writer)?,
+                ChunkKind::Prgi => if let Some(prgi) = &self.prgi { prgi.write_to_file::<PPrgi, _>(writer)? } else { 0 },
+                ChunkKind::Kgrp => if let Some(kgrp) = &self.kgrp { kgrp.write_to_file(writer)? } else { 0 },
+                ChunkKind::Pcmd => if let Some(pcmd) = &self.pcmd { pcmd.write_to_file(writer)? } else { 0 }
+            };
+        }
         bytes_written += SWDL::generate_eod_chunk_header().write_to_file(writer)?;
         Ok(bytes_written)
     }
     pub fn read_from_file<PWavi: Pointer<LittleEndian>, PPrgi: Pointer<LittleEndian>, R: Read + Seek>(&mut self, reader: &mut R) -> Result<(), DSEError> {
+        self.read_from_file_with_options::<PWavi, PPrgi, _>(reader, &LoadOptions::default())
+    }
+    pub fn read_from_file_with_options<PWavi: Pointer<LittleEndian>, PPrgi: Pointer<LittleEndian>, R: Read + Seek>(&mut self, reader: &mut R, options: &LoadOptions) -> Result<(), DSEError> {
         self.header.read_from_file(reader)?;
-        // WAVI
-        self.wavi.set_read_params(self.header.nbwavislots as usize);
-        self.wavi.read_from_file::<PWavi, _>(reader)?;
-        // PRGI {0x70, 0x72, 0x67, 0x69}
-        if peek_magic!(reader)? == [0x70, 0x72, 0x67, 0x69] {
-            let mut tmp = PRGIChunk::new(self.header.nbprgislots as usize);
-            tmp.read_from_file::<PPrgi, _>(reader)?;
-            self.prgi = Some(tmp);
-        }
-        // KGRP {0x6B, 0x67, 0x72, 0x70}
-        if peek_magic!(reader)? == [0x6B, 0x67, 0x72, 0x70] {
-            let mut tmp = KGRPChunk::default();
-            tmp.read_from_file(reader)?;
-            self.kgrp = Some(tmp);
-        }
-        // PCMD {0x70, 0x63, 0x6D, 0x64}
-        if peek_magic!(reader)? == [0x70, 0x63, 0x6D, 0x64] {
-            let mut tmp = PCMDChunk::default();
-            tmp.read_from_file(reader)?;
-            self.pcmd = Some(tmp);
+        // Chunks are identified by their magic, so they can be read in any order, but each may only appear once
+        let mut chunks_read: Vec<ChunkKind> = Vec::new();
+        let mut check_first_occurrence = |kind: ChunkKind| {
+            if chunks_read.contains(&kind) {
+                Err(DSEError::Invalid(format!("The {:?} chunk appears more than once in the file!", kind)))
+            } else {
+                chunks_read.push(kind);
+                Ok(())
+            }
+        };
+        loop {
+            match peek_magic!(reader)? {
+                // WAVI {0x77, 0x61, 0x76, 0x69}
+                [0x77, 0x61, 0x76, 0x69] => {
+                    check_first_occurrence(ChunkKind::Wavi)?;
+                    self.wavi.set_read_params(self.header.nbwavislots as usize);
+                    self.wavi.set_max_chunk_len(options.max_chunk_bytes);
+                    self.wavi.read_from_file::<PWavi, _>(reader)?;
+                },
+                // PRGI {0x70, 0x72, 0x67, 0x69}
+                [0x70, 0x72, 0x67, 0x69] => {
+                    check_first_occurrence(ChunkKind::Prgi)?;
+                    let mut tmp = PRGIChunk::new(self.header.nbprgislots as usize);
+                    tmp.set_max_chunk_len(options.max_chunk_bytes);
+                    tmp.read_from_file::<PPrgi, _>(reader)?;
+                    self.prgi = Some(tmp);
+                },
+                // KGRP {0x6B, 0x67, 0x72, 0x70}
+                [0x6B, 0x67, 0x72, 0x70] => {
+                    check_first_occurrence(ChunkKind::Kgrp)?;
+                    let mut tmp = KGRPChunk::default();
+                    tmp.read_from_file(reader)?;
+                    self.kgrp = Some(tmp);
+                },
+                // PCMD {0x70, 0x63, 0x6D, 0x64}
+                [0x70, 0x63, 0x6D, 0x64] => {
+                    check_first_occurrence(ChunkKind::Pcmd)?;
+                    let mut tmp = PCMDChunk::default();
+                    tmp.set_max_chunk_len(options.max_chunk_bytes);
+                    tmp.read_from_file(reader)?;
+                    self.pcmd = Some(tmp);
+                },
+                _ => break
+            }
         }
         // EOD\20 {0x65, 0x6F, 0x64, 0x20}
         self._eod.read_from_file(reader)?;
         Ok(())
     }
 }
+/// The chunks of a SWDL that can be reordered when writing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkKind {
+    Wavi,
+    Prgi,
+    Kgrp,
+    Pcmd
+}
+/// The order chunks are normally written in
+pub const DEFAULT_CHUNK_ORDER: &[ChunkKind] = &[ChunkKind::Wavi, ChunkKind::Prgi, ChunkKind::Kgrp, ChunkKind::Pcmd];
+
+/// Options controlling how a binary SWDL file is parsed.
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Maximum number of bytes any single chunk is allowed to declare. Useful when parsing untrusted files, where a corrupt `chunklen` would otherwise trigger a huge allocation. `None` means no cap.
+    pub max_chunk_bytes: Option<u64>
+}
 impl SWDL {
     pub fn load<R: Read + Seek>(file: &mut R) -> Result<SWDL, DSEError> {
+        SWDL::load_with_options(file, &LoadOptions::default())
+    }
+    /// Load an SWDL file straight out of an NDS ROM, given its path within the ROM's filesystem (e.g. `/data/SOUND/BGM/bgm0001.swd`).
+    #[cfg(feature = "nds-rom")]
+    pub fn load_from_nds_rom<R: Read + Seek>(mut rom: R, internal_path: &str) -> Result<SWDL, DSEError> {
+        let data = crate::nds::read_file_from_rom(&mut rom, internal_path)?;
+        SWDL::load(&mut Cursor::new(data))
+    }
+    pub fn load_with_options<R: Read + Seek>(file: &mut R, options: &LoadOptions) -> Result<SWDL, DSEError> {
         let flags = SongBuilderFlags::parse_from_swdl_file(file)?;
 
         let mut swdl = SWDL::default();
         if flags.contains(SongBuilderFlags::FULL_POINTER_EXTENSION) {
-            swdl.read_from_file::<u32, u32, _>(file)?;
+            swdl.read_from_file_with_options::<u32, u32, _>(file, options)?;
         } else if flags.contains(SongBuilderFlags::WAVI_POINTER_EXTENSION) {
-            swdl.read_from_file::<u32, u16, _>(file)?;
+            swdl.read_from_file_with_options::<u32, u16, _>(file, options)?;
         } else if flags.contains(SongBuilderFlags::PRGI_POINTER_EXTENSION) {
-            swdl.read_from_file::<u16, u32, _>(file)?;
+            swdl.read_from_file_with_options::<u16, u32, _>(file, options)?;
         } else {
-            swdl.read_from_file::<u16, u16, _>(file)?;
+            swdl.read_from_file_with_options::<u16, u16, _>(file, options)?;
         }
 
         Ok(swdl)
     }
+    /// Whether the WAVI chunk's pointers are 32-bit, according to the `SongBuilderFlags` stored in `unk18`.
+    ///
+    /// ```no_run
+    /// # use dse::swdl::SWDL;
+    /// let swdl = SWDL::load_path("bgm0001.swd").unwrap();
+    /// if swdl.wavi_pointer_extended() {
+    ///     println!("Bank uses 32-bit sample pointers");
+    /// }
+    /// ```
+    pub fn wavi_pointer_extended(&self) -> bool {
+        SongBuilderFlags::parse_from_swdl(self).contains(SongBuilderFlags::WAVI_POINTER_EXTENSION)
+    }
+    /// Whether the PRGI chunk's pointers are 32-bit, according to the `SongBuilderFlags` stored in `unk18`.
+    pub fn prgi_pointer_extended(&self) -> bool {
+        SongBuilderFlags::parse_from_swdl(self).contains(SongBuilderFlags::PRGI_POINTER_EXTENSION)
+    }
     pub fn load_xml<R: Read + Seek>(file: &mut R) -> Result<SWDL, DSEError> {
         let mut st = String::new();
         file.read_to_string(&mut st)?;
@@ -1236,10 +1691,10 @@ impl SWDL {
     pub fn load_path<P: AsRef<Path> + Debug>(path: P) -> Result<SWDL, DSEError> {
         let swdl;
         if valid_file_of_type(&path, "swd") {
-            println!("[*] Opening bank {:?}", &path);
+            info!("[*] Opening bank {:?}", &path);
             swdl = SWDL::load(&mut File::open(path)?)?;
         } else if valid_file_of_type(&path, "xml") {
-            println!("[*] Opening bank {:?} (xml)", &path);
+            info!("[*] Opening bank {:?} (xml)", &path);
             swdl = SWDL::load_xml(&mut File::open(path)?)?;
         } else {
             return Err(DSEError::Invalid(format!("File '{:?}' is not an SWD file!", path)));
@@ -1270,6 +1725,11 @@ impl SWDL {
         }
         Ok(())
     }
+    /// Save with the pointer widths chosen automatically by `SongBuilderFlags::auto_for`
+    pub fn save_auto<W: Read + Write + Seek>(&mut self, file: &mut W) -> Result<(), DSEError> {
+        let flags = SongBuilderFlags::auto_for(self);
+        self.save(file, Some(flags))
+    }
     pub fn save_xml<W: Read + Write + Seek>(&mut self, file: &mut W, flags: Option<SongBuilderFlags>) -> Result<(), DSEError> {
         if let Some(flags) = flags {
             self.set_song_builder_flags(flags);
@@ -1280,6 +1740,326 @@ impl SWDL {
     }
 }
 
+impl SWDL {
+    pub fn sample_info(&self, smpl_id: u16) -> Result<&SampleInfo, DSEError> {
+        self.wavi.data.objects.iter().find(|x| x.id == smpl_id).ok_or(DSEError::SampleNotFound(smpl_id))
+    }
+    /// The raw bytes of a sample inside this bank's pcmd chunk. Samples whose data lives in the main bank return `SampleInExternalBank`.
+    pub fn sample_bytes(&self, smpl_id: u16) -> Result<&[u8], DSEError> {
+        let sample_info = self.sample_info(smpl_id)?;
+        let pcmd = self.pcmd.as_ref().ok_or(DSEError::SampleInExternalBank(smpl_id))?;
+        let start = sample_info.smplpos as usize;
+        let len = (sample_info.loopbeg as usize + sample_info.looplen as usize) * 4;
+        pcmd.data.get(start..(start + len)).ok_or(DSEError::SampleReadError(smpl_id.to_string(), start as u64, len))
+    }
+    /// Find every split that plays the given sample, as `(program index, split index)` pairs into the prgi chunk
+    pub fn splits_using_sample(&self, smpl_id: u16) -> Vec<(usize, usize)> {
+        let prgi = if let Some(prgi) = &self.prgi { prgi } else { return Vec::new() };
+        prgi.data.objects.iter().enumerate().flat_map(|(program_i, program)| {
+            program.splits_table.objects.iter().enumerate()
+                .filter(|(_, split)| split.SmplID == smpl_id)
+                .map(move |(split_i, _)| (program_i, split_i))
+        }).collect()
+    }
+    /// The number of sample data bytes used by each program, by program id. Samples played by more than one split of a program are only counted once.
+    pub fn program_sample_memory(&self) -> BTreeMap<u16, u32> {
+        let prgi = if let Some(prgi) = &self.prgi { prgi } else { return BTreeMap::new() };
+        let sample_lengths: HashMap<u16, u32> = self.wavi.data.objects.iter().map(|x| (x.id, (x.loopbeg + x.looplen) * 4)).collect();
+        prgi.data.objects.iter().map(|program| {
+            let samples: BTreeSet<u16> = program.splits_table.objects.iter().map(|split| split.SmplID).collect();
+            (program.header.id, samples.iter().filter_map(|smpl_id| sample_lengths.get(smpl_id)).sum())
+        }).collect()
+    }
+    /// Remove a sample from the wavi chunk along with its data in the pcmd chunk, moving the data of the samples after it down to close the gap. Samples whose data overlaps the removed sample's keep it, and then nothing is removed from the pcmd chunk.
+    /// 
+    /// Fails if a split still plays the sample, see `splits_using_sample`.
+    pub fn remove_sample(&mut self, smpl_id: u16) -> Result<(), DSEError> {
+        let users = self.splits_using_sample(smpl_id);
+        if !users.is_empty() {
+            return Err(DSEError::Invalid(format!("Sample {} is still used by {} splits (program index, split index): {:?}! Use `splits_using_sample` to find and reassign them first.", smpl_id, users.len(), users)));
+        }
+        let sample_i = self.wavi.data.objects.iter().position(|x| x.id == smpl_id).ok_or(DSEError::SampleNotFound(smpl_id))?;
+        let removed = self.wavi.data.objects.remove(sample_i);
+        if let Some(pcmd) = &mut self.pcmd {
+            let start = removed.smplpos;
+            let len = (removed.loopbeg + removed.looplen) * 4;
+            let end = start + len;
+            let shared = self.wavi.data.objects.iter().any(|x| x.smplpos < end && x.smplpos + (x.loopbeg + x.looplen) * 4 > start);
+            if !shared && (end as usize) <= pcmd.data.len() {
+                pcmd.data.drain(start as usize..end as usize);
+                for sample_info in self.wavi.data.objects.iter_mut() {
+                    if sample_info.smplpos >= end {
+                        sample_info.smplpos -= len;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn sample_rate(&self, smpl_id: u16) -> Result<u32, DSEError> {
+        Ok(self.sample_info(smpl_id)?.smplrate)
+    }
+    /// Decode a sample and normalize it to [-1.0, 1.0]. Use `sample_rate` to get the matching sample rate.
+    pub fn sample_waveform_f32(&self, smpl_id: u16) -> Result<Vec<f32>, DSEError> {
+        let smplfmt = self.sample_info(smpl_id)?.smplfmt;
+        Ok(decode::decode_sample(self.sample_bytes(smpl_id)?, smplfmt)?.into_iter().map(|x| x as f32 / 32768.0).collect())
+    }
+    /// Decode every sample listed in this bank's wavi chunk, looking up the sample data in `main_bank` for songs whose samples live in the main bank.
+    /// 
+    /// Samples are matched by id. If this bank has its own pcmd chunk, its data is used instead.
+    pub fn resolve_external_samples(&self, main_bank: &SWDL) -> Result<Vec<(u16, Vec<i16>)>, DSEError> {
+        let mut resolved = Vec::with_capacity(self.wavi.data.objects.len());
+        for sample_info in &self.wavi.data.objects {
+            let smpl_id = sample_info.id;
+            let (data, smplfmt) = match self.sample_bytes(smpl_id) {
+                Ok(data) => (data, sample_info.smplfmt),
+                Err(DSEError::SampleInExternalBank(_)) => (main_bank.sample_bytes(smpl_id)?, main_bank.sample_info(smpl_id)?.smplfmt),
+                Err(e) => return Err(e)
+            };
+            resolved.push((smpl_id, decode::decode_sample(data, smplfmt)?));
+        }
+        Ok(resolved)
+    }
+    /// Decode every sample in the pcmd chunk in the order they are stored, and concatenate them into one mono 16-bit WAV file.
+    /// 
+    /// This is only meant for quickly listening to what's inside a bank. Samples may all have different sample rates, but the WAV file can only have one, so everything is played back at `assumed_rate` and samples recorded at other rates will sound sped up or slowed down.
+    /// Bytes in the pcmd chunk that no sample refers to are skipped.
+    pub fn dump_pcmd_as_wav(&self, assumed_rate: u32) -> Result<Vec<u8>, DSEError> {
+        let pcmd = self.pcmd.as_ref().ok_or(DSEError::SwdlPcmdMissing())?;
+        let mut sample_infos: Vec<&SampleInfo> = self.wavi.data.objects.iter().collect();
+        sample_infos.sort_by_key(|x| x.smplpos);
+        let mut samples: Vec<i16> = Vec::new();
+        let mut covered_until = 0;
+        for sample_info in sample_infos {
+            let start = sample_info.smplpos as usize;
+            let end = start + (sample_info.loopbeg as usize + sample_info.looplen as usize) * 4;
+            if start < covered_until || end > pcmd.data.len() {
+                continue; // Samples sharing data with one already dumped, or pointing outside of the pcmd chunk
+            }
+            match decode::decode_sample(&pcmd.data[start..end], sample_info.smplfmt) {
+                Ok(decoded) => samples.extend(decoded),
+                Err(DSEError::UnsupportedSampleFormat(_)) | Err(DSEError::UnknownSampleFormat(_)) => continue,
+                Err(e) => return Err(e)
+            }
+            covered_until = end;
+        }
+        wav::create_wav_mono_i16(&samples, assumed_rate, &[])
+    }
+    /// Decode a sample and write it out as a mono 16-bit WAV file at its own sample rate. Looped samples get a `smpl` chunk holding the loop points and root key, so that samplers pick up the loop.
+    pub fn export_sample_wav<W: Write>(&self, smpl_id: u16, out: &mut W) -> Result<(), DSEError> {
+        self.export_sample_wav_with_loop_mode(smpl_id, out, wav::LoopExportMode::Metadata)
+    }
+    /// Same as `export_sample_wav`, but with a choice of how the loop of looped samples is exported
+    pub fn export_sample_wav_with_loop_mode<W: Write>(&self, smpl_id: u16, out: &mut W, loop_mode: wav::LoopExportMode) -> Result<(), DSEError> {
+        let sample_info = self.sample_info(smpl_id)?;
+        let pcmd = self.pcmd.as_ref().ok_or(DSEError::SwdlPcmdMissing())?;
+        let mut samples = sample_info.decode_samples(pcmd)?;
+        let mut extra_chunks = Vec::new();
+        if sample_info.smplloop && !samples.is_empty() {
+            let loop_start = sample_info.loop_start_frame().min(samples.len() - 1);
+            match loop_mode {
+                wav::LoopExportMode::Metadata => {
+                    extra_chunks.push((*b"smpl", wav::create_smpl_chunk(sample_info.smplrate, sample_info.rootkey.clamp(0, 127) as u8, loop_start as u32, samples.len() as u32 - 1)?));
+                },
+                wav::LoopExportMode::Flatten(repeats) => {
+                    let loop_region = samples[loop_start..].to_vec();
+                    samples.reserve(loop_region.len() * repeats as usize);
+                    for _ in 0..repeats {
+                        samples.extend_from_slice(&loop_region);
+                    }
+                }
+            }
+        }
+        out.write_all(&wav::create_wav_mono_i16(&samples, sample_info.smplrate, &extra_chunks)?)?;
+        Ok(())
+    }
+    /// Record that the samples in `sample_mappings` (SF2 sample index to DSE sample id, as returned by `sf2::copy_raw_sample_data`) came from the soundfont `soundfont_name`.
+    pub fn record_sample_provenance(&mut self, soundfont_name: &str, sample_mappings: &HashMap<u16, u16>) {
+        for (&sf2_sample_i, &smpl_id) in sample_mappings {
+            self._sample_provenance.insert(smpl_id, (soundfont_name.to_string(), sf2_sample_i));
+        }
+    }
+    /// Get the name of the soundfont a sample was imported from, along with its original SF2 sample index, if it was recorded.
+    pub fn sample_provenance(&self, smpl_id: u16) -> Option<(&str, u16)> {
+        self._sample_provenance.get(&smpl_id).map(|(soundfont_name, sf2_sample_i)| (soundfont_name.as_str(), *sf2_sample_i))
+    }
+    /// The distinct sample rates used by the samples in this bank.
+    pub fn sample_rates(&self) -> BTreeSet<u32> {
+        self.wavi.data.objects.iter().map(|sample_info| sample_info.smplrate).collect()
+    }
+    /// The sample rates used in this bank that `sf2::sample_rate_adjustment` can't handle with the given curve. Only curve 2, the lookup table, rejects specific rates, but an invalid curve number rejects all of them.
+    pub fn unsupported_rates_for_curve(&self, curve: usize) -> Vec<u32> {
+        self.sample_rates().into_iter().filter(|&smplrate| sf2::sample_rate_adjustment(smplrate as f64, curve, 0).is_err()).collect()
+    }
+    /// Describe the format of every sample: whether it's PCM or ADPCM, and its bit depth before and after decoding. Formats this crate can't decode, like 0x0300 (possibly PSG), are flagged explicitly.
+    pub fn sample_format_summary(&self) -> Vec<(u16, String)> {
+        self.wavi.data.objects.iter().map(|sample_info| {
+            let description = match sample_info.smplfmt {
+                0x0000 => "8-bit PCM, decoded to 16-bit".to_string(),
+                0x0100 => "16-bit PCM".to_string(),
+                0x0200 => "4-bit ADPCM, decoded to 16-bit".to_string(),
+                0x0300 => "Unsupported format 0x0300 (possibly PSG), cannot be decoded".to_string(),
+                smplfmt => format!("Unknown format 0x{:04X}, cannot be decoded", smplfmt)
+            };
+            (sample_info.id, description)
+        }).collect()
+    }
+    /// Check every sample for loop settings that don't agree with its loop bounds. Returns the id of each problematic sample along with a description of the problem.
+    /// 
+    /// Bounds are only checked against the sample data if this SWDL contains a pcmd chunk.
+    pub fn verify_loops(&self) -> Vec<(u16, String)> {
+        let mut problems = Vec::new();
+        for sample_info in &self.wavi.data.objects {
+            if sample_info.smplloop && sample_info.looplen == 0 {
+                problems.push((sample_info.id, "Looping is enabled, but the loop length is zero".to_string()));
+            }
+            if let Some(pcmd) = &self.pcmd {
+                let available = (pcmd.data.len() as u64).saturating_sub(sample_info.smplpos as u64);
+                let loopbeg = sample_info.loopbeg as u64 * 4;
+                let loopend = loopbeg + sample_info.looplen as u64 * 4;
+                if loopbeg > available {
+                    problems.push((sample_info.id, format!("Loop start at byte {} is beyond the end of the sample data ({} bytes)", loopbeg, available)));
+                } else if sample_info.smplloop && loopend > available {
+                    problems.push((sample_info.id, format!("Loop end at byte {} extends past the end of the sample data ({} bytes)", loopend, available)));
+                }
+            }
+        }
+        problems
+    }
+    /// List the unknown fields that differ from their typical values. These are the fields gated behind `serde_use_common_values_for_unknowns`, so their actual values are dropped when exporting to XML and replaced by the typical ones when reading it back.
+    /// 
+    /// Returns the path of each field along with a description of its actual and typical values.
+    pub fn nondefault_unknowns(&self) -> Vec<(String, String)> {
+        const ENVELOPE_UNKNOWNS: &[&str] = &["unk19", "unk20", "unk21", "unk22", "unk57"];
+        const SAMPLE_UNKNOWNS: &[&str] = &["unk9", "unk10", "unk11", "unk12", "unk13"];
+        const PROGRAM_UNKNOWNS: &[&str] = &["unk3", "thatFbyte", "unk4", "unk5", "unk7", "unk8", "unk9"];
+        const LFO_UNKNOWNS: &[&str] = &["unk34", "unk29"];
+        const SPLIT_UNKNOWNS: &[&str] = &["unk11", "unk25", "unk16", "unk17", "unk22", "unk23", "unk24"];
+        const KEYGROUP_UNKNOWNS: &[&str] = &["unk50", "unk51"];
+        fn diff<T: Struct + Default>(path: &str, value: &T, fields: &[&str], nondefault: &mut Vec<(String, String)>) {
+            let typical = T::default();
+            for &name in fields {
+                if let (Some(actual), Some(expected)) = (value.field(name), typical.field(name)) {
+                    if actual.reflect_partial_eq(expected) != Some(true) {
+                        nondefault.push((format!("{}.{}", path, name), format!("{:?} (typically {:?})", actual, expected)));
+                    }
+                }
+            }
+        }
+
+        let mut nondefault = Vec::new();
+        for sample_info in &self.wavi.data.objects {
+            let path = format!("wavi[{}]", sample_info.id);
+            diff(&path, sample_info, SAMPLE_UNKNOWNS, &mut nondefault);
+            diff(&format!("{}.volume_envelope", path), &sample_info.volume_envelope, ENVELOPE_UNKNOWNS, &mut nondefault);
+        }
+        if let Some(prgi) = &self.prgi {
+            for program in &prgi.data.objects {
+                let path = format!("prgi[{}]", program.header.id);
+                diff(&path, &program.header, PROGRAM_UNKNOWNS, &mut nondefault);
+                for (lfo_i, lfo) in program.lfo_table.objects.iter().enumerate() {
+                    diff(&format!("{}.lfo[{}]", path, lfo_i), lfo, LFO_UNKNOWNS, &mut nondefault);
+                }
+                for split in &program.splits_table.objects {
+                    let split_path = format!("{}.split[{}]", path, split.id);
+                    diff(&split_path, split, SPLIT_UNKNOWNS, &mut nondefault);
+                    diff(&format!("{}.volume_envelope", split_path), &split.volume_envelope, ENVELOPE_UNKNOWNS, &mut nondefault);
+                }
+            }
+        }
+        if let Some(kgrp) = &self.kgrp {
+            for keygroup in &kgrp.data.objects {
+                diff(&format!("kgrp[{}]", keygroup.id), keygroup, KEYGROUP_UNKNOWNS, &mut nondefault);
+            }
+        }
+        nondefault
+    }
+    /// Hash the content of the bank: the samples, programs, keygroups and sample data, along with the header minus its timestamp. The file lengths, slot counts and other regenerated read markers are left out, so two banks differing only in when they were saved hash identically.
+    /// 
+    /// The hash is stable for a given build of this crate, but is not guaranteed to be stable across Rust versions.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        let mut header = self.header.clone();
+        (header.year, header.month, header.day, header.hour, header.minute, header.second, header.centisecond) = (0, 0, 0, 0, 0, 0, 0);
+        (header.flen, header.pcmdlen, header.nbwavislots, header.nbprgislots, header.wavilen) = (0, 0, 0, 0, 0);
+        hash_bytes_of(&header, &mut hasher);
+        for sample_info in &self.wavi.data.objects {
+            hash_bytes_of(sample_info, &mut hasher);
+        }
+        if let Some(prgi) = &self.prgi {
+            for program in &prgi.data.objects {
+                let mut normalized = program.clone();
+                normalized.header.nbsplits = normalized.splits_table.objects.len() as u16;
+                normalized.header.nblfos = normalized.lfo_table.objects.len() as u8;
+                hash_bytes_of(&normalized, &mut hasher);
+            }
+        }
+        if let Some(kgrp) = &self.kgrp {
+            for keygroup in &kgrp.data.objects {
+                hash_bytes_of(keygroup, &mut hasher);
+            }
+        }
+        if let Some(pcmd) = &self.pcmd {
+            hasher.write(&pcmd.data);
+        }
+        hasher.finish()
+    }
+    /// Report, for every keygroup either defined in the kgrp chunk or referenced by a split, how many splits use it and which programs they belong to.
+    pub fn keygroup_usage(&self) -> BTreeMap<u8, KeygroupUsage> {
+        let mut usage: BTreeMap<u8, KeygroupUsage> = BTreeMap::new();
+        if let Some(kgrp) = &self.kgrp {
+            for keygroup in &kgrp.data.objects {
+                if let Ok(kgrpid) = u8::try_from(keygroup.id) {
+                    usage.entry(kgrpid).or_default().keygroup = Some(keygroup.clone());
+                }
+            }
+        }
+        if let Some(prgi) = &self.prgi {
+            for program in &prgi.data.objects {
+                for split in &program.splits_table.objects {
+                    let keygroup_usage = usage.entry(split.kgrpid).or_default();
+                    keygroup_usage.nsplits += 1;
+                    keygroup_usage.programs.insert(program.header.id);
+                }
+            }
+        }
+        usage
+    }
+    /// Merge programs that are structurally identical apart from their id, which happens when several soundfonts contributing the same presets get imported into one bank.
+    /// 
+    /// Of each group of identical programs, the one with the lowest id is kept. Returns a mapping from the ids of the removed programs to the id of the program that replaced them, which can be handed to `SMDL::remap_programs` to update a paired song.
+    /// Samples should already be deduplicated, since splits are compared by their `SmplID`.
+    pub fn dedup_programs(&mut self) -> Result<HashMap<u16, u16>, DSEError> {
+        let mut mapping = HashMap::new();
+        let prgi = if let Some(prgi) = &mut self.prgi { prgi } else { return Ok(mapping) };
+        // Compare programs by their binary representation with the self-index and the regenerated counts normalized
+        let mut canonical_bytes: Vec<(u16, Vec<u8>)> = Vec::with_capacity(prgi.data.objects.len());
+        for program in &prgi.data.objects {
+            let mut normalized = program.clone();
+            normalized.header.id = 0;
+            normalized.header.nbsplits = normalized.splits_table.objects.len() as u16;
+            normalized.header.nblfos = normalized.lfo_table.objects.len() as u8;
+            let mut cursor = Cursor::new(Vec::new());
+            normalized.write_to_file(&mut cursor)?;
+            canonical_bytes.push((program.header.id, cursor.into_inner()));
+        }
+        canonical_bytes.sort_by_key(|(id, _)| *id);
+        let mut kept: HashMap<Vec<u8>, u16> = HashMap::new();
+        for (id, bytes) in canonical_bytes {
+            if let Some(&kept_id) = kept.get(&bytes) {
+                mapping.insert(id, kept_id);
+            } else {
+                kept.insert(bytes, id);
+            }
+        }
+        prgi.data.objects.retain(|program| !mapping.contains_key(&program.header.id));
+        if !mapping.is_empty() {
+            info!("Merged {} duplicate programs.", mapping.len());
+        }
+        Ok(mapping)
+    }
+}
+
 pub static BUILT_IN_SAMPLE_RATE_ADJUSTMENT_TABLE: phf::Map<u32, i64> = phf_map! {
     8000_u32 => -2600_i64,	11025_u32 => -1858_i64,	11031_u32 => -1856_i64,	11069_u32 => -1841_i64,	
     11281_u32 => -2013_i64,	14000_u32 => -1424_i64,	14002_u32 => -1423_i64,	14003_u32 => -1423_i64,	
@@ -1404,10 +2184,293 @@ pub fn lookup_env_time_value_i32(msec: i32) -> i8 {
         }
     }
 }
+/// Convert an envelope time index back into milliseconds, the inverse of `lookup_env_time_value_i16` and `lookup_env_time_value_i32`.
+/// 
+/// When `envmult` is nonzero the 16-bit table is used and the duration is multiplied by `envmult`, otherwise the 32-bit table is used. Negative indices are treated as 0.
+pub fn env_index_to_milliseconds(envmult: u8, index: i8) -> i32 {
+    let index = index.max(0) as usize;
+    if envmult != 0 {
+        LOOKUP_TABLE_20_B0_F50[index] as i32 * envmult as i32
+    } else {
+        LOOKUP_TABLE_20_B1050[index]
+    }
+}
+
+/// Values of the unknown fields of a `SampleInfo`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SampleUnknowns {
+    pub unk9: u8,
+    pub unk10: u16,
+    pub unk11: u16,
+    pub unk12: u16,
+    pub unk13: u32
+}
+impl SampleUnknowns {
+    pub fn of(sample_info: &SampleInfo) -> SampleUnknowns {
+        SampleUnknowns { unk9: sample_info.unk9, unk10: sample_info.unk10, unk11: sample_info.unk11, unk12: sample_info.unk12, unk13: sample_info.unk13 }
+    }
+}
+/// Distribution of the unknown `SampleInfo` fields across a collection of SWDL files, broken down by the sample properties they might depend on
+#[derive(Debug, Default)]
+pub struct SampleUnknownsSurvey {
+    pub samples: usize,
+    /// Distinct combinations of unknown values and how many times each was seen
+    pub values: BTreeMap<SampleUnknowns, usize>,
+    pub by_smplloop: BTreeMap<bool, BTreeMap<SampleUnknowns, usize>>,
+    pub by_smplfmt: BTreeMap<u16, BTreeMap<SampleUnknowns, usize>>,
+    pub by_smplrate: BTreeMap<u32, BTreeMap<SampleUnknowns, usize>>
+}
+impl SampleUnknownsSurvey {
+    /// Whether every `smplloop` value was only ever seen with one combination of unknown values
+    pub fn determined_by_smplloop(&self) -> bool {
+        self.by_smplloop.values().all(|values| values.len() == 1)
+    }
+    /// Whether every `smplfmt` value was only ever seen with one combination of unknown values
+    pub fn determined_by_smplfmt(&self) -> bool {
+        self.by_smplfmt.values().all(|values| values.len() == 1)
+    }
+    /// Whether every `smplrate` value was only ever seen with one combination of unknown values
+    pub fn determined_by_smplrate(&self) -> bool {
+        self.by_smplrate.values().all(|values| values.len() == 1)
+    }
+}
+/// Survey the unknown fields of every sample in the given SWDL files, to look for a relation between them and the loop flag, format or sample rate of the sample.
+pub fn survey_sample_unknowns<'a, I: IntoIterator<Item = &'a SWDL>>(swdls: I) -> SampleUnknownsSurvey {
+    let mut survey = SampleUnknownsSurvey::default();
+    for swdl in swdls {
+        for sample_info in &swdl.wavi.data.objects {
+            let unknowns = SampleUnknowns::of(sample_info);
+            survey.samples += 1;
+            *survey.values.entry(unknowns).or_insert(0) += 1;
+            *survey.by_smplloop.entry(sample_info.smplloop).or_default().entry(unknowns).or_insert(0) += 1;
+            *survey.by_smplfmt.entry(sample_info.smplfmt).or_default().entry(unknowns).or_insert(0) += 1;
+            *survey.by_smplrate.entry(sample_info.smplrate).or_default().entry(unknowns).or_insert(0) += 1;
+        }
+    }
+    survey
+}
 
 pub fn create_swdl_shell(last_modified: (u16, u8, u8, u8, u8, u8, u8), fname: String) -> Result<SWDL, DSEError> {
     let mut track_swdl = SWDL::default();
     track_swdl.set_metadata(last_modified, fname)?;
     Ok(track_swdl)
 }
+/// Collect the samples of several self-contained song banks into a single main bank, the way the EoS ROM shares one bank between all of its BGM.
+/// 
+/// Only samples referenced by a split are collected, or every sample for songs without a prgi chunk. Samples with identical data and parameters are stored once. Along with the main bank, returns one mapping per song (in the same order as `songs`) from its sample ids to their ids in the main bank, for rewriting the `SmplID` of the song's splits.
+pub fn build_main_bank(songs: &[&SWDL]) -> Result<(SWDL, Vec<HashMap<u16, u16>>), DSEError> {
+    let mut main_bank = SWDL::default();
+    let pcmd = main_bank.pcmd.get_or_insert(PCMDChunk::default());
+    let mut kept: HashMap<(Vec<u8>, Vec<u8>), u16> = HashMap::new();
+    let mut remappings = Vec::with_capacity(songs.len());
+    for song in songs {
+        let referenced: BTreeSet<u16> = match &song.prgi {
+            Some(prgi) => prgi.data.objects.iter().flat_map(|program| program.splits_table.objects.iter().map(|split| split.SmplID)).collect(),
+            None => song.wavi.data.objects.iter().map(|sample_info| sample_info.id).collect()
+        };
+        let mut remapping = HashMap::new();
+        for smpl_id in referenced {
+            let data = song.sample_bytes(smpl_id)?;
+            // Compare samples by their data and their binary sample info with the self-index and position normalized
+            let mut sample_info = song.sample_info(smpl_id)?.clone();
+            sample_info.id = 0;
+            sample_info.smplpos = 0;
+            let mut cursor = Cursor::new(Vec::new());
+            sample_info.write_to_file(&mut cursor)?;
+            let key = (cursor.into_inner(), data.to_vec());
+            let new_id = if let Some(&new_id) = kept.get(&key) {
+                new_id
+            } else {
+                let new_id = main_bank.wavi.data.objects.len() as u16;
+                sample_info.id = new_id;
+                sample_info.smplpos = pcmd.data.len() as u32;
+                pcmd.data.extend_from_slice(data);
+                main_bank.wavi.data.objects.push(sample_info);
+                kept.insert(key, new_id);
+                new_id
+            };
+            remapping.insert(smpl_id, new_id);
+        }
+        remappings.push(remapping);
+    }
+    info!("Built a main bank of {} samples from {} songs.", main_bank.wavi.data.objects.len(), songs.len());
+    Ok((main_bank, remappings))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small bank with one looped sample, one program, an odd number of keygroups (so the kgrp chunk is padded), and some sample data
+    fn synthetic_swdl() -> SWDL {
+        let mut swdl = SWDL::default();
+        swdl.wavi.data.objects.push(SampleInfo::builder(0).rootkey(60).volume(127).pan(64).format(SampleFormat::Pcm16).smplrate(22050).loop_bounds(1, 3).smplloop(true).build());
 
+        let mut program_info = ProgramInfo::default();
+        program_info.header.prgvol = 127;
+        program_info.header.prgpan = 64;
+        program_info.lfo_table.objects = (0..4).map(|_| LFOEntry::default()).collect();
+        let mut split = SplitEntry::default();
+        split.hikey = 127;
+        split.hivel = 127;
+        program_info.splits_table.objects = vec![split];
+        let mut prgi = PRGIChunk::new(0);
+        prgi.data.objects.push(program_info);
+        swdl.prgi = Some(prgi);
+
+        let mut kgrp = KGRPChunk::default();
+        kgrp.data.objects = (0..3).map(|id| Keygroup { id, poly: -1, priority: 8, vclow: 0, vchigh: 15, unk50: 0, unk51: 0 }).collect();
+        swdl.kgrp = Some(kgrp);
+
+        let mut pcmd = PCMDChunk::default();
+        pcmd.data = (0..16).collect();
+        swdl.pcmd = Some(pcmd);
+        swdl
+    }
+    fn save_to_bytes(swdl: &mut SWDL, flags: Option<SongBuilderFlags>) -> Vec<u8> {
+        let mut cursor = Cursor::new(Vec::new());
+        swdl.save(&mut cursor, flags).unwrap();
+        cursor.into_inner()
+    }
+    /// Load `data` and save it again with the same song builder flags, asserting that nothing but the file length in the header changed
+    fn assert_round_trips(data: &[u8]) {
+        const FLEN_BYTES: std::ops::Range<usize> = 0x08..0x0C;
+        let mut swdl = SWDL::load(&mut Cursor::new(data)).unwrap();
+        let flags = swdl.get_song_builder_flags();
+        let written = save_to_bytes(&mut swdl, Some(flags));
+        assert_eq!(written.len(), data.len());
+        let mismatches: Vec<usize> = data.iter().zip(written.iter()).enumerate()
+            .filter(|&(i, (a, b))| a != b && !FLEN_BYTES.contains(&i))
+            .map(|(i, _)| i).collect();
+        assert!(mismatches.is_empty(), "Bytes at {:X?} changed on round-trip", mismatches);
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let data = save_to_bytes(&mut synthetic_swdl(), Some(SongBuilderFlags::empty()));
+        assert_round_trips(&data);
+    }
+
+    #[test]
+    fn prgi_only_pointer_extension_round_trip() {
+        let data = save_to_bytes(&mut synthetic_swdl(), Some(SongBuilderFlags::PRGI_POINTER_EXTENSION));
+        assert_round_trips(&data);
+
+        let swdl = SWDL::load(&mut Cursor::new(&data)).unwrap();
+        assert!(swdl.prgi_pointer_extended());
+        assert!(!swdl.wavi_pointer_extended());
+        assert_eq!(swdl.wavi.data.objects.len(), 1);
+        assert_eq!(swdl.prgi.as_ref().unwrap().data.objects.len(), 1);
+
+        // 32-bit pointer tables start with a 0xFFFFFFFF magic right after the 16 byte chunk header
+        let pointer_table_start = |label: &[u8; 4]| {
+            let start = data.windows(4).position(|x| x == label).unwrap() + 16;
+            data[start..start + 4].to_vec()
+        };
+        assert_eq!(pointer_table_start(b"prgi"), vec![0xFF; 4]);
+        assert_ne!(pointer_table_start(b"wavi"), vec![0xFF; 4]);
+    }
+
+    #[test]
+    fn kgrp_writes_back_nonstandard_padding() {
+        // An odd number of keygroups, so the chunk is padded
+        let mut kgrp = KGRPChunk::default();
+        kgrp.data.objects = (0..3).map(|id| Keygroup { id, poly: -1, priority: 8, vclow: 0, vchigh: 15, unk50: 0, unk51: 0 }).collect();
+        kgrp.header.chunklen = 3 * 8;
+        let mut written = Cursor::new(Vec::new());
+        kgrp.write_to_file(&mut written).unwrap();
+        let mut bytes = written.into_inner();
+        let padding_start = bytes.len() - 8;
+        assert_eq!(bytes[padding_start..], [0x67, 0xC0, 0x40, 0x00, 0x88, 0x00, 0xFF, 0x04]);
+        bytes[padding_start..].copy_from_slice(&[0xAA; 8]);
+
+        let mut file = bytes.clone();
+        file.extend_from_slice(b"eod ");
+        let mut read_back = KGRPChunk::default();
+        read_back.read_from_file(&mut Cursor::new(file)).unwrap();
+        assert_eq!(read_back.data.objects.len(), 3);
+        let mut rewritten = Cursor::new(Vec::new());
+        read_back.write_to_file(&mut rewritten).unwrap();
+        assert_eq!(rewritten.into_inner(), bytes);
+    }
+
+    #[test]
+    fn tuning_from_ratio() {
+        assert_eq!(Tuning::from_ratio(1.0).to_cents(), 0);
+        assert_eq!(Tuning::from_ratio(2.0).to_cents(), 1200);
+        assert_eq!(Tuning::from_ratio(0.5).to_cents(), -1200);
+    }
+
+    #[test]
+    fn env_index_to_milliseconds_inverts_the_lookups() {
+        for index in [0_i8, 1, 20, 64, 100, 127] {
+            let msec = env_index_to_milliseconds(0, index);
+            assert_eq!(msec, LOOKUP_TABLE_20_B1050[index as usize]);
+            assert_eq!(env_index_to_milliseconds(0, lookup_env_time_value_i32(msec)), msec);
+
+            let msec = env_index_to_milliseconds(1, index);
+            assert_eq!(msec, LOOKUP_TABLE_20_B0_F50[index as usize] as i32);
+            assert_eq!(env_index_to_milliseconds(1, lookup_env_time_value_i16(msec as i16)), msec);
+            assert_eq!(env_index_to_milliseconds(3, index), msec * 3);
+        }
+        assert_eq!(env_index_to_milliseconds(0, -5), env_index_to_milliseconds(0, 0));
+    }
+    #[test]
+    fn sample_info_builder_leaves_unknowns_at_defaults() {
+        let sample_info = SampleInfo::builder(3)
+            .tuning(Tuning::new(64, -7))
+            .rootkey(57)
+            .volume(127)
+            .pan(64)
+            .format(SampleFormat::Pcm16)
+            .smplrate(22050)
+            .loop_bounds(10, 200)
+            .smplloop(true)
+            .build();
+        assert_eq!(sample_info.id, 3);
+        assert_eq!((sample_info.tuning.ftune(), sample_info.tuning.ctune()), (64, -7));
+        assert_eq!((sample_info.rootkey, sample_info.ktps), (57, 3));
+        assert_eq!(sample_info.format().unwrap(), SampleFormat::Pcm16);
+        assert_eq!((sample_info.smplrate, sample_info.loopbeg, sample_info.looplen, sample_info.smplloop), (22050, 10, 200, true));
+
+        let default = SampleInfo::default();
+        assert_eq!(
+            (sample_info.unk1, sample_info.unk5, sample_info.unk58, sample_info.unk6, sample_info.unk7, sample_info.unk59, sample_info.unk9, sample_info.unk10, sample_info.unk11, sample_info.unk12, sample_info.unk13),
+            (default.unk1, default.unk5, default.unk58, default.unk6, default.unk7, default.unk59, default.unk9, default.unk10, default.unk11, default.unk12, default.unk13)
+        );
+    }
+
+    #[test]
+    fn dedup_self_indices_moves_the_later_duplicate() {
+        let mut wavi: PointerTable<SampleInfo> = PointerTable::new(0, 0);
+        for id in [0, 2, 2] {
+            wavi.objects.push(SampleInfo::builder(id).format(SampleFormat::Pcm16).build());
+        }
+        assert!(wavi.write_to_file::<u16, _>(&mut Cursor::new(Vec::new())).is_err());
+
+        assert_eq!(wavi.next_free_self_index(), 1);
+        assert_eq!(wavi.dedup_self_indices().unwrap(), vec![(2, 1)]);
+        assert_eq!(wavi.objects.iter().map(|sample_info| sample_info.id).collect::<Vec<_>>(), vec![0, 2, 1]);
+        assert!(wavi.write_to_file::<u16, _>(&mut Cursor::new(Vec::new())).is_ok());
+        assert!(wavi.dedup_self_indices().unwrap().is_empty());
+    }
+    #[test]
+    fn remove_sample_closes_the_gap_in_pcmd() {
+        let mut swdl = SWDL::default();
+        for (id, smplpos) in [(0, 0), (1, 8), (2, 20)] {
+            swdl.wavi.data.objects.push(SampleInfo::builder(id).format(SampleFormat::Pcm16).smplpos(smplpos).loop_bounds(0, if id == 1 { 3 } else { 2 }).build());
+        }
+        let mut pcmd = PCMDChunk::default();
+        pcmd.data = (0..28).collect();
+        swdl.pcmd = Some(pcmd);
+
+        swdl.remove_sample(1).unwrap();
+
+        assert_eq!(swdl.wavi.data.objects.iter().map(|x| (x.id, x.smplpos)).collect::<Vec<_>>(), vec![(0, 0), (2, 8)]);
+        assert_eq!(swdl.pcmd.as_ref().unwrap().data.len(), 16);
+        assert_eq!(swdl.sample_bytes(0).unwrap(), (0..8).collect::<Vec<u8>>().as_slice());
+        assert_eq!(swdl.sample_bytes(2).unwrap(), (20..28).collect::<Vec<u8>>().as_slice());
+        assert!(matches!(swdl.remove_sample(1), Err(DSEError::SampleNotFound(1))));
+    }
+}
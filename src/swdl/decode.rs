@@ -0,0 +1,36 @@
+use byteorder::{ByteOrder, LittleEndian};
+use dse_dsp_sys::{adpcm_decode_to_16bitpcm, adpcm_encode_16bitpcm_byte_pos_preview_batch};
+
+use crate::dtype::DSEError;
+use crate::swdl::SampleFormat;
+
+/// Decode NDS-flavored IMA ADPCM with the decoder in `dse_dsp_sys`, the counterpart of the encoder used by `copy_raw_sample_data`. The data must start with the 4 byte preamble (initial predictor as an i16, then the initial step index, then a padding byte).
+pub fn decode_adpcm(data: &[u8]) -> Result<Vec<i16>, DSEError> {
+    if data.len() < 4 {
+        return Err(DSEError::Invalid("ADPCM sample data must begin with a 4 byte preamble!".to_string()));
+    }
+    Ok(adpcm_decode_to_16bitpcm(data))
+}
+/// Decode signed 8-bit PCM, scaled up to 16-bit
+pub fn decode_pcm8(data: &[u8]) -> Vec<i16> {
+    data.iter().map(|&x| (x as i8 as i16) << 8).collect()
+}
+/// Decode little-endian signed 16-bit PCM
+pub fn decode_pcm16(data: &[u8]) -> Vec<i16> {
+    data.chunks_exact(2).map(LittleEndian::read_i16).collect()
+}
+/// Decode raw sample data according to a `SampleInfo::smplfmt` value. PSG samples (0x0300) can't be decoded and are rejected rather than being read as some other format.
+pub fn decode_sample(data: &[u8], smplfmt: u16) -> Result<Vec<i16>, DSEError> {
+    match SampleFormat::try_from(smplfmt)? {
+        SampleFormat::Pcm8 => Ok(decode_pcm8(data)),
+        SampleFormat::Pcm16 => Ok(decode_pcm16(data)),
+        SampleFormat::Adpcm4 => decode_adpcm(data),
+        SampleFormat::Psg => Err(DSEError::UnsupportedSampleFormat(SampleFormat::Psg))
+    }
+}
+/// Preview where the given sample-point positions will land in the ADPCM data once encoded, in bytes from the start of the sample (including the 4 byte preamble of each block).
+/// 
+/// This is a thin wrapper around the preview in `dse_dsp_sys` that `copy_raw_sample_data` relies on to keep track of the loop bounds, so the positions match what the encoder will actually produce. `samples_per_block` is passed through to the encoder as is. Loop points must fall on 4 byte boundaries in the final data, so a position that doesn't should be moved before encoding.
+pub fn preview_loop_byte_positions(samples_per_block: usize, loop_points: &[usize]) -> Vec<usize> {
+    adpcm_encode_16bitpcm_byte_pos_preview_batch(samples_per_block, loop_points)
+}
@@ -1,23 +1,324 @@
-use std::collections::{HashMap, BTreeMap};
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::fs::File;
 use std::io::{Seek, Cursor, Read};
+use std::path::Path;
 
 use byteorder::{ReadBytesExt, LittleEndian, WriteBytesExt};
-use colored::Colorize;
+use log::{debug, trace, warn};
 use crate::math::{timecents_to_milliseconds, gain};
-use crate::swdl::{SWDL, SampleInfo, ADSRVolumeEnvelope, ProgramInfo, SplitEntry, LFOEntry, PCMDChunk, Tuning};
+use crate::swdl::{SWDL, SampleInfo, ADSRVolumeEnvelope, ProgramInfo, SplitEntry, LFOEntry, LfoDest, PCMDChunk, PRGIChunk, KGRPChunk, Keygroup, Tuning};
 use crate::dtype::{DSEError, PointerTable};
 
 use dse_dsp_sys::{process_mono_preserve_looping, SampleRateChoicePreference, init_deltas, block_alignment};
-use soundfont::data::{SampleHeader, GeneratorType};
+use soundfont::data::{SampleHeader, SampleLink, GeneratorType};
 use soundfont::{SoundFont2, Zone, Preset, Instrument};
 
 use super::{BUILT_IN_SAMPLE_RATE_ADJUSTMENT_TABLE, lookup_env_time_value_i16, lookup_env_time_value_i32, SWDLHeader};
 
+/// Lowest sample rate a relative resample is allowed to produce
+const MIN_RELATIVE_SAMPLE_RATE: f64 = 1000.0;
+/// How much to attenuate a pair of hard-panned stereo splits, so that the two channels summing at the center don't come out louder than a mono split would
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PanLaw {
+    /// Leave the split volumes alone
+    #[default]
+    None,
+    /// Constant power, -3dB at the center
+    Minus3Db,
+    /// Constant amplitude, -6dB at the center
+    Minus6Db
+}
+impl PanLaw {
+    pub fn center_attenuation_db(&self) -> f64 {
+        match self {
+            PanLaw::None => 0.0,
+            PanLaw::Minus3Db => -3.0,
+            PanLaw::Minus6Db => -6.0
+        }
+    }
+}
+#[derive(Debug, Clone, Copy)]
 pub struct DSPOptions {
     pub resample_threshold: u32,
     pub sample_rate: f64,
     pub sample_rate_relative: bool,
-    pub adpcm_encoder_lookahead: i32
+    pub adpcm_encoder_lookahead: i32,
+    /// If set, leading and trailing samples quieter than this level (in dB relative to full scale, e.g. `-60.0`) are trimmed away before resampling. Trimming never cuts into the loop region.
+    pub trim_silence_db: Option<f64>,
+    /// Pan law applied to stereo split pairs by `apply_pan_law`
+    pub pan_law: PanLaw,
+    /// Amount of stereo spread applied to multisampled programs by `apply_stereo_spread`, from 0.0 (respect the SF2 pan only) to 1.0 (lowest split hard left, highest split hard right)
+    pub stereo_spread: f64,
+    /// If true, samples whose encoded data and parameters are identical to a sample already in the bank reuse that sample instead of being written again
+    pub dedup_identical_samples: bool
+}
+impl DSPOptions {
+    /// Start building a `DSPOptions` with validated values, starting from the defaults. The defaults match the ones used by `swdl_tool`.
+    pub fn builder() -> DSPOptionsBuilder {
+        DSPOptionsBuilder {
+            options: DSPOptions::default()
+        }
+    }
+}
+impl Default for DSPOptions {
+    fn default() -> Self {
+        DSPOptions {
+            resample_threshold: 25000,
+            sample_rate: 22050.0,
+            sample_rate_relative: false,
+            adpcm_encoder_lookahead: 3,
+            trim_silence_db: None,
+            pan_law: PanLaw::None,
+            stereo_spread: 0.0,
+            dedup_identical_samples: false
+        }
+    }
+}
+pub struct DSPOptionsBuilder {
+    options: DSPOptions
+}
+impl DSPOptionsBuilder {
+    pub fn resample_threshold(mut self, resample_threshold: u32) -> DSPOptionsBuilder {
+        self.options.resample_threshold = resample_threshold;
+        self
+    }
+    /// Resample to a fixed sample rate. The rate must be above zero.
+    pub fn sample_rate(mut self, sample_rate: f64) -> Result<DSPOptionsBuilder, DSEError> {
+        if !sample_rate.is_finite() || sample_rate < 1.0 {
+            return Err(DSEError::Invalid(format!("Invalid sample rate {}! The sample rate must be at least 1 Hz.", sample_rate)));
+        }
+        self.options.sample_rate = sample_rate;
+        self.options.sample_rate_relative = false;
+        Ok(self)
+    }
+    /// Resample relative to each sample's original rate. A multiplier of 1.0 or above scales the original rate directly, while a multiplier below 1.0 is applied repeatedly until the rate drops to or under the resample threshold.
+    /// 
+    /// The multiplier must be above zero, since otherwise the repeated multiplication would produce a rate of zero or flip its sign instead of converging.
+    pub fn sample_rate_relative(mut self, multiplier: f64) -> Result<DSPOptionsBuilder, DSEError> {
+        if !multiplier.is_finite() || multiplier <= 0.0 {
+            return Err(DSEError::Invalid(format!("Invalid relative sample rate multiplier {}! The multiplier must be greater than zero.", multiplier)));
+        }
+        self.options.sample_rate = multiplier;
+        self.options.sample_rate_relative = true;
+        Ok(self)
+    }
+    pub fn adpcm_encoder_lookahead(mut self, adpcm_encoder_lookahead: i32) -> Result<DSPOptionsBuilder, DSEError> {
+        if adpcm_encoder_lookahead < 0 {
+            return Err(DSEError::Invalid(format!("Invalid ADPCM encoder lookahead {}! The lookahead cannot be negative.", adpcm_encoder_lookahead)));
+        }
+        self.options.adpcm_encoder_lookahead = adpcm_encoder_lookahead;
+        Ok(self)
+    }
+    pub fn trim_silence_db(mut self, trim_silence_db: Option<f64>) -> Result<DSPOptionsBuilder, DSEError> {
+        if let Some(db) = trim_silence_db {
+            if !db.is_finite() || db > 0.0 {
+                return Err(DSEError::Invalid(format!("Invalid silence threshold {} dB! The threshold must be at or below 0 dBFS.", db)));
+            }
+        }
+        self.options.trim_silence_db = trim_silence_db;
+        Ok(self)
+    }
+    pub fn pan_law(mut self, pan_law: PanLaw) -> DSPOptionsBuilder {
+        self.options.pan_law = pan_law;
+        self
+    }
+    pub fn dedup_identical_samples(mut self, dedup_identical_samples: bool) -> DSPOptionsBuilder {
+        self.options.dedup_identical_samples = dedup_identical_samples;
+        self
+    }
+    pub fn stereo_spread(mut self, stereo_spread: f64) -> Result<DSPOptionsBuilder, DSEError> {
+        if !(0.0..=1.0).contains(&stereo_spread) {
+            return Err(DSEError::Invalid(format!("Invalid stereo spread {}! The spread must be within 0.0-1.0.", stereo_spread)));
+        }
+        self.options.stereo_spread = stereo_spread;
+        Ok(self)
+    }
+    /// Finish building. A relative rate below 1.0 also requires a nonzero resample threshold, since the rate is reduced until it falls to or under it.
+    pub fn build(self) -> Result<DSPOptions, DSEError> {
+        if self.options.sample_rate_relative && self.options.sample_rate < 1.0 && self.options.resample_threshold == 0 {
+            return Err(DSEError::Invalid("A relative sample rate below 1.0 requires a resample threshold above zero!".to_string()));
+        }
+        Ok(self.options)
+    }
+}
+/// Options for `SWDL::import_sf2_file`
+#[derive(Debug, Clone, Copy)]
+pub struct Sf2ImportOptions {
+    pub dsp_options: DSPOptions,
+    /// See `sample_rate_adjustment` for the available curves
+    pub sample_rate_adjustment_curve: usize,
+    /// Pitch adjustment applied to every sample, in cents
+    pub pitch_adjust: i64,
+    /// Map the SF2 vibrato LFO of each preset onto the first free (disabled) LFO of its program, after any LFOs set up from modulators. See `vibrato_lfo_from_preset` for how the parameters are converted.
+    pub vibrato_lfo: bool,
+    /// Map the SF2 modulators of each preset onto its program where DSE has an equivalent. See `apply_modulators_to_program` for what's supported.
+    pub modulators: bool,
+    /// Approximate the SF2 lowpass filter of each preset with a cutoff LFO in the first free LFO slot of its program. The DSE filter is poorly understood, so this is off by default. See `cutoff_lfo_from_preset`.
+    pub filter_cutoff_lfo: bool
+}
+impl Default for Sf2ImportOptions {
+    fn default() -> Self {
+        Sf2ImportOptions {
+            dsp_options: DSPOptions::default(),
+            sample_rate_adjustment_curve: 1,
+            pitch_adjust: 0,
+            vibrato_lfo: false,
+            modulators: false,
+            filter_cutoff_lfo: false
+        }
+    }
+}
+/// What `SWDL::import_sf2_file` added to the bank
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+    /// SF2 sample indices to the ids of the samples they were imported as
+    pub sample_mappings: HashMap<u16, u16>,
+    /// Ids of the programs created, one for each preset (`bank * 128 + preset`)
+    pub program_ids: Vec<u16>
+}
+/// SF2 bank holding the percussion presets
+pub const PERCUSSION_BANK: u16 = 128;
+/// Keygroup given to the splits of melodic presets by `SWDL::import_sf2_melodic`. This is keygroup 0, the general-purpose keygroup found at the start of every bank, with no polyphony limit and the default priority of 8.
+pub const MELODIC_KEYGROUP: Keygroup = Keygroup { id: 0, poly: -1, priority: 8, vclow: 0, vchigh: 15, unk50: 0, unk51: 0 };
+/// Keygroup given to the splits of percussion presets by `SWDL::import_sf2_percussion`. Drum hits are short but come thick and fast, so they're allowed up to 8 voices at once, but with a priority of 1 so that melodic voices win when the NDS runs out of voice channels. The `id` is assigned when the keygroup is added.
+pub const PERCUSSION_KEYGROUP: Keygroup = Keygroup { id: 0, poly: 8, priority: 1, vclow: 0, vchigh: 15, unk50: 0, unk51: 0 };
+impl SWDL {
+    /// Import all the samples and presets of a soundfont into this bank in one go. Presets become programs with the id `bank * 128 + preset`, and it's an error for one of those to already exist in the bank. That is checked before anything is added, so the bank is left untouched when it happens.
+    /// 
+    /// This is a convenience wrapper around `copy_raw_sample_data` and `copy_presets`, which remain available when more control is needed.
+    pub fn import_sf2_file<P: AsRef<Path>>(&mut self, path: P, options: Sf2ImportOptions) -> Result<ImportReport, DSEError> {
+        self.import_sf2_presets(path, options, |_| true, None)
+    }
+    /// Same as `import_sf2_file`, but only imports the melodic presets (every bank but `PERCUSSION_BANK`) and the samples they use. Their splits are put in keygroup 0, which is added as `MELODIC_KEYGROUP` if the bank has no keygroups yet.
+    pub fn import_sf2_melodic<P: AsRef<Path>>(&mut self, path: P, options: Sf2ImportOptions) -> Result<ImportReport, DSEError> {
+        self.import_sf2_presets(path, options, |preset| preset.header.bank != PERCUSSION_BANK, Some(&MELODIC_KEYGROUP))
+    }
+    /// Same as `import_sf2_file`, but only imports the percussion presets (`PERCUSSION_BANK`) and the samples they use. Their splits are put in a keygroup with the settings of `PERCUSSION_KEYGROUP`, which is added after the existing keygroups unless an identical one already exists.
+    pub fn import_sf2_percussion<P: AsRef<Path>>(&mut self, path: P, options: Sf2ImportOptions) -> Result<ImportReport, DSEError> {
+        self.import_sf2_presets(path, options, |preset| preset.header.bank == PERCUSSION_BANK, Some(&PERCUSSION_KEYGROUP))
+    }
+    /// Return the id of a keygroup with the same settings as `template`, adding it to the end of the kgrp chunk if there isn't one. An empty kgrp chunk always gets `MELODIC_KEYGROUP` as keygroup 0 first.
+    fn find_or_add_keygroup(&mut self, template: &Keygroup) -> Result<u8, DSEError> {
+        let kgrp = self.kgrp.get_or_insert_with(KGRPChunk::default);
+        if kgrp.data.objects.is_empty() {
+            kgrp.data.objects.push(MELODIC_KEYGROUP);
+        }
+        let same_settings = |keygroup: &Keygroup| (keygroup.poly, keygroup.priority, keygroup.vclow, keygroup.vchigh) == (template.poly, template.priority, template.vclow, template.vchigh);
+        let id = match kgrp.data.objects.iter().find(|keygroup| same_settings(keygroup)) {
+            Some(keygroup) => keygroup.id,
+            None => {
+                let mut keygroup = template.clone();
+                keygroup.id = kgrp.data.objects.len() as u16;
+                kgrp.data.objects.push(keygroup);
+                kgrp.data.objects.len() as u16 - 1
+            }
+        };
+        u8::try_from(id).map_err(|_| DSEError::Invalid(format!("Keygroup {} cannot be referenced by a split, split keygroup ids only go up to {}!", id, u8::MAX)))
+    }
+    fn import_sf2_presets<P: AsRef<Path>>(&mut self, path: P, options: Sf2ImportOptions, filter_presets: impl Fn(&Preset) -> bool, keygroup: Option<&Keygroup>) -> Result<ImportReport, DSEError> {
+        let sf2 = SoundFont2::load(&mut File::open(&path)?).map_err(|x| DSEError::SoundFontParseError(format!("{:?}", x)))?;
+
+        // Check for program id conflicts before touching the bank, so that a failed import leaves it as it was
+        let mut new_program_ids: HashSet<u16> = HashSet::new();
+        for preset in sf2.presets.iter().filter(|preset| filter_presets(preset)) {
+            let id = preset.header.bank * 128 + preset.header.preset;
+            let exists = self.prgi.as_ref().map_or(false, |prgi| prgi.data.objects.iter().any(|x| x.header.id == id));
+            if exists || !new_program_ids.insert(id) {
+                return Err(DSEError::Invalid(format!("Program {} from soundfont '{}' already exists in the bank!", id, path.as_ref().display())));
+            }
+        }
+        let kgrpid = keygroup.map(|keygroup| self.find_or_add_keygroup(keygroup)).transpose()?;
+
+        // Only bring in the samples used by the presets being imported
+        let mut samples_used: HashSet<u16> = HashSet::new();
+        for preset in sf2.presets.iter().filter(|preset| filter_presets(preset)) {
+            for preset_zone in &preset.zones {
+                if let Some(&instrument_i) = preset_zone.instrument() {
+                    samples_used.extend(sf2.instruments[instrument_i as usize].zones.iter().filter_map(|zone| zone.sample().copied()));
+                }
+            }
+        }
+        // The other channel of a stereo sample gets a split of its own, so it has to come along too
+        let linked_samples: Vec<u16> = samples_used.iter().filter_map(|&sample_i| sf2.sample_headers.get(sample_i as usize)).filter(|sample_header| matches!(sample_header.sample_type,
+            SampleLink::LeftSample | SampleLink::RightSample | SampleLink::RomLeftSample | SampleLink::RomRightSample
+        )).map(|sample_header| sample_header.sample_link).collect();
+        samples_used.extend(linked_samples);
+        let (sample_mappings, mut sample_infos) = copy_raw_sample_data(File::open(&path)?, &sf2, self, options.dsp_options, options.sample_rate_adjustment_curve, options.pitch_adjust, |i, _| samples_used.contains(&(i as u16)))?;
+
+        let mut programs = PointerTable::new(0, 0);
+        copy_presets(&sf2, &mut sample_infos, &mut programs, |i| sample_mappings.get(&i).copied(), options.sample_rate_adjustment_curve, options.pitch_adjust, options.dsp_options.stereo_spread, |_, preset, _, _, _, _, _| filter_presets(preset), |_, preset, _| Some(preset.header.bank * 128 + preset.header.preset).filter(|_| filter_presets(preset)))?;
+
+        // copy_presets may update the loop flags of the samples it uses, so bring those back into the bank
+        for sample_info in self.wavi.data.objects.iter_mut() {
+            if let Some(updated) = sample_infos.get(&sample_info.id) {
+                sample_info.smplloop = updated.smplloop;
+            }
+        }
+
+        let prgi = self.prgi.get_or_insert_with(|| PRGIChunk::new(0));
+        let mut program_ids = Vec::with_capacity(programs.objects.len());
+        for mut program_info in programs.objects {
+            if let Some(kgrpid) = kgrpid {
+                for split in program_info.splits_table.objects.iter_mut() {
+                    split.kgrpid = kgrpid;
+                }
+            }
+            let preset = sf2.presets.iter().find(|preset| preset.header.bank * 128 + preset.header.preset == program_info.header.id);
+            if options.modulators {
+                if let Some(preset) = preset {
+                    let mut modulator_zones: Vec<&Zone> = Vec::new();
+                    for preset_zone in preset.zones.iter() {
+                        modulator_zones.push(preset_zone);
+                        if let Some(&instrument_i) = preset_zone.instrument() {
+                            modulator_zones.extend(sf2.instruments[instrument_i as usize].zones.iter());
+                        }
+                    }
+                    apply_modulators_to_program(&mut program_info, &modulator_zones);
+                }
+            }
+            if options.vibrato_lfo {
+                if let Some(lfo) = preset.and_then(|preset| vibrato_lfo_from_preset(&sf2, preset)) {
+                    // LFOs set up by `apply_modulators_to_program` come first, so the vibrato takes the first slot still free
+                    if let Some(slot) = program_info.lfo_table.objects.iter_mut().find(|lfo| lfo.destination() == LfoDest::Disabled) {
+                        *slot = lfo;
+                    } else {
+                        warn!("Program {} has a vibrato, but no free LFO slot is left for it! Skipping.", program_info.header.id);
+                    }
+                }
+            }
+            if options.filter_cutoff_lfo {
+                if let Some(lfo) = preset.and_then(|preset| cutoff_lfo_from_preset(&sf2, preset)) {
+                    if let Some(slot) = program_info.lfo_table.objects.iter_mut().find(|lfo| lfo.destination() == LfoDest::Disabled) {
+                        warn!("Program {} has a lowpass filter at {} Hz, which is approximated with a cutoff LFO that puts the frequency in the unknown `unk33` field. It may not sound the same in-game!", program_info.header.id, lfo.unk33);
+                        *slot = lfo;
+                    } else {
+                        warn!("Program {} has a lowpass filter, but no free LFO slot is left for it! Skipping.", program_info.header.id);
+                    }
+                }
+            }
+            apply_pan_law(&mut program_info, options.dsp_options.pan_law);
+            program_ids.push(program_info.header.id);
+            prgi.data.objects.push(program_info);
+        }
+
+        let soundfont_name = path.as_ref().file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        self.record_sample_provenance(&soundfont_name, &sample_mappings);
+        self.regenerate_automatic_parameters()?;
+        Ok(ImportReport { sample_mappings, program_ids })
+    }
+}
+/// Apply the relative sample rate `multiplier`, which must be within (0, 1), to `sample_rate` as many times as it takes to get to or under `threshold`. Returns `None` if that can't be done, or if the result would be under `MIN_RELATIVE_SAMPLE_RATE`.
+fn relative_resample_rate(sample_rate: f64, threshold: f64, multiplier: f64) -> Option<f64> {
+    let steps = ((threshold / sample_rate).ln() / multiplier.ln()).ceil().max(0.0);
+    if !steps.is_finite() {
+        return None;
+    }
+    let mut rate = sample_rate * multiplier.powf(steps);
+    if rate > threshold {
+        // Rounding error in the logarithms can leave the rate just over the threshold
+        rate *= multiplier;
+    }
+    Some(rate).filter(|&rate| rate >= MIN_RELATIVE_SAMPLE_RATE)
 }
 pub fn copy_raw_sample_data<R>(mut sf2file: R, sf2: &SoundFont2, bank: &mut SWDL, dsp_options: DSPOptions, sample_rate_adjustment_curve: usize, pitch_adjust: i64, mut filter_samples: impl FnMut(usize, &SampleHeader) -> bool) -> Result<(HashMap<u16, u16>, BTreeMap<u16, SampleInfo>), DSEError>
 where
@@ -29,18 +330,30 @@ where
 
     // Create the SampleInfo entries for all the samples
     let mut sample_infos = BTreeMap::new(); //::with_capacity(sf2.sample_headers.len())
-    let first_available_id = main_bank_swdl_wavi.data.slots();
+    let mut next_id = main_bank_swdl_wavi.data.slots();
     let mut pos_in_memory = 0;
 
     // Record the sample ID mappings
     let mut sample_mappings = HashMap::new();
 
-    for (old_i, i, sample_header) in sf2.sample_headers.iter().enumerate().filter(|&(i, sample_header)| filter_samples(i, sample_header)).enumerate().map(|(i, (old_i, sample_header))| (old_i, i, sample_header)) {
+    // Encoded data, loop settings and pitch of the samples already in the bank, for deduplication
+    let mut existing_samples: HashMap<(Vec<u8>, bool, u32, u32, u32, i8, i64), u16> = HashMap::new();
+    if dsp_options.dedup_identical_samples {
+        for sample_info in &main_bank_swdl_wavi.data.objects {
+            let start = sample_info.smplpos as usize;
+            let len = (sample_info.loopbeg as usize + sample_info.looplen as usize) * 4;
+            if let Some(data) = main_bank_swdl_pcmd.data.get(start..(start + len)) {
+                existing_samples.entry((data.to_vec(), sample_info.smplloop, sample_info.loopbeg, sample_info.looplen, sample_info.smplrate, sample_info.rootkey, sample_info.tuning.to_cents())).or_insert(sample_info.id);
+            }
+        }
+    }
+
+    for (old_i, sample_header) in sf2.sample_headers.iter().enumerate().filter(|&(i, sample_header)| filter_samples(i, sample_header)) {
         // Create blank sampleinfo object
         let mut sample_info = SampleInfo::default();
 
         // ID
-        sample_info.id = (first_available_id + i) as u16;
+        sample_info.id = next_id as u16;
         sample_mappings.insert(old_i as u16, sample_info.id);
 
         sample_info.smplrate = sample_header.sample_rate;
@@ -77,17 +390,41 @@ where
             sf2file.seek(std::io::SeekFrom::Start(sample_pos_bytes)).map_err(|_| DSEError::SampleFindError(sample_header.name.clone(), sample_pos_bytes))?;
             sf2file.read_i16_into::<LittleEndian>(&mut raw_sample_data).map_err(|_| DSEError::SampleReadError(sample_header.name.clone(), sample_pos_bytes, raw_sample_data.len()))?;
 
+            let mut loop_bounds = if sample_header.loop_start >= sample_header.start &&
+                sample_header.loop_end > sample_header.loop_start {
+                Some(((sample_header.loop_start - sample_header.start) as usize, (sample_header.loop_end - sample_header.start) as usize))
+            } else {
+                None
+            };
+
+            // Trim silence
+            if let Some(trim_silence_db) = dsp_options.trim_silence_db {
+                let threshold = 32768.0 * 10.0_f64.powf(trim_silence_db / 20.0);
+                let is_silent = |x: &i16| (*x as f64).abs() <= threshold;
+                let lead = raw_sample_data.iter().position(|x| !is_silent(x)).unwrap_or(raw_sample_data.len())
+                    .min(loop_bounds.map(|(loopbeg, _)| loopbeg).unwrap_or(raw_sample_data.len().saturating_sub(1)));
+                let end = if let Some((_, loopend)) = loop_bounds {
+                    loopend // Anything past the loop end is discarded anyways
+                } else {
+                    raw_sample_data.iter().rposition(|x| !is_silent(x)).map(|x| x + 1).unwrap_or(0).max(lead + 1)
+                }.min(raw_sample_data.len());
+                if lead < end {
+                    raw_sample_data = raw_sample_data[lead..end].to_vec();
+                    loop_bounds = loop_bounds.map(|(loopbeg, loopend)| (loopbeg - lead, loopend - lead));
+                }
+            }
+
             // Resample and encode to ADPCM
             let mut new_sample_rate = if sample_header.sample_rate > dsp_options.resample_threshold {
                 if dsp_options.sample_rate_relative {
                     if dsp_options.sample_rate >= 1.0 {
                         dsp_options.sample_rate * (sample_header.sample_rate as f64)
                     } else {
-                        let mut accum = sample_header.sample_rate as f64;
-                        while accum > dsp_options.resample_threshold as f64 {
-                            accum *= dsp_options.sample_rate;
+                        if !(dsp_options.sample_rate > 0.0) {
+                            return Err(DSEError::Invalid(format!("Relative sample rate multiplier {} must be greater than zero!", dsp_options.sample_rate)));
                         }
-                        accum
+                        relative_resample_rate(sample_header.sample_rate as f64, dsp_options.resample_threshold as f64, dsp_options.sample_rate)
+                            .ok_or_else(|| DSEError::Invalid(format!("Could not bring sample '{}' from {}Hz to under the resample threshold of {}Hz with a relative sample rate of {} without going under {}Hz!", sample_header.name, sample_header.sample_rate, dsp_options.resample_threshold, dsp_options.sample_rate, MIN_RELATIVE_SAMPLE_RATE)))?
                     }
                 } else {
                     dsp_options.sample_rate
@@ -98,10 +435,7 @@ where
             let (mut raw_sample_data, new_loop_bounds) = {
                 let raw_sample_data_pre_loop;
                 let raw_sample_data_loop;
-                if sample_header.loop_start >= sample_header.start &&
-                    sample_header.loop_end > sample_header.loop_start {
-                    let loopbeg_in_sample_points = (sample_header.loop_start - sample_header.start) as usize;
-                    let loopend_in_sample_points = (sample_header.loop_end - sample_header.start) as usize;
+                if let Some((loopbeg_in_sample_points, loopend_in_sample_points)) = loop_bounds {
                     raw_sample_data_pre_loop = &raw_sample_data[..loopbeg_in_sample_points];
                     raw_sample_data_loop = &raw_sample_data[loopbeg_in_sample_points..loopend_in_sample_points];
                 } else {
@@ -130,6 +464,24 @@ where
             sample_info.looplen = raw_sample_data_len_32 - sample_info.loopbeg; // Set new looplen
             raw_sample_data.resize((sample_info.loopbeg as usize + sample_info.looplen as usize) * 4, 0);
 
+            // Point duplicates at the sample that's already there. Only the id is reused: a sample written by an earlier call lies before `first_sample_pos`, so it has no position relative to the data written by this one.
+            if dsp_options.dedup_identical_samples {
+                let key = (raw_sample_data.clone(), sample_info.smplloop, sample_info.loopbeg, sample_info.looplen, sample_info.smplrate, sample_info.rootkey, sample_info.tuning.to_cents());
+                if let Some(&existing_id) = existing_samples.get(&key) {
+                    debug!("Sample '{}' is identical to sample {}, reusing it.", sample_header.name, existing_id);
+                    sample_mappings.insert(old_i as u16, existing_id);
+                    sample_infos.entry(existing_id).or_insert_with(|| {
+                        let mut shared = sample_info.clone();
+                        shared.id = existing_id;
+                        shared.smplpos = 0;
+                        shared.volume_envelope = ADSRVolumeEnvelope::default2();
+                        shared
+                    });
+                    continue;
+                }
+                existing_samples.insert(key, sample_info.id);
+            }
+
             // Write the sample
             let mut cursor = Cursor::new(&mut main_bank_swdl_pcmd.data);
             cursor.seek(std::io::SeekFrom::Start(pos_in_memory as u64 + first_sample_pos as u64)).map_err(|_| DSEError::_InMemorySeekFailed())?;
@@ -137,7 +489,7 @@ where
                 cursor.write_u8(sample).map_err(|_| DSEError::_InMemoryWriteFailed())?;
             }
         } else {
-            println!("{}SF2 file does not contain any sample data!", "Warning: ".yellow());
+            warn!("SF2 file does not contain any sample data!");
         }
         sample_info.volume_envelope = ADSRVolumeEnvelope::default2();
 
@@ -153,15 +505,91 @@ where
         sample_infos.insert(sample_info.id, sample_info_track_swdl);
         // Add the other sampleinfo object into the main bank's swdl
         main_bank_swdl_wavi.data.objects.push(sample_info);
+        next_id += 1;
     }
 
     Ok((sample_mappings, sample_infos))
 }
 
+/// Build a pitch LFO out of the vibrato LFO of a preset, or `None` if none of its zones have vibrato. DSE LFOs belong to the whole program, so only the first zone with a nonzero `VibLfoToPitch` is used, with its preset zone's generators added on top as SF2 does.
+/// 
+/// The units of the DSE LFO parameters aren't known for sure, so the conversion makes these assumptions:
+/// - `rate` is in Hz. `FreqVibLFO` is converted from absolute cents (8.176 Hz at 0) and rounded, with a minimum of 1 Hz.
+/// - `delay` is in milliseconds, converted from the timecents of `DelayVibLFO`.
+/// - `depth` is in cents, taken as the magnitude of `VibLfoToPitch`.
+pub fn vibrato_lfo_from_preset(sf2: &SoundFont2, preset: &Preset) -> Option<LFOEntry> {
+    find_in_preset_zones(sf2, preset, |generator| {
+        let depth = generator(GeneratorType::VibLfoToPitch, 0);
+        if depth == 0 {
+            return None;
+        }
+        let freq = generator(GeneratorType::FreqVibLFO, 0);
+        let delay = generator(GeneratorType::DelayVibLFO, -12000);
+
+        let mut lfo = LFOEntry::default();
+        lfo.unk52 = 1;
+        lfo.set_destination(LfoDest::Pitch);
+        lfo.rate = absolute_cents_to_hz(freq).round().clamp(1.0, u16::MAX as f64) as u16;
+        lfo.depth = depth.unsigned_abs().min(u16::MAX as u32) as u16;
+        lfo.delay = timecents_to_milliseconds(delay.clamp(i16::MIN as i32, i16::MAX as i32) as i16).clamp(0, u16::MAX as i32) as u16;
+        Some(lfo)
+    })
+}
+/// Cutoffs at or above this frequency in Hz are treated as the filter being off
+pub const FILTER_CUTOFF_THRESHOLD_HZ: f64 = 19900.0;
+/// Build an LFO approximating the SF2 lowpass filter of a preset, or `None` if none of its zones have a cutoff below `FILTER_CUTOFF_THRESHOLD_HZ`. As with `vibrato_lfo_from_preset`, only the first zone with a filter is used.
+/// 
+/// How DSE filters work isn't known, so this is only a guess at the mapping:
+/// - `dest` is set to `LfoDest::Cutoff`, with a `depth` and `rate` of 0 so that the cutoff stays put.
+/// - `unk33`, which might be the cutoff frequency, is set to the cutoff of `InitialFilterFc` in Hz.
+/// - `InitialFilterQ` has nowhere to go and is ignored.
+pub fn cutoff_lfo_from_preset(sf2: &SoundFont2, preset: &Preset) -> Option<LFOEntry> {
+    find_in_preset_zones(sf2, preset, |generator| {
+        let cutoff = absolute_cents_to_hz(generator(GeneratorType::InitialFilterFc, 13500));
+        if cutoff >= FILTER_CUTOFF_THRESHOLD_HZ {
+            return None;
+        }
+
+        let mut lfo = LFOEntry::default();
+        lfo.unk52 = 1;
+        lfo.set_destination(LfoDest::Cutoff);
+        lfo.unk33 = cutoff.round().clamp(0.0, u16::MAX as f64) as u16;
+        Some(lfo)
+    })
+}
+/// Convert SF2 absolute cents to Hz, where 0 is 8.176 Hz
+fn absolute_cents_to_hz(cents: i32) -> f64 {
+    8.176 * 2.0_f64.powf(cents as f64 / 1200.0)
+}
+/// Call `f` for each instrument zone with a sample used by the preset until it returns something. `f` is given a function that looks up a generator's value for the zone, falling back to the global instrument zone and then to the given default, with the preset zone's (or global preset zone's) value added on top as SF2 does.
+fn find_in_preset_zones<T>(sf2: &SoundFont2, preset: &Preset, mut f: impl FnMut(&dyn Fn(GeneratorType, i16) -> i32) -> Option<T>) -> Option<T> {
+    let find_i16 = |zones: &[&Zone], ty: GeneratorType| find_gen_in_zones(zones, ty).and_then(|g| g.amount.as_i16().copied());
+    let global_preset_zone = preset.zones.first().filter(|zone| zone.instrument().is_none());
+    for preset_zone in preset.zones.iter() {
+        let instrument_i = if let Some(&instrument_i) = preset_zone.instrument() { instrument_i } else { continue };
+        let instrument = if let Some(instrument) = sf2.instruments.get(instrument_i as usize) { instrument } else { continue };
+        let global_instrument_zone = instrument.zones.first().filter(|zone| zone.sample().is_none());
+        let mut preset_zones = vec![preset_zone];
+        preset_zones.extend(global_preset_zone);
+        for instrument_zone in instrument.zones.iter().filter(|zone| zone.sample().is_some()) {
+            let mut instrument_zones = vec![instrument_zone];
+            instrument_zones.extend(global_instrument_zone);
+            let generator = |ty: GeneratorType, default: i16| find_i16(&instrument_zones, ty).unwrap_or(default) as i32 + find_i16(&preset_zones, ty).unwrap_or(0) as i32;
+            if let Some(found) = f(&generator) {
+                return Some(found);
+            }
+        }
+    }
+    None
+}
 pub fn find_gen_in_zones<'a>(zones: &'a [&Zone], ty: GeneratorType) -> Option<&'a soundfont::data::Generator> {
     zones.iter().map(|x| x.gen_list.iter()).flatten().find(|g| g.ty == ty)
 }
-pub fn copy_presets(sf2: &SoundFont2, sample_infos: &mut BTreeMap<u16, SampleInfo>, prgi_pointer_table: &mut PointerTable<ProgramInfo>, mut map_samples: impl FnMut(u16) -> Option<u16>, sample_rate_adjustment_curve: usize, pitch_adjust: i64, mut filter_instruments: impl FnMut(usize, &Preset, Option<&Zone>, usize, &Zone, u16, &Instrument) -> bool, mut map_presets: impl FnMut(usize, &Preset, &ProgramInfo) -> Option<u16>) {
+// https://stackoverflow.com/questions/67016985/map-numeric-range-rust
+fn map_range(from_range: (f64, f64), to_range: (f64, f64), s: f64) -> f64 {
+    to_range.0 + (s - from_range.0) * (to_range.1 - to_range.0) / (from_range.1 - from_range.0)
+}
+pub fn copy_presets(sf2: &SoundFont2, sample_infos: &mut BTreeMap<u16, SampleInfo>, prgi_pointer_table: &mut PointerTable<ProgramInfo>, mut map_samples: impl FnMut(u16) -> Option<u16>, sample_rate_adjustment_curve: usize, pitch_adjust: i64, stereo_spread: f64, mut filter_instruments: impl FnMut(usize, &Preset, Option<&Zone>, usize, &Zone, u16, &Instrument) -> bool, mut map_presets: impl FnMut(usize, &Preset, &ProgramInfo) -> Option<u16>) -> Result<(), DSEError> {
     // Loop through the presets and use it to fill in the track swdl object
     for (preset_i, preset) in sf2.presets.iter().enumerate() {
         // Create blank programinfo object
@@ -172,19 +600,19 @@ pub fn copy_presets(sf2: &SoundFont2, sample_infos: &mut BTreeMap<u16, SampleInf
         program_info.header.prgpan = 64;
         program_info.header.PadByte = 170;
 
-        // Create the 4 LFOs (each preset in SF2 can have many instruments, with each instruments containing multiple samples, and each of those samples can have their own LFOs. 4 is just not enough to map all that, and so this is left to its default state. For now, please add LFOs manually to taste :)
+        // A pan in the global preset zone pans the whole program. The splits only get the pans of the instrument zones and the local preset zones.
+        if let Some(&pan) = preset.zones.first().filter(|zone| zone.instrument().is_none()).and_then(|zone| find_gen_in_zones(&[zone], GeneratorType::Pan)).and_then(|g| g.amount.as_i16()) {
+            program_info.header.prgpan = map_range((-500.0, 500.0), (0.0, 127.0), pan as f64).round() as i8;
+        }
+
+        // Create the 4 LFOs (each preset in SF2 can have many instruments, with each instruments containing multiple samples, and each of those samples can have their own LFOs. 4 is just not enough to map all that, and so this is left to its default state. For now, please add LFOs manually to taste, or use `vibrato_lfo_from_preset` to bring over the SF2 vibrato :)
         let lfos: Vec<LFOEntry> = (0..4).map(|_| LFOEntry::default()).collect();
         program_info.lfo_table.objects = lfos;
 
         /// Function to apply data from a zone to a split
         /// 
         /// Returns `true` if the zone provided is a global zone
-        fn apply_zone_data_to_split(split_entry: &mut SplitEntry, additive: Option<&[&Zone]>, zone: &Zone, sample_infos: &mut BTreeMap<u16, SampleInfo>, sample_i: u16, mut map_samples: impl FnMut(u16) -> Option<u16>, sample_rate_adjustment_curve: usize, pitch_adjust: i64) {
-            // https://stackoverflow.com/questions/67016985/map-numeric-range-rust
-            fn map_range(from_range: (f64, f64), to_range: (f64, f64), s: f64) -> f64 {
-                to_range.0 + (s - from_range.0) * (to_range.1 - to_range.0) / (from_range.1 - from_range.0)
-            }
-            
+        fn apply_zone_data_to_split(split_entry: &mut SplitEntry, additive: Option<&[&Zone]>, zone: &Zone, sample_infos: &mut BTreeMap<u16, SampleInfo>, sample_i: u16, mut map_samples: impl FnMut(u16) -> Option<u16>, sample_rate_adjustment_curve: usize, pitch_adjust: i64) -> Result<(), DSEError> {
             let (mut attack, mut hold, mut decay, mut release) = (None, None, None, None);
             let fill_env_from_additive_source = |param: &mut Option<i16>, gen_ty: soundfont::data::GeneratorType| {
                 if let None = param {
@@ -326,8 +754,9 @@ pub fn copy_presets(sf2: &SoundFont2, sample_infos: &mut BTreeMap<u16, SampleInf
                     soundfont::data::GeneratorType::Reserved2 => {  },
                     soundfont::data::GeneratorType::EndloopAddrsCoarseOffset => {  },
                     soundfont::data::GeneratorType::CoarseTune => {
-                        let smpl = sample_infos.get(&map_samples(sample_i).unwrap()).ok_or(DSEError::_SampleInPresetMissing(map_samples(sample_i).unwrap())).unwrap();
-                        let mut tuning = sample_rate_adjustment(smpl.smplrate as f64, sample_rate_adjustment_curve, pitch_adjust).unwrap();
+                        let smpl_id = map_samples(sample_i).ok_or(DSEError::_SampleInPresetMissing(sample_i))?;
+                        let smpl = sample_infos.get(&smpl_id).ok_or(DSEError::_SampleInPresetMissing(smpl_id))?;
+                        let mut tuning = sample_rate_adjustment(smpl.smplrate as f64, sample_rate_adjustment_curve, pitch_adjust)?;
                         tuning.add_semitones(*gen.amount.as_i16().unwrap() as i64);
                         tuning.add_semitones(if let Some(additive_source_zones) = additive { find_gen_in_zones(additive_source_zones, soundfont::data::GeneratorType::CoarseTune).map(|g| *g.amount.as_i16().unwrap()).unwrap_or(0) } else { 0 } as i64);
                         tuning.add_cents(find_gen_in_zones(&[&zone], soundfont::data::GeneratorType::FineTune).map(|g| *g.amount.as_i16().unwrap()).unwrap_or(0) as i64);
@@ -335,8 +764,9 @@ pub fn copy_presets(sf2: &SoundFont2, sample_infos: &mut BTreeMap<u16, SampleInf
                         split_entry.tuning = tuning;
                     },
                     soundfont::data::GeneratorType::FineTune => {
-                        let smpl = sample_infos.get(&map_samples(sample_i).unwrap()).ok_or(DSEError::_SampleInPresetMissing(map_samples(sample_i).unwrap())).unwrap();
-                        let mut tuning = sample_rate_adjustment(smpl.smplrate as f64, sample_rate_adjustment_curve, pitch_adjust).unwrap();
+                        let smpl_id = map_samples(sample_i).ok_or(DSEError::_SampleInPresetMissing(sample_i))?;
+                        let smpl = sample_infos.get(&smpl_id).ok_or(DSEError::_SampleInPresetMissing(smpl_id))?;
+                        let mut tuning = sample_rate_adjustment(smpl.smplrate as f64, sample_rate_adjustment_curve, pitch_adjust)?;
                         tuning.add_semitones(find_gen_in_zones(&[&zone], soundfont::data::GeneratorType::CoarseTune).map(|g| *g.amount.as_i16().unwrap()).unwrap_or(0) as i64);
                         tuning.add_semitones(if let Some(additive_source_zones) = additive { find_gen_in_zones(additive_source_zones, soundfont::data::GeneratorType::CoarseTune).map(|g| *g.amount.as_i16().unwrap()).unwrap_or(0) } else { 0 } as i64);
                         tuning.add_cents(*gen.amount.as_i16().unwrap() as i64);
@@ -345,10 +775,12 @@ pub fn copy_presets(sf2: &SoundFont2, sample_infos: &mut BTreeMap<u16, SampleInf
                     },
                     soundfont::data::GeneratorType::SampleID => {
                         // Check if the zone specifies which sample we have to use!
-                        split_entry.SmplID = map_samples(*gen.amount.as_u16().unwrap()).unwrap();
+                        let sf2_sample_i = *gen.amount.as_u16().unwrap();
+                        split_entry.SmplID = map_samples(sf2_sample_i).ok_or(DSEError::_SampleInPresetMissing(sf2_sample_i))?;
                     },
                     soundfont::data::GeneratorType::SampleModes => {
-                        let smpl = sample_infos.get_mut(&map_samples(sample_i).unwrap()).ok_or(DSEError::_SampleInPresetMissing(map_samples(sample_i).unwrap())).unwrap();
+                        let smpl_id = map_samples(sample_i).ok_or(DSEError::_SampleInPresetMissing(sample_i))?;
+                        let smpl = sample_infos.get_mut(&smpl_id).ok_or(DSEError::_SampleInPresetMissing(smpl_id))?;
                         let flags = u16::from_ne_bytes(gen.amount.as_i16().unwrap().to_ne_bytes());
                         smpl.smplloop = (flags & 0x3) % 2 == 1;
                     },
@@ -396,11 +828,41 @@ pub fn copy_presets(sf2: &SoundFont2, sample_infos: &mut BTreeMap<u16, SampleInf
                     }
                 }
             }
+            Ok(())
+        }
+
+        /// Function to start a split off with the tuning, root key and envelope of the sample it plays
+        fn copy_sample_info_to_split(split_entry: &mut SplitEntry, sample_infos: &BTreeMap<u16, SampleInfo>, smpl_id: u16) -> Result<(), DSEError> {
+            let smpl_ref = sample_infos.get(&smpl_id).ok_or(DSEError::_SampleInPresetMissing(smpl_id))?;
+            split_entry.tuning = smpl_ref.tuning;
+            split_entry.rootkey = smpl_ref.rootkey;
+            split_entry.ktps = smpl_ref.ktps;
+            split_entry.volume_envelope = smpl_ref.volume_envelope.clone();
+            Ok(())
+        }
+
+        /// Function to apply the instrument and preset zones behind a split, in order, for the SF2 sample `sample_i`
+        fn apply_zones_to_split(split_entry: &mut SplitEntry, global_preset_zone: Option<&Zone>, preset_zone: &Zone, global_instrument_zone: Option<&Zone>, instrument_zone: &Zone, sample_infos: &mut BTreeMap<u16, SampleInfo>, sample_i: u16, mut map_samples: impl FnMut(u16) -> Option<u16>, sample_rate_adjustment_curve: usize, pitch_adjust: i64) -> Result<(), DSEError> {
+            if let Some(global_instrument_zone) = global_instrument_zone {
+                apply_zone_data_to_split(split_entry, None, global_instrument_zone, sample_infos, sample_i, &mut map_samples, sample_rate_adjustment_curve, pitch_adjust)?;
+            }
+            apply_zone_data_to_split(split_entry, None, instrument_zone, sample_infos, sample_i, &mut map_samples, sample_rate_adjustment_curve, pitch_adjust)?;
+            let mut additive_source_zones = vec![instrument_zone];
+            if let Some(global_instrument_zone) = global_instrument_zone {
+                additive_source_zones.push(global_instrument_zone);
+            }
+            if let Some(global_preset_zone) = global_preset_zone {
+                let smplpan = split_entry.smplpan; // The pan of the global preset zone goes to `prgpan` instead
+                apply_zone_data_to_split(split_entry, Some(&additive_source_zones), global_preset_zone, sample_infos, sample_i, &mut map_samples, sample_rate_adjustment_curve, pitch_adjust)?;
+                split_entry.smplpan = smplpan;
+            }
+            apply_zone_data_to_split(split_entry, Some(&additive_source_zones), preset_zone, sample_infos, sample_i, &mut map_samples, sample_rate_adjustment_curve, pitch_adjust)
         }
 
         /// Function to create splits from zones
-        fn create_splits_from_zones(global_preset_zone: Option<&Zone>, preset_zone: &Zone, instrument_zones: &Vec<Zone>, sample_infos: &mut BTreeMap<u16, SampleInfo>, mut map_samples: impl FnMut(u16) -> Option<u16>, sample_rate_adjustment_curve: usize, pitch_adjust: i64) -> Vec<SplitEntry> {
+        fn create_splits_from_zones(global_preset_zone: Option<&Zone>, preset_zone: &Zone, instrument_zones: &Vec<Zone>, sample_headers: &[SampleHeader], sample_infos: &mut BTreeMap<u16, SampleInfo>, mut map_samples: impl FnMut(u16) -> Option<u16>, sample_rate_adjustment_curve: usize, pitch_adjust: i64) -> Result<Vec<SplitEntry>, DSEError> {
             let mut splits = Vec::with_capacity(instrument_zones.len());
+            let mut split_samples: Vec<(u16, &Zone)> = Vec::with_capacity(instrument_zones.len()); // The SF2 sample index and instrument zone of each split
             let mut global_instrument_zone: Option<&Zone> = None;
             for (i, instrument_zone) in instrument_zones.iter().enumerate() {
                 let mut split = SplitEntry::default();
@@ -411,62 +873,74 @@ pub fn copy_presets(sf2: &SoundFont2, sample_infos: &mut BTreeMap<u16, SampleInf
                 split.hivel = 127;
                 if let Some(&sample_i) = instrument_zone.sample() {
                     if let Some(mapping) = map_samples(sample_i) {
-                        let smpl_ref = sample_infos.get(&mapping).ok_or(DSEError::_SampleInPresetMissing(mapping)).unwrap();
-                        split.tuning = smpl_ref.tuning;
-                        split.rootkey = smpl_ref.rootkey;
-                        split.volume_envelope = smpl_ref.volume_envelope.clone();
+                        copy_sample_info_to_split(&mut split, sample_infos, mapping)?;
                     } else {
-                        println!("{}", format!("Sample associated with split unmapped! Skipping.").green());
+                        debug!("Sample associated with split unmapped! Skipping.");
                         continue;
                     }
                 } else if i != 0 {
-                    println!("{}Some instrument zones contain no samples!", "Warning: ".yellow());
+                    warn!("Some instrument zones contain no samples!");
                     continue;
                 } else {
                     split.tuning = Tuning::new(0, 0);
                     split.rootkey = 60;
                     split.volume_envelope = ADSRVolumeEnvelope::default();
-                    println!("{}", "Global instrument zone detected!".green());
+                    debug!("Global instrument zone detected!");
                 }
                 split.smplvol = 127;
                 split.smplpan = 64;
                 split.kgrpid = 0;
 
                 if let Some(&sample_i) = instrument_zone.sample() {
-                    if let Some(global_instrument_zone) = global_instrument_zone {
-                        apply_zone_data_to_split(&mut split, None, global_instrument_zone, sample_infos, sample_i, &mut map_samples, sample_rate_adjustment_curve, pitch_adjust);
-                    }
-                    apply_zone_data_to_split(&mut split, None, instrument_zone, sample_infos, sample_i, &mut map_samples, sample_rate_adjustment_curve, pitch_adjust);
-                    if let Some(global_preset_zone) = global_preset_zone {
-                        apply_zone_data_to_split(&mut split, Some(&(|| {
-                            let mut additive_source_zones = vec![instrument_zone];
-                            if let Some(global_instrument_zone) = global_instrument_zone {
-                                additive_source_zones.push(global_instrument_zone);
-                            }
-                            additive_source_zones
-                        })()), global_preset_zone, sample_infos, sample_i, &mut map_samples, sample_rate_adjustment_curve, pitch_adjust);
-                    }
-                    apply_zone_data_to_split(&mut split, Some(&(|| {
-                        let mut additive_source_zones = vec![instrument_zone];
-                        if let Some(global_instrument_zone) = global_instrument_zone {
-                            additive_source_zones.push(global_instrument_zone);
-                        }
-                        additive_source_zones
-                    })()), preset_zone, sample_infos, sample_i, &mut map_samples, sample_rate_adjustment_curve, pitch_adjust);
+                    apply_zones_to_split(&mut split, global_preset_zone, preset_zone, global_instrument_zone, instrument_zone, sample_infos, sample_i, &mut map_samples, sample_rate_adjustment_curve, pitch_adjust)?;
                 } else if i == 0 {
                     global_instrument_zone = Some(instrument_zone);
                     skip_this_split = true;
-                    println!("{}", "Global instrument zone detected!".green());
+                    debug!("Global instrument zone detected!");
                 } else {
                     skip_this_split = true;
-                    println!("{}Some instrument zones contain no samples!", "Warning: ".yellow());
+                    warn!("Some instrument zones contain no samples!");
                 }
 
                 if !skip_this_split { // If this split represents a global instrument zone or is missing a corresponding sample it should not be included.
+                    if let Some(&sample_i) = instrument_zone.sample() {
+                        split_samples.push((sample_i, instrument_zone));
+                    }
                     splits.push(split);
                 }
             }
-            splits
+
+            // A stereo sample only plays in stereo if the instrument also has a zone for its other channel over the same region. Add a split for the other channel when it's missing, and pan the pair by their sample types.
+            let same_region = |a: &SplitEntry, b: &SplitEntry| (a.lowkey, a.hikey, a.lovel, a.hivel) == (b.lowkey, b.hikey, b.lovel, b.hivel);
+            let mut missing_channels = Vec::new();
+            for (split_i, &(sample_i, _)) in split_samples.iter().enumerate() {
+                let sample_header = if let Some(sample_header) = sample_headers.get(sample_i as usize) { sample_header } else { continue };
+                let (pan, linked_pan) = match sample_header.sample_type {
+                    SampleLink::LeftSample | SampleLink::RomLeftSample => (0, 127),
+                    SampleLink::RightSample | SampleLink::RomRightSample => (127, 0),
+                    _ => continue
+                };
+                let linked_i = sample_header.sample_link;
+                let has_linked_split = split_samples.iter().zip(splits.iter()).any(|(&(other_sample_i, _), other)| other_sample_i == linked_i && same_region(other, &splits[split_i]));
+                if !has_linked_split {
+                    missing_channels.push((split_i, linked_i, pan, linked_pan));
+                }
+            }
+            for (split_i, linked_i, pan, linked_pan) in missing_channels {
+                if let Some(mapping) = map_samples(linked_i) {
+                    // Build the other channel's split from the same zones, but with its own sample's tuning, root key and envelope
+                    let mut linked_split = splits[split_i].clone();
+                    copy_sample_info_to_split(&mut linked_split, sample_infos, mapping)?;
+                    apply_zones_to_split(&mut linked_split, global_preset_zone, preset_zone, global_instrument_zone, split_samples[split_i].1, sample_infos, linked_i, &mut map_samples, sample_rate_adjustment_curve, pitch_adjust)?;
+                    linked_split.SmplID = mapping;
+                    linked_split.smplpan = linked_pan;
+                    splits[split_i].smplpan = pan;
+                    splits.push(linked_split);
+                } else {
+                    debug!("Other channel of stereo sample {} unmapped! Leaving it mono.", split_samples[split_i].0);
+                }
+            }
+            Ok(splits)
         }
 
         // Create splits
@@ -475,23 +949,24 @@ pub fn copy_presets(sf2: &SoundFont2, sample_infos: &mut BTreeMap<u16, SampleInf
             if let Some(&instrument_i) = preset_zone.instrument() {
                 let instrument = &sf2.instruments[instrument_i as usize];
                 if filter_instruments(preset_i, &preset, global_preset_zone, preset_zone_i, preset_zone, instrument_i, instrument) {
-                    create_splits_from_zones(global_preset_zone, preset_zone, &instrument.zones, sample_infos, &mut map_samples, sample_rate_adjustment_curve, pitch_adjust)
+                    create_splits_from_zones(global_preset_zone, preset_zone, &instrument.zones, &sf2.sample_headers, sample_infos, &mut map_samples, sample_rate_adjustment_curve, pitch_adjust)
                 } else {
-                    Vec::new() // The instrument has been filtered out
+                    Ok(Vec::new()) // The instrument has been filtered out
                 }
             } else if preset_zone_i == 0 {
                 global_preset_zone = Some(preset_zone);
-                println!("{}", "Global preset zone detected!".green());
-                Vec::new() // The global preset zone should not be included.
+                debug!("Global preset zone detected!");
+                Ok(Vec::new()) // The global preset zone should not be included.
             } else {
-                println!("{}Some preset zones contain no instruments!", "Warning: ".yellow());
-                Vec::new()
+                warn!("Some preset zones contain no instruments!");
+                Ok(Vec::new())
             }
-        }).flatten().enumerate().map(|(i, mut x)| {
+        }).collect::<Result<Vec<Vec<SplitEntry>>, DSEError>>()?.into_iter().flatten().enumerate().map(|(i, mut x)| {
             x.id = i as u8;
             x
         }).collect();
         program_info.splits_table.objects = splits;
+        apply_stereo_spread(&mut program_info, stereo_spread);
 
         // Add to the prgi chunk
         if let Some(mapping) = map_presets(preset_i, preset, &program_info) {
@@ -499,6 +974,113 @@ pub fn copy_presets(sf2: &SoundFont2, sample_infos: &mut BTreeMap<u16, SampleInf
             prgi_pointer_table.objects.push(program_info);
         }
     }
+    Ok(())
+}
+
+/// Attenuate the `smplvol` of every pair of stereo splits in a program according to `pan_law`.
+/// 
+/// A pair is two splits covering the same key and velocity ranges, with one panned hard left (0) and the other hard right (127).
+pub fn apply_pan_law(program_info: &mut ProgramInfo, pan_law: PanLaw) {
+    if pan_law == PanLaw::None {
+        return;
+    }
+    let center_gain = gain(pan_law.center_attenuation_db());
+    let mut paired = vec![false; program_info.splits_table.objects.len()];
+    for (i, j) in program_info.overlapping_splits() {
+        let (a, b) = (&program_info.splits_table.objects[i], &program_info.splits_table.objects[j]);
+        let same_region = a.lowkey == b.lowkey && a.hikey == b.hikey && a.lovel == b.lovel && a.hivel == b.hivel;
+        let hard_panned = (a.smplpan == 0 && b.smplpan == 127) || (a.smplpan == 127 && b.smplpan == 0);
+        if same_region && hard_panned && !paired[i] && !paired[j] {
+            paired[i] = true;
+            paired[j] = true;
+        }
+    }
+    for (split, paired) in program_info.splits_table.objects.iter_mut().zip(paired) {
+        if paired {
+            split.smplvol = (split.smplvol as f64 * center_gain).round() as i8;
+        }
+    }
+}
+
+/// Spread the splits of a multisampled program across the stereo field by key range, low keys to the left and high keys to the right, like the stereo image of a piano.
+/// 
+/// The offset is added on top of the existing `smplpan` of each split. `spread` goes from 0.0 (no change) to 1.0 (the lowest split is moved a full half of the pan range to the left, and the highest one to the right). Programs whose splits all cover the same key range are left alone.
+pub fn apply_stereo_spread(program_info: &mut ProgramInfo, spread: f64) {
+    if spread == 0.0 {
+        return;
+    }
+    let centers: Vec<f64> = program_info.splits_table.objects.iter().map(|split| (split.lowkey as f64 + split.hikey as f64) / 2.0).collect();
+    let lowest = centers.iter().copied().fold(f64::INFINITY, f64::min);
+    let highest = centers.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if !(highest > lowest) {
+        return;
+    }
+    for (split, center) in program_info.splits_table.objects.iter_mut().zip(centers) {
+        let position = (center - lowest) / (highest - lowest) * 2.0 - 1.0; // -1.0 is the lowest split, 1.0 the highest
+        split.smplpan = (split.smplpan as f64 + position * spread * 63.5).round().clamp(0.0, 127.0) as i8;
+    }
+}
+
+/// Map the SF2 modulators found in `zones` onto a program where DSE has an equivalent. Only a few common modulators are supported:
+/// 
+/// * Mod wheel (CC1) to vibrato or modulation LFO pitch: the program's first LFO is set up as a pitch LFO, using the modulator's amount (in cents) as the depth and the zones' LFO frequency and delay generators for the rate and delay.
+/// * Mod wheel (CC1) to modulation LFO volume: the program's second LFO is set up as a volume LFO in the same way.
+/// * Note-on velocity to initial attenuation: DSE always scales note volume by velocity, so this is already the default behavior. A modulator with an amount of zero, which makes a patch velocity-insensitive, cannot be represented.
+/// 
+/// SF2 also defines default modulators that apply to every zone unless overridden. Of those, only mod wheel to vibrato LFO pitch (50 cents) has a DSE equivalent, so it's applied first and replaced by any explicit mod wheel to pitch modulator in the zones. The other defaults (velocity to attenuation and filter cutoff, channel pressure to vibrato, volume, expression, pan, reverb, chorus, and pitch wheel) are either already built into DSE or have nowhere to go.
+/// 
+/// The depth unit of DSE LFOs isn't known, so the modulator's amount in cents is used directly as the depth, as `vibrato_lfo_from_preset` does.
+/// 
+/// DSE has no way to scale an LFO by the mod wheel, so the LFOs are written with their on flag (`unk52`) cleared. Turn them on by hand or with LFO events in the song. All other modulators are ignored.
+pub fn apply_modulators_to_program(program_info: &mut ProgramInfo, zones: &[&Zone]) {
+    const SRC_VELOCITY: u16 = 0x0002;
+    const SRC_MOD_WHEEL: u16 = 0x0081; // CC flag + controller 1
+    const DEST_MOD_LFO_TO_PITCH: u16 = 5;
+    const DEST_VIB_LFO_TO_PITCH: u16 = 6;
+    const DEST_MOD_LFO_TO_VOLUME: u16 = 13;
+    const DEST_INITIAL_ATTENUATION: u16 = 48;
+
+    let lfo_rate = |gen_ty: GeneratorType| find_gen_in_zones(zones, gen_ty)
+        .map(|g| 8.176 * 2.0_f64.powf(*g.amount.as_i16().unwrap() as f64 / 1200.0))
+        .unwrap_or(8.176).round() as u16; // Absolute cents to Hz
+    let lfo_delay = |gen_ty: GeneratorType| find_gen_in_zones(zones, gen_ty)
+        .map(|g| timecents_to_milliseconds(*g.amount.as_i16().unwrap()).clamp(0, u16::MAX as i32) as u16)
+        .unwrap_or(0);
+
+    let mut apply_modulator = |src: u16, dest: u16, amount: i16| {
+        if src == SRC_MOD_WHEEL && (dest == DEST_VIB_LFO_TO_PITCH || dest == DEST_MOD_LFO_TO_PITCH) {
+            if let Some(lfo) = program_info.lfo_table.objects.get_mut(0) {
+                let (freq_gen, delay_gen) = if dest == DEST_VIB_LFO_TO_PITCH {
+                    (GeneratorType::FreqVibLFO, GeneratorType::DelayVibLFO)
+                } else {
+                    (GeneratorType::FreqModLFO, GeneratorType::DelayModLFO)
+                };
+                lfo.dest = 1; // Pitch
+                lfo.depth = amount.unsigned_abs();
+                lfo.rate = lfo_rate(freq_gen);
+                lfo.delay = lfo_delay(delay_gen);
+            }
+        } else if src == SRC_MOD_WHEEL && dest == DEST_MOD_LFO_TO_VOLUME {
+            if let Some(lfo) = program_info.lfo_table.objects.get_mut(1) {
+                lfo.dest = 2; // Volume
+                lfo.depth = amount.unsigned_abs();
+                lfo.rate = lfo_rate(GeneratorType::FreqModLFO);
+                lfo.delay = lfo_delay(GeneratorType::DelayModLFO);
+            }
+        } else if src == SRC_VELOCITY && dest == DEST_INITIAL_ATTENUATION {
+            if amount == 0 {
+                warn!("A velocity-insensitive modulator was found, but DSE always scales volume by velocity!");
+            }
+        } else {
+            debug!("Unsupported modulator 0x{:04X} -> {} skipped.", src, dest);
+        }
+    };
+    apply_modulator(SRC_MOD_WHEEL, DEST_VIB_LFO_TO_PITCH, 50); // Default modulator
+    for zone in zones {
+        for modulator in zone.mod_list.iter() {
+            apply_modulator(modulator.src & 0x00FF, modulator.dest, modulator.amount);
+        }
+    }
 }
 
 pub fn find_preset_in_soundfont(soundfont: &SoundFont2, bank: u16, program: u16) -> Option<usize> {
@@ -527,23 +1109,64 @@ pub fn find_preset_in_soundfonts<'a>(soundfonts: &'a [&SoundFont2], bank: u16, p
     }
     return None;
 }
+/// Find a preset by its name instead of its bank/program numbers. The comparison ignores case and surrounding whitespace.
+pub fn find_preset_by_name(sf2: &SoundFont2, name: &str) -> Option<usize> {
+    let name = name.trim();
+    sf2.presets.iter().position(|preset| preset.header.name.trim_end_matches('\0').trim().eq_ignore_ascii_case(name))
+}
+/// Find a preset by name across multiple soundfonts. Like `find_preset_in_soundfonts`, the first soundfont containing a match wins.
+/// 
+/// The bank/program numbers of the preset found can then be read from its header to feed into the rest of the import pipeline.
+pub fn find_preset_by_name_in_soundfonts<'a>(soundfonts: &'a [&SoundFont2], name: &str) -> Option<(usize, usize)> {
+    for (soundfont_i, soundfont) in soundfonts.iter().enumerate() {
+        if let Some(preset_i) = find_preset_by_name(soundfont, name) {
+            return Some((soundfont_i, preset_i));
+        }
+    }
+    return None;
+}
 
 pub fn sample_rate_adjustment_in_cents(sample_rate: f64) -> f64 {
     ((sample_rate - 1115.9471180474397) / 31832.602532753794).ln() / 0.0005990154279493774
 }
 
 pub fn sample_rate_adjustment_ideal(sample_rate: f64) -> Tuning {
-    Tuning::from_cents((1200.0 * (sample_rate / 32728.5).log2()).round() as i64)
+    Tuning::from_ratio(sample_rate / 32728.5)
+}
+/// Inverse of `sample_rate_adjustment_ideal`. Returns the sample rate a sample originally at `source_rate` should be resampled to so that it plays exactly in tune without any `ftune`/`ctune` correction, leaving only a whole-semitone offset that can be absorbed into the root key.
+/// 
+/// Despite its name, `target_note` is not a MIDI note number but a semitone offset. With a `target_note` of zero, the closest such rate to `source_rate` is chosen, and any other value shifts the result by that many semitones, which then need to be added to the root key to compensate.
+pub fn ideal_sample_rate_for_note(target_note: i8, source_rate: u32) -> u32 {
+    let semitones = (1200.0 * (source_rate as f64 / 32728.5).log2() / 100.0).round() + target_note as f64;
+    (32728.5 * 2.0_f64.powf(semitones / 12.0)).round() as u32
 }
 pub fn sample_rate_adjustment_table(sample_rate: f64) -> Result<Tuning, DSEError> {
     let smplrate = sample_rate.round() as u32;
     if let Some(&cents) = BUILT_IN_SAMPLE_RATE_ADJUSTMENT_TABLE.get(&smplrate) {
-        println!("{:?}", Tuning::from_cents(cents));
+        trace!("{:?}", Tuning::from_cents(cents));
         Ok(Tuning::from_cents(cents))
     } else {
         Err(DSEError::SampleRateUnsupported(sample_rate))
     }
 }
+/// Like `sample_rate_adjustment_table`, but rates missing from the table are linearly interpolated (in cents) between the two nearest rates in the table. Rates outside of the table are extrapolated from the nearest rate in the table as the ideal curve would.
+pub fn sample_rate_adjustment_table_interpolated(sample_rate: f64) -> Tuning {
+    if let Ok(tuning) = sample_rate_adjustment_table(sample_rate) {
+        return tuning;
+    }
+    let below = BUILT_IN_SAMPLE_RATE_ADJUSTMENT_TABLE.entries().filter(|(smplrate, _)| (**smplrate as f64) < sample_rate).max_by_key(|(smplrate, _)| **smplrate);
+    let above = BUILT_IN_SAMPLE_RATE_ADJUSTMENT_TABLE.entries().filter(|(smplrate, _)| (**smplrate as f64) > sample_rate).min_by_key(|(smplrate, _)| **smplrate);
+    let cents = match (below, above) {
+        (Some((&rate_lo, &cents_lo)), Some((&rate_hi, &cents_hi))) => {
+            cents_lo as f64 + (cents_hi - cents_lo) as f64 * (sample_rate - rate_lo as f64) / (rate_hi - rate_lo) as f64
+        },
+        (Some((&rate, &cents)), None) | (None, Some((&rate, &cents))) => {
+            cents as f64 + 1200.0 * (sample_rate / rate as f64).log2()
+        },
+        (None, None) => return sample_rate_adjustment_ideal(sample_rate)
+    };
+    Tuning::from_cents(cents.round() as i64)
+}
 pub fn sample_rate_adjustment_fitted(sample_rate: f64) -> Tuning {
     Tuning::from_cents(sample_rate_adjustment_in_cents(sample_rate) as i64)
 }
@@ -552,6 +1175,7 @@ pub fn sample_rate_adjustment(sample_rate: f64, curve: usize, additional_adjust:
         1 => Ok(sample_rate_adjustment_ideal(sample_rate)),
         2 => sample_rate_adjustment_table(sample_rate),
         3 => Ok(sample_rate_adjustment_fitted(sample_rate)),
+        4 => Ok(sample_rate_adjustment_table_interpolated(sample_rate)),
         _ => return Err(DSEError::Invalid("Invalid sample rate adjustment curve number!".to_string()))
     }?;
     val.add_cents(additional_adjust);
@@ -567,3 +1191,295 @@ pub fn timecents_to_index(timecents: i16) -> (u8, i8) {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn riff_chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+        chunk.extend_from_slice(data);
+        chunk
+    }
+    fn riff_list(id: &[u8; 4], kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = kind.to_vec();
+        for chunk in chunks {
+            data.extend_from_slice(chunk);
+        }
+        riff_chunk(id, &data)
+    }
+    fn name20(name: &str) -> Vec<u8> {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.resize(20, 0);
+        bytes
+    }
+    /// `sample_type` is 1 for mono, 2 for right and 4 for left samples
+    fn sample_header_record(name: &str, start: u32, end: u32, sample_rate: u32, sample_link: u16, sample_type: u16) -> Vec<u8> {
+        let mut record = name20(name);
+        for x in [start, end, 0, 0, sample_rate] {
+            record.write_u32::<LittleEndian>(x).unwrap();
+        }
+        record.write_u8(60).unwrap(); // origpitch
+        record.write_i8(0).unwrap(); // pitchadj
+        record.write_u16::<LittleEndian>(sample_link).unwrap();
+        record.write_u16::<LittleEndian>(sample_type).unwrap();
+        record
+    }
+    fn preset_header_record(name: &str, bag_i: usize) -> Vec<u8> {
+        let mut record = name20(name);
+        record.write_u16::<LittleEndian>(0).unwrap(); // preset
+        record.write_u16::<LittleEndian>(0).unwrap(); // bank
+        record.write_u16::<LittleEndian>(bag_i as u16).unwrap();
+        record.extend_from_slice(&[0; 12]); // library, genre, morphology
+        record
+    }
+    fn instrument_header_record(name: &str, bag_i: usize) -> Vec<u8> {
+        let mut record = name20(name);
+        record.write_u16::<LittleEndian>(bag_i as u16).unwrap();
+        record
+    }
+    /// Start a new zone at the next generator
+    fn push_bag_record(bags: &mut Vec<u8>, generators: &[u8]) {
+        bags.write_u16::<LittleEndian>((generators.len() / 4) as u16).unwrap();
+        bags.write_u16::<LittleEndian>(0).unwrap();
+    }
+    fn push_generator_record(generators: &mut Vec<u8>, oper: u16, amount: i16) {
+        generators.write_u16::<LittleEndian>(oper).unwrap();
+        generators.write_i16::<LittleEndian>(amount).unwrap();
+    }
+    /// A minimal soundfont with a single unlooped mono sample. If `preset` is given, there is also a preset 0:0 playing the sample through a single instrument zone, as `(global preset zone generators, instrument zone generators)`. The global preset zone is left out if it has no generators.
+    fn sf2_bytes(data: &[i16], preset: Option<(&[(u16, i16)], &[(u16, i16)])>) -> Vec<u8> {
+        let mut ifil = Vec::new();
+        ifil.write_u16::<LittleEndian>(2).unwrap();
+        ifil.write_u16::<LittleEndian>(1).unwrap();
+        let info = riff_list(b"LIST", b"INFO", &[
+            riff_chunk(b"ifil", &ifil),
+            riff_chunk(b"isng", b"EMU8000\0"),
+            riff_chunk(b"INAM", b"Test\0\0")
+        ]);
+
+        let mut smpl = Vec::new();
+        for &x in data.iter().chain([0_i16; 46].iter()) {
+            smpl.write_i16::<LittleEndian>(x).unwrap();
+        }
+        let sdta = riff_list(b"LIST", b"sdta", &[riff_chunk(b"smpl", &smpl)]);
+
+        let (mut phdr, mut pbag, mut pgen) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut inst, mut ibag, mut igen) = (Vec::new(), Vec::new(), Vec::new());
+        if let Some((global_preset_generators, instrument_generators)) = preset {
+            phdr.extend(preset_header_record("Preset", 0));
+            if !global_preset_generators.is_empty() {
+                push_bag_record(&mut pbag, &pgen);
+                for &(oper, amount) in global_preset_generators {
+                    push_generator_record(&mut pgen, oper, amount);
+                }
+            }
+            push_bag_record(&mut pbag, &pgen);
+            push_generator_record(&mut pgen, 41, 0); // instrument
+
+            inst.extend(instrument_header_record("Instrument", 0));
+            push_bag_record(&mut ibag, &igen);
+            for &(oper, amount) in instrument_generators {
+                push_generator_record(&mut igen, oper, amount);
+            }
+            push_generator_record(&mut igen, 53, 0); // sampleID
+        }
+        phdr.extend(preset_header_record("EOP", pbag.len() / 4));
+        push_bag_record(&mut pbag, &pgen);
+        push_generator_record(&mut pgen, 0, 0);
+        inst.extend(instrument_header_record("EOI", ibag.len() / 4));
+        push_bag_record(&mut ibag, &igen);
+        push_generator_record(&mut igen, 0, 0);
+        let mut shdr = sample_header_record("Sample", 0, data.len() as u32, 22050, 0, 1);
+        shdr.extend(sample_header_record("EOS", 0, 0, 0, 0, 1));
+        let pdta = riff_list(b"LIST", b"pdta", &[
+            riff_chunk(b"phdr", &phdr),
+            riff_chunk(b"pbag", &pbag),
+            riff_chunk(b"pmod", &[0; 10]),
+            riff_chunk(b"pgen", &pgen),
+            riff_chunk(b"inst", &inst),
+            riff_chunk(b"ibag", &ibag),
+            riff_chunk(b"imod", &[0; 10]),
+            riff_chunk(b"igen", &igen),
+            riff_chunk(b"shdr", &shdr)
+        ]);
+
+        riff_list(b"RIFF", b"sfbk", &[info, sdta, pdta])
+    }
+
+    /// A soundfont with a left sample linked to a right sample, both holding `data`, and a preset 0:0 whose only instrument zone plays the left sample
+    fn stereo_sf2_bytes(data: &[i16]) -> Vec<u8> {
+        let mut ifil = Vec::new();
+        ifil.write_u16::<LittleEndian>(2).unwrap();
+        ifil.write_u16::<LittleEndian>(1).unwrap();
+        let info = riff_list(b"LIST", b"INFO", &[
+            riff_chunk(b"ifil", &ifil),
+            riff_chunk(b"isng", b"EMU8000\0"),
+            riff_chunk(b"INAM", b"Test\0\0")
+        ]);
+
+        let mut smpl = Vec::new();
+        for _ in 0..2 {
+            for &x in data.iter().chain([0_i16; 46].iter()) {
+                smpl.write_i16::<LittleEndian>(x).unwrap();
+            }
+        }
+        let sdta = riff_list(b"LIST", b"sdta", &[riff_chunk(b"smpl", &smpl)]);
+
+        let (mut phdr, mut pbag, mut pgen) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut inst, mut ibag, mut igen) = (Vec::new(), Vec::new(), Vec::new());
+        phdr.extend(preset_header_record("Preset", 0));
+        push_bag_record(&mut pbag, &pgen);
+        push_generator_record(&mut pgen, 41, 0); // instrument
+        phdr.extend(preset_header_record("EOP", pbag.len() / 4));
+        push_bag_record(&mut pbag, &pgen);
+        push_generator_record(&mut pgen, 0, 0);
+        inst.extend(instrument_header_record("Instrument", 0));
+        push_bag_record(&mut ibag, &igen);
+        push_generator_record(&mut igen, 53, 0); // sampleID
+        inst.extend(instrument_header_record("EOI", ibag.len() / 4));
+        push_bag_record(&mut ibag, &igen);
+        push_generator_record(&mut igen, 0, 0);
+        let right_start = data.len() as u32 + 46;
+        let mut shdr = sample_header_record("Left", 0, data.len() as u32, 22050, 1, 4);
+        shdr.extend(sample_header_record("Right", right_start, right_start + data.len() as u32, 22050, 0, 2));
+        shdr.extend(sample_header_record("EOS", 0, 0, 0, 0, 1));
+        let pdta = riff_list(b"LIST", b"pdta", &[
+            riff_chunk(b"phdr", &phdr),
+            riff_chunk(b"pbag", &pbag),
+            riff_chunk(b"pmod", &[0; 10]),
+            riff_chunk(b"pgen", &pgen),
+            riff_chunk(b"inst", &inst),
+            riff_chunk(b"ibag", &ibag),
+            riff_chunk(b"imod", &[0; 10]),
+            riff_chunk(b"igen", &igen),
+            riff_chunk(b"shdr", &shdr)
+        ]);
+
+        riff_list(b"RIFF", b"sfbk", &[info, sdta, pdta])
+    }
+
+    #[test]
+    fn dedup_writes_a_sample_shared_by_two_soundfonts_once() {
+        let data: Vec<i16> = (0..512).map(|i| ((i as f64 / 8.0).sin() * 8000.0) as i16).collect();
+        let dsp_options = DSPOptions::builder().dedup_identical_samples(true).build().unwrap();
+        let mut bank = SWDL::default();
+
+        let mut results = Vec::new();
+        for soundfont in [sf2_bytes(&data, None), sf2_bytes(&data, None)] {
+            let sf2 = SoundFont2::load(&mut Cursor::new(&soundfont)).unwrap();
+            results.push(copy_raw_sample_data(Cursor::new(&soundfont), &sf2, &mut bank, dsp_options, 1, 0, |i, _| i == 0).unwrap());
+        }
+        let (first_mappings, first_infos) = &results[0];
+        let (second_mappings, second_infos) = &results[1];
+
+        assert_eq!(bank.wavi.data.objects.len(), 1);
+        let shared = &bank.wavi.data.objects[0];
+        assert_eq!(bank.pcmd.as_ref().unwrap().data.len(), ((shared.loopbeg + shared.looplen) * 4) as usize);
+        assert_eq!(first_mappings.get(&0), Some(&shared.id));
+        assert_eq!(second_mappings.get(&0), Some(&shared.id));
+        assert_eq!(first_infos.get(&shared.id).unwrap().smplpos, 0);
+        assert_eq!(second_infos.get(&shared.id).unwrap().smplpos, 0);
+    }
+
+    #[test]
+    fn global_preset_zone_pan_sets_prgpan() {
+        let prgpan = |global_preset_generators: &[(u16, i16)]| {
+            let soundfont = sf2_bytes(&[0; 64], Some((global_preset_generators, &[][..])));
+            let sf2 = SoundFont2::load(&mut Cursor::new(&soundfont)).unwrap();
+            let mut bank = SWDL::default();
+            let (sample_mappings, mut sample_infos) = copy_raw_sample_data(Cursor::new(&soundfont), &sf2, &mut bank, DSPOptions::default(), 1, 0, |i, _| i == 0).unwrap();
+            let mut programs = PointerTable::new(0, 0);
+            copy_presets(&sf2, &mut sample_infos, &mut programs, |i| sample_mappings.get(&i).copied(), 1, 0, 0.0, |_, _, _, _, _, _, _| true, |_, preset, _| Some(preset.header.bank * 128 + preset.header.preset)).unwrap();
+            programs.objects[0].header.prgpan
+        };
+        // Pan is generator 17, in 0.1% units from -500 (left) to 500 (right)
+        assert_eq!(prgpan(&[(17, 250)]), 95);
+        assert_eq!(prgpan(&[(17, -500)]), 0);
+        assert_eq!(prgpan(&[]), 64);
+    }
+
+    #[test]
+    fn left_sample_brings_in_its_right_channel() {
+        let soundfont = stereo_sf2_bytes(&[0; 64]);
+        let sf2 = SoundFont2::load(&mut Cursor::new(&soundfont)).unwrap();
+        let mut bank = SWDL::default();
+        let (sample_mappings, mut sample_infos) = copy_raw_sample_data(Cursor::new(&soundfont), &sf2, &mut bank, DSPOptions::default(), 1, 0, |i, _| i < 2).unwrap();
+        let mut programs = PointerTable::new(0, 0);
+        copy_presets(&sf2, &mut sample_infos, &mut programs, |i| sample_mappings.get(&i).copied(), 1, 0, 0.0, |_, _, _, _, _, _, _| true, |_, preset, _| Some(preset.header.bank * 128 + preset.header.preset)).unwrap();
+
+        let splits = &programs.objects[0].splits_table.objects;
+        assert_eq!(splits.len(), 2);
+        assert_eq!((splits[0].SmplID, splits[0].smplpan), (sample_mappings[&0], 0));
+        assert_eq!((splits[1].SmplID, splits[1].smplpan), (sample_mappings[&1], 127));
+        assert_ne!(splits[0].SmplID, splits[1].SmplID);
+        assert_eq!(splits[1].rootkey, sample_infos[&sample_mappings[&1]].rootkey);
+    }
+
+    #[test]
+    fn vibrato_lfo_from_vib_lfo_generators() {
+        let vibrato_lfo = |instrument_generators: &[(u16, i16)]| {
+            let soundfont = sf2_bytes(&[0; 64], Some((&[][..], instrument_generators)));
+            let sf2 = SoundFont2::load(&mut Cursor::new(&soundfont)).unwrap();
+            vibrato_lfo_from_preset(&sf2, &sf2.presets[0])
+        };
+        // VibLfoToPitch is generator 6, DelayVibLFO 21 and FreqVibLFO 22. 1200 absolute cents is 16.352 Hz, and -1200 timecents is half a second.
+        let lfo = vibrato_lfo(&[(6, -50), (21, -1200), (22, 1200)]).unwrap();
+        assert_eq!(lfo.destination(), LfoDest::Pitch);
+        assert_eq!(lfo.unk52, 1);
+        assert_eq!((lfo.rate, lfo.depth, lfo.delay), (16, 50, 500));
+        // Very slow vibratos still get a rate of 1 Hz
+        assert_eq!(vibrato_lfo(&[(6, 50), (22, -16000)]).unwrap().rate, 1);
+        assert!(vibrato_lfo(&[(21, -1200), (22, 1200)]).is_none());
+    }
+
+    #[test]
+    fn cutoff_lfo_from_initial_filter_fc() {
+        let cutoff_lfo = |instrument_generators: &[(u16, i16)]| {
+            let soundfont = sf2_bytes(&[0; 64], Some((&[][..], instrument_generators)));
+            let sf2 = SoundFont2::load(&mut Cursor::new(&soundfont)).unwrap();
+            cutoff_lfo_from_preset(&sf2, &sf2.presets[0])
+        };
+        // InitialFilterFc is generator 8. 9000 absolute cents is about 1480 Hz.
+        let lfo = cutoff_lfo(&[(8, 9000)]).unwrap();
+        assert_eq!(lfo.destination(), LfoDest::Cutoff);
+        assert_eq!(lfo.unk33, 1480);
+        // 13500 absolute cents, the default, is about 19913 Hz, which is above the threshold
+        assert!(cutoff_lfo(&[(8, 13500)]).is_none());
+        assert!(cutoff_lfo(&[]).is_none());
+    }
+
+    #[test]
+    fn relative_resample_rate_takes_as_many_steps_as_needed() {
+        assert_eq!(relative_resample_rate(44100.0, 22050.0, 0.5), Some(22050.0));
+        // 0.99 takes 69 steps to bring 44100 Hz under 22050 Hz
+        let rate = relative_resample_rate(44100.0, 22050.0, 0.99).unwrap();
+        assert!(rate <= 22050.0 && rate > 22050.0 * 0.99);
+        assert!((rate - 44100.0 * 0.99_f64.powi(69)).abs() < 1e-6);
+        assert_eq!(relative_resample_rate(44100.0, 0.0, 0.5), None);
+        assert_eq!(relative_resample_rate(44100.0, 1500.0, 0.1), None);
+    }
+
+    #[test]
+    fn interpolated_table_curve() {
+        // 8000 Hz and 11025 Hz are adjacent in the table, at -2600 and -1858 cents
+        assert_eq!(sample_rate_adjustment(8000.0, 4, 0).unwrap().to_cents(), -2600);
+        assert_eq!(sample_rate_adjustment(9512.5, 4, 0).unwrap().to_cents(), -2229);
+        assert_eq!(sample_rate_adjustment(4000.0, 4, 0).unwrap().to_cents(), -3800);
+        assert!(sample_rate_adjustment(9512.5, 2, 0).is_err());
+    }
+
+    #[test]
+    fn ideal_sample_rate_round_trips_to_whole_semitones() {
+        for source_rate in [8000, 11025, 22050, 32000, 32728, 44100, 48000] {
+            for semitone_offset in [-12, -1, 0, 1, 7] {
+                let rate = ideal_sample_rate_for_note(semitone_offset, source_rate);
+                let cents = sample_rate_adjustment_ideal(rate as f64).to_cents();
+                assert_eq!(cents % 100, 0, "{} Hz with an offset of {} gave {} Hz, which is {} cents off", source_rate, semitone_offset, rate, cents);
+            }
+            let rate = ideal_sample_rate_for_note(0, source_rate);
+            assert!((1200.0 * (rate as f64 / source_rate as f64).log2()).abs() <= 50.0);
+        }
+    }
+}
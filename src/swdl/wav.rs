@@ -0,0 +1,63 @@
+use std::io::Write;
+
+use byteorder::{WriteBytesExt, LittleEndian};
+
+use crate::dtype::DSEError;
+use crate::riff::{self, FourCC};
+
+/// How the loop of a looped sample is exported to WAV
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoopExportMode {
+    /// Write the sample as is, with the loop points in a `smpl` chunk
+    #[default]
+    Metadata,
+    /// Repeat the loop region inline this many more times after the end of the sample, without a `smpl` chunk, for players that ignore loop points
+    Flatten(u32)
+}
+/// Create a mono 16-bit PCM WAV file. `extra_chunks` are written after the `data` chunk.
+pub fn create_wav_mono_i16(samples: &[i16], sample_rate: u32, extra_chunks: &[(FourCC, Vec<u8>)]) -> Result<Vec<u8>, DSEError> {
+    let mut fmt = Vec::with_capacity(16);
+    fmt.write_u16::<LittleEndian>(1)?; // PCM
+    fmt.write_u16::<LittleEndian>(1)?; // Mono
+    fmt.write_u32::<LittleEndian>(sample_rate)?;
+    fmt.write_u32::<LittleEndian>(sample_rate * 2)?; // Byte rate
+    fmt.write_u16::<LittleEndian>(2)?; // Block align
+    fmt.write_u16::<LittleEndian>(16)?; // Bits per sample
+
+    let mut data = Vec::with_capacity(samples.len() * 2);
+    for &sample in samples {
+        data.write_i16::<LittleEndian>(sample)?;
+    }
+
+    let mut body: Vec<u8> = Vec::new();
+    body.write_all(b"WAVE")?;
+    riff::write_chunk(&mut body, b"fmt ", &fmt)?;
+    riff::write_chunk(&mut body, b"data", &data)?;
+    for (id, chunk_data) in extra_chunks {
+        riff::write_chunk(&mut body, id, chunk_data)?;
+    }
+
+    let mut wav = Vec::with_capacity(body.len() + 8);
+    riff::write_chunk(&mut wav, b"RIFF", &body)?;
+    Ok(wav)
+}
+/// Create the data of a `smpl` chunk with a single forward loop. `loop_start` and `loop_end` are sample frames, and `loop_end` is the last frame played before looping back (inclusive).
+pub fn create_smpl_chunk(sample_rate: u32, unity_note: u8, loop_start: u32, loop_end: u32) -> Result<Vec<u8>, DSEError> {
+    let mut smpl = Vec::with_capacity(60);
+    smpl.write_u32::<LittleEndian>(0)?; // Manufacturer
+    smpl.write_u32::<LittleEndian>(0)?; // Product
+    smpl.write_u32::<LittleEndian>(1_000_000_000 / sample_rate.max(1))?; // Sample period in nanoseconds
+    smpl.write_u32::<LittleEndian>(unity_note as u32)?;
+    smpl.write_u32::<LittleEndian>(0)?; // Pitch fraction
+    smpl.write_u32::<LittleEndian>(0)?; // SMPTE format
+    smpl.write_u32::<LittleEndian>(0)?; // SMPTE offset
+    smpl.write_u32::<LittleEndian>(1)?; // Number of loops
+    smpl.write_u32::<LittleEndian>(0)?; // Sampler data
+    smpl.write_u32::<LittleEndian>(0)?; // Cue point id
+    smpl.write_u32::<LittleEndian>(0)?; // Loop type, forward
+    smpl.write_u32::<LittleEndian>(loop_start)?;
+    smpl.write_u32::<LittleEndian>(loop_end)?;
+    smpl.write_u32::<LittleEndian>(0)?; // Fraction
+    smpl.write_u32::<LittleEndian>(0)?; // Play count, infinite
+    Ok(smpl)
+}
@@ -0,0 +1,178 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Display;
+
+use crate::dtype::{DSELinkBytes, DSEError};
+use crate::smdl::{SMDL, DSEEvent};
+use crate::swdl::SWDL;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error
+}
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "Warning"),
+            Severity::Error => write!(f, "Error")
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticLocation {
+    /// The package as a whole, or the file headers
+    Package,
+    /// A track in the SMDL, and optionally an event index within it
+    Track { trkid: u8, event_i: Option<usize> },
+    /// A program in the SWDL, and optionally a split index within it
+    Program { id: u16, split_i: Option<usize> },
+    /// A sample in the SWDL's wavi chunk
+    Sample(u16),
+    /// A keygroup in the SWDL's kgrp chunk
+    Keygroup(u16)
+}
+impl Display for DiagnosticLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiagnosticLocation::Package => write!(f, "package"),
+            DiagnosticLocation::Track { trkid, event_i: Some(event_i) } => write!(f, "trk{:02} event {}", trkid, event_i),
+            DiagnosticLocation::Track { trkid, event_i: None } => write!(f, "trk{:02}", trkid),
+            DiagnosticLocation::Program { id, split_i: Some(split_i) } => write!(f, "program {} split {}", id, split_i),
+            DiagnosticLocation::Program { id, split_i: None } => write!(f, "program {}", id),
+            DiagnosticLocation::Sample(id) => write!(f, "sample {}", id),
+            DiagnosticLocation::Keygroup(id) => write!(f, "keygroup {}", id)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub location: DiagnosticLocation,
+    pub message: String
+}
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): {}", self.severity, self.location, self.message)
+    }
+}
+
+/// Check that a song's SWDL and SMDL have the same link bytes, which they need for the game to pair them up. Meant to be called before writing a hand-assembled pair.
+pub fn check_link_bytes_match(swdl: &SWDL, smdl: &SMDL) -> Result<(), DSEError> {
+    if swdl.get_link_bytes() != smdl.get_link_bytes() {
+        return Err(DSEError::LinkBytesMismatch(swdl.get_link_bytes(), smdl.get_link_bytes()));
+    }
+    Ok(())
+}
+
+/// Run every available check on a song's SMD and its paired SWD, and collect the problems found. Errors will very likely break playback, while warnings may just be unusual.
+///
+/// The checks are:
+/// * The link bytes of both files, and the SetSwdl/SetBank events of every track, agree with each other.
+/// * Every program referenced by a SetProgram event exists in the SWDL.
+/// * Every sample referenced by a split exists in the SWDL, and lies within the pcmd chunk if there is one.
+/// * Loop flags and loop bounds of every sample agree (see `SWDL::verify_loops`).
+/// * No track other than the meta track is empty of notes.
+/// * Every keygroup referenced by a split exists, and no track plays more simultaneous notes than the keygroups it uses allow.
+pub fn validate_song_package(smdl: &SMDL, swdl: &SWDL) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut push = |severity: Severity, location: DiagnosticLocation, message: String| {
+        diagnostics.push(Diagnostic { severity, location, message });
+    };
+
+    // ======== LINK BYTES ========
+    let link_bytes = smdl.get_link_bytes();
+    if link_bytes != swdl.get_link_bytes() {
+        push(Severity::Error, DiagnosticLocation::Package, format!("SMDL link bytes {:?} do not match SWDL link bytes {:?}!", link_bytes, swdl.get_link_bytes()));
+    }
+
+    // ======== SAMPLES ========
+    let samples: HashMap<u16, _> = swdl.wavi.data.objects.iter().map(|sample_info| (sample_info.id, sample_info)).collect();
+    if let Some(pcmd) = &swdl.pcmd {
+        for sample_info in &swdl.wavi.data.objects {
+            let end = sample_info.smplpos as u64 + (sample_info.loopbeg as u64 + sample_info.looplen as u64) * 4;
+            if end > pcmd.data.len() as u64 {
+                push(Severity::Error, DiagnosticLocation::Sample(sample_info.id), format!("Sample data ends at byte {}, past the end of the pcmd chunk ({} bytes)!", end, pcmd.data.len()));
+            }
+        }
+    }
+    for (smpl_id, problem) in swdl.verify_loops() {
+        push(Severity::Warning, DiagnosticLocation::Sample(smpl_id), problem);
+    }
+
+    // ======== PROGRAMS AND KEYGROUPS ========
+    let keygroups: HashMap<u16, i8> = swdl.kgrp.as_ref().map(|kgrp| kgrp.data.objects.iter().map(|keygroup| (keygroup.id, keygroup.poly)).collect()).unwrap_or_default();
+    let mut program_keygroups: HashMap<u16, BTreeSet<u16>> = HashMap::new();
+    if let Some(prgi) = &swdl.prgi {
+        for program in &prgi.data.objects {
+            for (split_i, split) in program.splits_table.objects.iter().enumerate() {
+                let location = DiagnosticLocation::Program { id: program.header.id, split_i: Some(split_i) };
+                if !samples.contains_key(&split.SmplID) {
+                    push(Severity::Error, location.clone(), format!("Split refers to sample {}, which does not exist!", split.SmplID));
+                }
+                if swdl.kgrp.is_some() && !keygroups.contains_key(&(split.kgrpid as u16)) {
+                    push(Severity::Error, location, format!("Split refers to keygroup {}, which does not exist!", split.kgrpid));
+                }
+                program_keygroups.entry(program.header.id).or_insert(BTreeSet::new()).insert(split.kgrpid as u16);
+            }
+        }
+    }
+
+    // ======== TRACKS ========
+    for trk in &smdl.trks.objects {
+        let trkid = trk.preamble.trkid;
+        let mut has_notes = false;
+        let mut programs_used: HashSet<u16> = HashSet::new();
+        let mut note_offs: BTreeMap<u128, usize> = BTreeMap::new();
+        let mut max_polyphony = 0;
+        let mut last_duration: u32 = 0;
+        for (event_i, (tick, event)) in trk.events_with_ticks().into_iter().enumerate() {
+            let location = DiagnosticLocation::Track { trkid, event_i: Some(event_i) };
+            match event {
+                DSEEvent::PlayNote(note) => {
+                    has_notes = true;
+                    if note.keydownduration != 0 {
+                        last_duration = note.keydownduration;
+                    }
+                    // Drop the notes that have already been released
+                    note_offs = note_offs.split_off(&(tick + 1));
+                    *note_offs.entry(tick + last_duration as u128).or_insert(0) += 1;
+                    max_polyphony = max_polyphony.max(note_offs.values().sum::<usize>());
+                },
+                DSEEvent::Other(other) => match other.code {
+                    0xA9 if other.parameters[0] != link_bytes.1 => { // SetSwdl
+                        push(Severity::Warning, location, format!("SetSwdl({}) does not match the link bytes {:?}!", other.parameters[0], link_bytes));
+                    },
+                    0xAA if other.parameters[0] != link_bytes.0 => { // SetBank
+                        push(Severity::Warning, location, format!("SetBank({}) does not match the link bytes {:?}!", other.parameters[0], link_bytes));
+                    },
+                    0xAC => { // SetProgram
+                        let program_id = other.parameters[0] as u16;
+                        programs_used.insert(program_id);
+                        let exists = swdl.prgi.as_ref().map(|prgi| prgi.data.objects.iter().any(|program| program.header.id == program_id)).unwrap_or(false);
+                        if !exists {
+                            push(Severity::Error, location, format!("SetProgram refers to program {}, which does not exist in the SWDL!", program_id));
+                        }
+                    },
+                    _ => {  }
+                },
+                DSEEvent::FixedDurationPause(_) => {  }
+            }
+        }
+        if trkid != 0 && !has_notes {
+            push(Severity::Warning, DiagnosticLocation::Track { trkid, event_i: None }, "Track contains no notes!".to_string());
+        }
+        let keygroups_used: BTreeSet<u16> = programs_used.iter().filter_map(|program_id| program_keygroups.get(program_id)).flatten().copied().collect();
+        for kgrpid in keygroups_used {
+            if let Some(&poly) = keygroups.get(&kgrpid) {
+                if poly >= 0 && (poly as usize) < max_polyphony {
+                    push(Severity::Warning, DiagnosticLocation::Keygroup(kgrpid), format!("trk{:02} plays up to {} notes at once, but the keygroup only allows {}! Some notes will be cut off.", trkid, max_polyphony, poly));
+                }
+            }
+        }
+    }
+
+    diagnostics.sort_by_key(|diagnostic| std::cmp::Reverse(diagnostic.severity));
+    diagnostics
+}